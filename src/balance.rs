@@ -0,0 +1,312 @@
+use crate::{ChainMonitor, ChannelManager, Error, Wallet};
+
+use lightning::chain::channelmonitor::Balance;
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// Details of the on-chain and lightning balances of the node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalanceDetails {
+	/// The total balance of the on-chain wallet, in satoshis.
+	pub total_onchain_balance_sats: u64,
+	/// The currently spendable balance of the on-chain wallet, in satoshis.
+	pub spendable_onchain_balance_sats: u64,
+	/// The total balance we could claim if all open channels were closed, in satoshis.
+	pub total_lightning_balance_sats: u64,
+	/// The total balance of closed channels that waits for the closing transaction to confirm, in
+	/// satoshis.
+	pub total_awaiting_confirmation_sats: u64,
+	/// The total balance of closed channels that is encumbered by a timelock, in satoshis.
+	pub total_timelocked_sats: u64,
+	/// The total value of HTLCs that still need to be claimed on-chain, in satoshis.
+	pub total_contentious_sats: u64,
+	/// The balances of the individual channels, including closed ones that still hold funds.
+	pub channel_balances: Vec<ChannelBalance>,
+}
+
+/// The balances held by a single channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelBalance {
+	/// The `channel_id` of the channel.
+	pub channel_id: [u8; 32],
+	/// Whether the channel is still open.
+	pub is_open: bool,
+	/// The balance we could claim if the channel was closed right now, in satoshis.
+	///
+	/// This is always zero for closed channels.
+	pub lightning_balance_sats: u64,
+	/// The balance that waits for the closing transaction to confirm, in satoshis.
+	///
+	/// This is always zero for open channels.
+	pub awaiting_confirmation_sats: u64,
+	/// The balances that are encumbered by a timelock.
+	pub timelocked_balances: Vec<TimelockedBalance>,
+	/// The HTLCs that still need to be claimed on-chain.
+	pub contentious_claims: Vec<ContentiousClaim>,
+}
+
+/// A balance that will become spendable once a timelock expired.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelockedBalance {
+	/// The amount, in satoshis.
+	pub amount_sats: u64,
+	/// The block height at which the balance becomes spendable.
+	pub maturity_height: u32,
+}
+
+/// An HTLC output that is claimable on-chain by us or our counterparty, depending on who claims
+/// it first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentiousClaim {
+	/// We know the preimage and can claim the HTLC, unless our counterparty claims it back after
+	/// `timeout_height`.
+	ClaimableWithPreimage {
+		/// The amount, in satoshis.
+		amount_sats: u64,
+		/// The block height after which our counterparty can claim the HTLC back.
+		timeout_height: u32,
+	},
+	/// We offered the HTLC and can claim it back after `claimable_height`, unless our
+	/// counterparty claims it with the preimage first.
+	ClaimableAfterTimeout {
+		/// The amount, in satoshis.
+		amount_sats: u64,
+		/// The block height from which on we can claim the HTLC back.
+		claimable_height: u32,
+	},
+	/// We received the HTLC but don't know the preimage, so our counterparty will claim it back
+	/// after `expiry_height`.
+	AwaitingPreimage {
+		/// The amount, in satoshis.
+		amount_sats: u64,
+		/// The block height after which our counterparty can claim the HTLC back.
+		expiry_height: u32,
+	},
+	/// Our counterparty broadcast a revoked state and we can claim the output as a penalty.
+	RevokedOutput {
+		/// The amount, in satoshis.
+		amount_sats: u64,
+	},
+}
+
+impl ContentiousClaim {
+	/// Returns the amount of the claim, in satoshis.
+	pub fn amount_sats(&self) -> u64 {
+		match self {
+			Self::ClaimableWithPreimage { amount_sats, .. }
+			| Self::ClaimableAfterTimeout { amount_sats, .. }
+			| Self::AwaitingPreimage { amount_sats, .. }
+			| Self::RevokedOutput { amount_sats } => *amount_sats,
+		}
+	}
+}
+
+impl ChannelBalance {
+	fn new(channel_id: [u8; 32], is_open: bool, balances: &[Balance]) -> Self {
+		let mut lightning_balance_sats = 0;
+		let mut awaiting_confirmation_sats = 0;
+		let mut timelocked_balances = Vec::new();
+		let mut contentious_claims = Vec::new();
+		for balance in balances {
+			match *balance {
+				Balance::ClaimableOnChannelClose { claimable_amount_satoshis } => {
+					if is_open {
+						lightning_balance_sats += claimable_amount_satoshis;
+					} else {
+						awaiting_confirmation_sats += claimable_amount_satoshis;
+					}
+				}
+				Balance::ClaimableAwaitingConfirmations {
+					claimable_amount_satoshis,
+					confirmation_height,
+				} => {
+					timelocked_balances.push(TimelockedBalance {
+						amount_sats: claimable_amount_satoshis,
+						maturity_height: confirmation_height,
+					});
+				}
+				Balance::ContentiousClaimable { claimable_amount_satoshis, timeout_height } => {
+					contentious_claims.push(ContentiousClaim::ClaimableWithPreimage {
+						amount_sats: claimable_amount_satoshis,
+						timeout_height,
+					});
+				}
+				Balance::MaybeTimeoutClaimableHTLC {
+					claimable_amount_satoshis,
+					claimable_height,
+				} => {
+					contentious_claims.push(ContentiousClaim::ClaimableAfterTimeout {
+						amount_sats: claimable_amount_satoshis,
+						claimable_height,
+					});
+				}
+				Balance::MaybePreimageClaimableHTLC {
+					claimable_amount_satoshis,
+					expiry_height,
+				} => {
+					contentious_claims.push(ContentiousClaim::AwaitingPreimage {
+						amount_sats: claimable_amount_satoshis,
+						expiry_height,
+					});
+				}
+				Balance::CounterpartyRevokedOutputClaimable { claimable_amount_satoshis } => {
+					contentious_claims.push(ContentiousClaim::RevokedOutput {
+						amount_sats: claimable_amount_satoshis,
+					});
+				}
+			}
+		}
+		timelocked_balances.sort_by_key(|b| b.maturity_height);
+
+		Self {
+			channel_id,
+			is_open,
+			lightning_balance_sats,
+			awaiting_confirmation_sats,
+			timelocked_balances,
+			contentious_claims,
+		}
+	}
+
+	/// Returns the total amount encumbered by timelocks, in satoshis.
+	pub fn timelocked_sats(&self) -> u64 {
+		self.timelocked_balances.iter().map(|b| b.amount_sats).sum()
+	}
+
+	/// Returns the total amount of all contentious claims, in satoshis.
+	pub fn contentious_sats(&self) -> u64 {
+		self.contentious_claims.iter().map(|c| c.amount_sats()).sum()
+	}
+}
+
+/// Reports the balances of the on-chain wallet and of our channels.
+pub struct BalanceReporter {
+	wallet: Arc<Wallet<bdk::sled::Tree>>,
+	chain_monitor: Arc<ChainMonitor>,
+	channel_manager: Arc<ChannelManager>,
+}
+
+impl BalanceReporter {
+	pub(crate) fn new(
+		wallet: Arc<Wallet<bdk::sled::Tree>>, chain_monitor: Arc<ChainMonitor>,
+		channel_manager: Arc<ChannelManager>,
+	) -> Self {
+		Self { wallet, chain_monitor, channel_manager }
+	}
+
+	/// Retrieves the current balances of the on-chain wallet and of all channels that still hold
+	/// funds, including closed channels that are waiting to be resolved on-chain.
+	pub fn balance_details(&self) -> Result<BalanceDetails, Error> {
+		let onchain_balance = self.wallet.get_balance()?;
+
+		let open_channel_ids = self
+			.channel_manager
+			.list_channels()
+			.iter()
+			.map(|c| c.channel_id)
+			.collect::<HashSet<_>>();
+
+		let mut channel_balances = Vec::new();
+		for funding_txo in self.chain_monitor.list_monitors() {
+			let monitor = match self.chain_monitor.get_monitor(funding_txo) {
+				Ok(monitor) => monitor,
+				Err(()) => continue,
+			};
+
+			let balances = monitor.get_claimable_balances();
+			if balances.is_empty() {
+				continue;
+			}
+
+			let channel_id = funding_txo.to_channel_id();
+			let is_open = open_channel_ids.contains(&channel_id);
+			channel_balances.push(ChannelBalance::new(channel_id, is_open, &balances));
+		}
+
+		let total_lightning_balance_sats =
+			channel_balances.iter().map(|b| b.lightning_balance_sats).sum();
+		let total_awaiting_confirmation_sats =
+			channel_balances.iter().map(|b| b.awaiting_confirmation_sats).sum();
+		let total_timelocked_sats = channel_balances.iter().map(|b| b.timelocked_sats()).sum();
+		let total_contentious_sats = channel_balances.iter().map(|b| b.contentious_sats()).sum();
+
+		Ok(BalanceDetails {
+			total_onchain_balance_sats: onchain_balance.get_total(),
+			spendable_onchain_balance_sats: onchain_balance.get_spendable(),
+			total_lightning_balance_sats,
+			total_awaiting_confirmation_sats,
+			total_timelocked_sats,
+			total_contentious_sats,
+			channel_balances,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn channel_balance_from_monitor_balances() {
+		let balances = vec![
+			Balance::ClaimableOnChannelClose { claimable_amount_satoshis: 10_000 },
+			Balance::ClaimableAwaitingConfirmations {
+				claimable_amount_satoshis: 2_000,
+				confirmation_height: 150,
+			},
+			Balance::ClaimableAwaitingConfirmations {
+				claimable_amount_satoshis: 1_000,
+				confirmation_height: 120,
+			},
+			Balance::ContentiousClaimable { claimable_amount_satoshis: 300, timeout_height: 140 },
+			Balance::MaybeTimeoutClaimableHTLC {
+				claimable_amount_satoshis: 200,
+				claimable_height: 130,
+			},
+			Balance::CounterpartyRevokedOutputClaimable { claimable_amount_satoshis: 100 },
+		];
+
+		let open_balance = ChannelBalance::new([1u8; 32], true, &balances);
+		assert_eq!(open_balance.lightning_balance_sats, 10_000);
+		assert_eq!(open_balance.awaiting_confirmation_sats, 0);
+		assert_eq!(open_balance.timelocked_sats(), 3_000);
+		assert_eq!(open_balance.contentious_sats(), 600);
+		assert_eq!(
+			open_balance.contentious_claims[0],
+			ContentiousClaim::ClaimableWithPreimage { amount_sats: 300, timeout_height: 140 }
+		);
+
+		// The balance claimable on close waits for the closing transaction once closed.
+		let closed_balance = ChannelBalance::new([1u8; 32], false, &balances);
+		assert_eq!(closed_balance.lightning_balance_sats, 0);
+		assert_eq!(closed_balance.awaiting_confirmation_sats, 10_000);
+	}
+
+	#[test]
+	fn timelocked_balances_sorted_by_maturity() {
+		let balances = vec![
+			Balance::ClaimableAwaitingConfirmations {
+				claimable_amount_satoshis: 3_000,
+				confirmation_height: 300,
+			},
+			Balance::ClaimableAwaitingConfirmations {
+				claimable_amount_satoshis: 1_000,
+				confirmation_height: 100,
+			},
+			Balance::ClaimableAwaitingConfirmations {
+				claimable_amount_satoshis: 2_000,
+				confirmation_height: 200,
+			},
+		];
+
+		let channel_balance = ChannelBalance::new([1u8; 32], false, &balances);
+		let maturity_heights = channel_balance
+			.timelocked_balances
+			.iter()
+			.map(|b| b.maturity_height)
+			.collect::<Vec<_>>();
+		assert_eq!(maturity_heights, vec![100, 200, 300]);
+		assert_eq!(channel_balance.timelocked_balances[0].amount_sats, 1_000);
+	}
+}
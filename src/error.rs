@@ -19,6 +19,42 @@ pub enum LdkLiteError {
 	ConnectionFailed,
 	/// Payment of the given invoice has already been intiated.
 	NonUniquePaymentHash,
+	/// The given custom TLV records are invalid.
+	InvalidCustomTlvs,
+	/// Sending a payment has failed.
+	PaymentSendingFailed,
+	/// The given amount is invalid.
+	InvalidAmount,
+	/// A BOLT12 offer could not be created.
+	OfferCreationFailed,
+	/// A BOLT12 refund could not be created.
+	RefundCreationFailed,
+	/// A payment for a BOLT12 refund could not be requested.
+	RefundRequestFailed,
+	/// The given payment is unknown.
+	PaymentNotFound,
+	/// The given payment can't be claimed or cancelled in its current state.
+	PaymentNotClaimable,
+	/// The given payment can't be abandoned in its current state.
+	PaymentNotAbandonable,
+	/// The given onion message TLV type is reserved for the protocol.
+	InvalidOnionMessageType,
+	/// No route to the given destination could be found.
+	RouteNotFound,
+	/// Sending a probe has failed.
+	ProbeSendingFailed,
+	/// The given scorer data could not be decoded.
+	InvalidScorerData,
+	/// Syncing the network graph via rapid gossip sync has failed.
+	GossipSyncFailed,
+	/// The config of a channel could not be updated.
+	ChannelConfigUpdateFailed,
+	/// The given forwarding policy is invalid.
+	InvalidForwardingPolicy,
+	/// A rebalance could not be initiated.
+	RebalanceFailed,
+	/// A request to an LSP failed or timed out.
+	LspRequestFailed,
 	/// A given peer info could not be parsed.
 	PeerInfoParse(&'static str),
 	/// A wrapped LDK `APIError`
@@ -51,6 +87,38 @@ impl fmt::Display for LdkLiteError {
 			}
 			LdkLiteError::ConnectionFailed => write!(f, "network connection closed"),
 			LdkLiteError::NonUniquePaymentHash => write!(f, "an invoice must not get payed twice."),
+			LdkLiteError::InvalidCustomTlvs => {
+				write!(f, "custom TLV records must have unique types of at least 2^16")
+			}
+			LdkLiteError::PaymentSendingFailed => write!(f, "sending the payment failed"),
+			LdkLiteError::InvalidAmount => write!(f, "the given amount is invalid"),
+			LdkLiteError::OfferCreationFailed => write!(f, "the offer could not be created"),
+			LdkLiteError::RefundCreationFailed => write!(f, "the refund could not be created"),
+			LdkLiteError::RefundRequestFailed => {
+				write!(f, "the refund payment could not be requested")
+			}
+			LdkLiteError::PaymentNotFound => write!(f, "the given payment is unknown"),
+			LdkLiteError::PaymentNotClaimable => {
+				write!(f, "the payment can't be claimed or cancelled in its current state")
+			}
+			LdkLiteError::PaymentNotAbandonable => {
+				write!(f, "the payment can't be abandoned in its current state")
+			}
+			LdkLiteError::InvalidOnionMessageType => {
+				write!(f, "custom onion message types must be at least 64")
+			}
+			LdkLiteError::RouteNotFound => write!(f, "no route to the destination could be found"),
+			LdkLiteError::ProbeSendingFailed => write!(f, "sending the probe failed"),
+			LdkLiteError::InvalidScorerData => write!(f, "the given scorer data is invalid"),
+			LdkLiteError::GossipSyncFailed => write!(f, "rapid gossip sync failed"),
+			LdkLiteError::ChannelConfigUpdateFailed => {
+				write!(f, "the channel config could not be updated")
+			}
+			LdkLiteError::InvalidForwardingPolicy => {
+				write!(f, "the given forwarding policy is invalid")
+			}
+			LdkLiteError::RebalanceFailed => write!(f, "the rebalance could not be initiated"),
+			LdkLiteError::LspRequestFailed => write!(f, "the request to the LSP failed"),
 			LdkLiteError::PeerInfoParse(ref e) => {
 				write!(f, "given peer info could not be parsed: {}", e)
 			}
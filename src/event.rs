@@ -1,26 +1,37 @@
-use crate::{
-	hex_utils, ChannelManager, Config, Error, KeysManager, NetworkGraph, PaymentInfo,
-	PaymentInfoStorage, PaymentStatus, Wallet,
-};
+use crate::{hex_utils, ChannelManager, Config, Error, KeysManager, NetworkGraph, Wallet};
 
-use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::forwarding::ForwardingHistory;
+use crate::forwarding_policy::ForwardingPolicyManager;
+use crate::forwarding_scheduler::ForwardingScheduler;
+use crate::htlc_failure::HtlcFailureTracker;
+use crate::invoice::InboundAmountPolicy;
+use crate::lsp::LspHandler;
+use crate::lsps::LspsClient;
+use crate::payment_store::{PaymentDirection, PaymentInfo, PaymentStatus, PaymentStore};
+use crate::probing::ProbeHandler;
+use crate::resolution::ResolutionTracker;
+use crate::scoring::RoutingScorer;
+
+use crate::logger::{log_error, log_given_level, log_info, log_internal, log_warn, Logger};
 
 use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget, FeeEstimator};
+use lightning::ln::channelmanager::PaymentId;
 use lightning::ln::PaymentHash;
 use lightning::routing::gossip::NodeId;
 use lightning::util::errors::APIError;
+use lightning::util::events::ClosureReason as LdkClosureReason;
 use lightning::util::events::Event as LdkEvent;
 use lightning::util::events::EventHandler as LdkEventHandler;
+use lightning::util::events::HTLCDestination;
 use lightning::util::events::PaymentPurpose;
 use lightning::util::persist::KVStorePersister;
 use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
 
 use bitcoin::secp256k1::Secp256k1;
 use rand::{thread_rng, Rng};
-use std::collections::{hash_map, VecDeque};
+use std::collections::VecDeque;
 use std::ops::Deref;
 use std::sync::{Arc, Condvar, Mutex};
-use std::time::Duration;
 
 /// The event queue will be persisted under this key.
 pub(crate) const EVENTS_PERSISTENCE_KEY: &str = "events";
@@ -32,13 +43,45 @@ pub(crate) const EVENTS_PERSISTENCE_KEY: &str = "events";
 pub enum Event {
 	/// A sent payment was successful.
 	PaymentSuccessful {
+		/// The id of the payment.
+		payment_id: PaymentId,
 		/// The hash of the payment.
 		payment_hash: PaymentHash,
 	},
 	/// A sent payment has failed.
 	PaymentFailed {
+		/// The id of the payment.
+		payment_id: PaymentId,
+		/// The hash of the payment, if it was known before the payment failed.
+		payment_hash: Option<PaymentHash>,
+	},
+	/// A refund for a previously received payment was successful.
+	RefundSuccessful {
+		/// The id of the refund payment.
+		payment_id: PaymentId,
+		/// The hash of the refund payment.
+		payment_hash: PaymentHash,
+		/// The hash of the inbound payment that was refunded.
+		refunded_payment_hash: PaymentHash,
+	},
+	/// A refund for a previously received payment has failed.
+	RefundFailed {
+		/// The id of the refund payment.
+		payment_id: PaymentId,
+		/// The hash of the inbound payment that was to be refunded.
+		refunded_payment_hash: PaymentHash,
+	},
+	/// A payment for a hold invoice has arrived and awaits being claimed or cancelled.
+	///
+	/// If neither happens in time, the payment will automatically be failed back before
+	/// `claim_deadline`.
+	PaymentClaimable {
 		/// The hash of the payment.
 		payment_hash: PaymentHash,
+		/// The value, in thousandths of a satoshi, that is claimable.
+		amount_msat: u64,
+		/// The block height at which the payment can no longer be claimed, if known.
+		claim_deadline: Option<u32>,
 	},
 	/// A payment has been received.
 	PaymentReceived {
@@ -46,6 +89,48 @@ pub enum Event {
 		payment_hash: PaymentHash,
 		/// The value, in thousandths of a satoshi, that has been received.
 		amount_msat: u64,
+		/// The custom TLV records the sender included with a spontaneous payment.
+		custom_tlvs: Vec<(u64, Vec<u8>)>,
+	},
+	/// An inbound payment has been rejected and failed back to the sender.
+	PaymentRejected {
+		/// The hash of the payment.
+		payment_hash: PaymentHash,
+		/// The value, in thousandths of a satoshi, that was offered.
+		amount_msat: u64,
+		/// The reason why the payment was rejected.
+		reason: PaymentRejectionReason,
+	},
+	/// A payment has been forwarded from one of our channels to another.
+	PaymentForwarded {
+		/// The `channel_id` of the channel the payment arrived on, if known.
+		prev_channel_id: Option<[u8; 32]>,
+		/// The `channel_id` of the channel the payment was forwarded over, if known.
+		next_channel_id: Option<[u8; 32]>,
+		/// The amount forwarded to the next hop, in thousandths of a satoshi, if known.
+		outbound_amount_msat: Option<u64>,
+		/// The fee we earned, in thousandths of a satoshi, if known.
+		fee_earned_msat: Option<u64>,
+		/// Whether we claimed the inbound HTLC on-chain.
+		claim_from_onchain_tx: bool,
+	},
+	/// An HTLC we were to forward or receive has been failed back.
+	///
+	/// Failures are also counted per channel by the [`HtlcFailureTracker`].
+	///
+	/// [`HtlcFailureTracker`]: crate::htlc_failure::HtlcFailureTracker
+	HtlcHandlingFailed {
+		/// The `channel_id` of the channel the HTLC arrived on.
+		prev_channel_id: [u8; 32],
+		/// Where the HTLC was headed when it was failed, see [`HtlcFailureReason`].
+		reason: HtlcFailureReason,
+	},
+	/// A custom onion message of a registered TLV type has been received.
+	OnionMessageReceived {
+		/// The TLV type of the message.
+		tlv_type: u64,
+		/// The raw message data.
+		data: Vec<u8>,
 	},
 	/// A channel is ready to be used.
 	ChannelReady {
@@ -55,12 +140,290 @@ pub enum Event {
 		user_channel_id: u128,
 	},
 	/// A channel has been closed.
+	///
+	/// Any balance still locked in the channel may only become spendable later on. The on-chain
+	/// resolution of the channel can be tracked via [`ResolutionTracker`].
+	///
+	/// [`ResolutionTracker`]: crate::resolution::ResolutionTracker
 	ChannelClosed {
 		/// The `channel_id` of the channel.
 		channel_id: [u8; 32],
 		/// The `user_channel_id` of the channel.
 		user_channel_id: u128,
+		/// The reason why the channel was closed.
+		reason: ClosureReason,
+	},
+	/// All funds of a previously closed channel have been claimed on-chain and were handed to the
+	/// on-chain wallet.
+	ChannelResolved {
+		/// The `channel_id` of the channel.
+		channel_id: [u8; 32],
+		/// The `user_channel_id` of the channel.
+		user_channel_id: u128,
+	},
+}
+
+/// The reason a channel was closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClosureReason {
+	/// Our counterparty force-closed the channel, providing the given error message.
+	CounterpartyForceClosed {
+		/// The error message sent by our counterparty.
+		///
+		/// Note that this is provided by our counterparty and hence should not be trusted.
+		peer_msg: String,
 	},
+	/// We force-closed the channel, e.g., upon request of the user.
+	HolderForceClosed,
+	/// The channel was closed cooperatively.
+	CooperativeClosure,
+	/// A commitment transaction was confirmed on-chain, closing the channel.
+	CommitmentTxConfirmed,
+	/// The funding transaction failed to confirm in a timely manner.
+	FundingTimedOut,
+	/// The channel was closed due to an error while processing a message or event.
+	ProcessingError {
+		/// A developer-readable description of the error.
+		err: String,
+	},
+	/// The peer disconnected before the funding transaction was negotiated.
+	DisconnectedPeer,
+	/// The channel manager was out of date and the channel monitor closed the channel.
+	OutdatedChannelManager,
+}
+
+impl From<LdkClosureReason> for ClosureReason {
+	fn from(reason: LdkClosureReason) -> Self {
+		match reason {
+			LdkClosureReason::CounterpartyForceClosed { peer_msg } => {
+				Self::CounterpartyForceClosed { peer_msg }
+			}
+			LdkClosureReason::HolderForceClosed => Self::HolderForceClosed,
+			LdkClosureReason::CooperativeClosure => Self::CooperativeClosure,
+			LdkClosureReason::CommitmentTxConfirmed => Self::CommitmentTxConfirmed,
+			LdkClosureReason::FundingTimedOut => Self::FundingTimedOut,
+			LdkClosureReason::ProcessingError { err } => Self::ProcessingError { err },
+			LdkClosureReason::DisconnectedPeer => Self::DisconnectedPeer,
+			LdkClosureReason::OutdatedChannelManager => Self::OutdatedChannelManager,
+		}
+	}
+}
+
+impl Readable for ClosureReason {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		match Readable::read(reader)? {
+			0u8 => {
+				let peer_msg: String = Readable::read(reader)?;
+				Ok(Self::CounterpartyForceClosed { peer_msg })
+			}
+			1u8 => Ok(Self::HolderForceClosed),
+			2u8 => Ok(Self::CooperativeClosure),
+			3u8 => Ok(Self::CommitmentTxConfirmed),
+			4u8 => Ok(Self::FundingTimedOut),
+			5u8 => {
+				let err: String = Readable::read(reader)?;
+				Ok(Self::ProcessingError { err })
+			}
+			6u8 => Ok(Self::DisconnectedPeer),
+			7u8 => Ok(Self::OutdatedChannelManager),
+			_ => Err(lightning::ln::msgs::DecodeError::InvalidValue),
+		}
+	}
+}
+
+impl Writeable for ClosureReason {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		match self {
+			Self::CounterpartyForceClosed { peer_msg } => {
+				0u8.write(writer)?;
+				peer_msg.write(writer)?;
+			}
+			Self::HolderForceClosed => 1u8.write(writer)?,
+			Self::CooperativeClosure => 2u8.write(writer)?,
+			Self::CommitmentTxConfirmed => 3u8.write(writer)?,
+			Self::FundingTimedOut => 4u8.write(writer)?,
+			Self::ProcessingError { err } => {
+				5u8.write(writer)?;
+				err.write(writer)?;
+			}
+			Self::DisconnectedPeer => 6u8.write(writer)?,
+			Self::OutdatedChannelManager => 7u8.write(writer)?,
+		}
+		Ok(())
+	}
+}
+
+/// The reason an inbound payment was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentRejectionReason {
+	/// The payment paid less than the amount requested by the invoice.
+	Underpaid {
+		/// The amount requested by the invoice, in thousandths of a satoshi.
+		invoice_amount_msat: u64,
+	},
+	/// The payment paid more than the configured overpayment tolerance allows.
+	///
+	/// See [`InboundAmountPolicy`] for how to configure the tolerance.
+	///
+	/// [`InboundAmountPolicy`]: crate::invoice::InboundAmountPolicy
+	Overpaid {
+		/// The amount requested by the invoice, in thousandths of a satoshi.
+		invoice_amount_msat: u64,
+		/// The maximum amount we would have accepted, in thousandths of a satoshi.
+		max_amount_msat: u64,
+	},
+	/// We don't know the preimage of the payment.
+	PreimageUnknown,
+	/// The payment was abandoned via [`PaymentStore::abandon_payment`].
+	///
+	/// [`PaymentStore::abandon_payment`]: crate::payment_store::PaymentStore::abandon_payment
+	Abandoned,
+}
+
+/// A classification of why an HTLC we were to forward or receive was failed.
+///
+/// LDK only tells us where a failed HTLC was headed, not why it was failed. The variants are
+/// therefore heuristics based on that destination and, for failures on our own channels, on the
+/// state of the channel when we learn about the failure. In particular, HTLCs paying too little
+/// in fees and HTLCs expiring too soon can't be told apart, and both end up as
+/// [`InvalidForward`].
+///
+/// [`InvalidForward`]: Self::InvalidForward
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HtlcFailureReason {
+	/// The HTLC was to be forwarded over a channel we don't know, e.g., to an unknown peer.
+	UnknownNextHop {
+		/// The `short_channel_id` the HTLC was to be forwarded over.
+		requested_forward_scid: u64,
+	},
+	/// The HTLC was to be forwarded over one of our channels, which was closed or whose
+	/// counterparty was disconnected by the time the failure was reported.
+	///
+	/// The channel may still have been usable when the HTLC was failed.
+	NextChannelUnavailable {
+		/// The `channel_id` of the channel.
+		channel_id: [u8; 32],
+	},
+	/// The HTLC was to be forwarded over one of our channels, which was still usable when the
+	/// failure was reported.
+	///
+	/// The channel couldn't take the HTLC, e.g., as it lacked the outbound liquidity or the HTLC
+	/// violated the counterparty's limits, but the exact reason is unknown.
+	NextChannelFailed {
+		/// The `channel_id` of the channel.
+		channel_id: [u8; 32],
+	},
+	/// The HTLC was rejected before forwarding, e.g., as it paid too little in fees, expired too
+	/// soon or its onion was invalid. LDK doesn't tell which.
+	InvalidForward {
+		/// The `short_channel_id` the HTLC was to be forwarded over.
+		requested_forward_scid: u64,
+	},
+	/// The HTLC was destined to us but we failed the payment, e.g., as we rejected it.
+	ReceiveFailed {
+		/// The hash of the payment.
+		payment_hash: PaymentHash,
+	},
+}
+
+impl Readable for HtlcFailureReason {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		match Readable::read(reader)? {
+			0u8 => {
+				let requested_forward_scid: u64 = Readable::read(reader)?;
+				Ok(Self::UnknownNextHop { requested_forward_scid })
+			}
+			1u8 => {
+				let channel_id: [u8; 32] = Readable::read(reader)?;
+				Ok(Self::NextChannelUnavailable { channel_id })
+			}
+			2u8 => {
+				let channel_id: [u8; 32] = Readable::read(reader)?;
+				Ok(Self::NextChannelFailed { channel_id })
+			}
+			3u8 => {
+				let requested_forward_scid: u64 = Readable::read(reader)?;
+				Ok(Self::InvalidForward { requested_forward_scid })
+			}
+			4u8 => {
+				let payment_hash: PaymentHash = Readable::read(reader)?;
+				Ok(Self::ReceiveFailed { payment_hash })
+			}
+			_ => Err(lightning::ln::msgs::DecodeError::InvalidValue),
+		}
+	}
+}
+
+impl Writeable for HtlcFailureReason {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		match self {
+			Self::UnknownNextHop { requested_forward_scid } => {
+				0u8.write(writer)?;
+				requested_forward_scid.write(writer)?;
+			}
+			Self::NextChannelUnavailable { channel_id } => {
+				1u8.write(writer)?;
+				channel_id.write(writer)?;
+			}
+			Self::NextChannelFailed { channel_id } => {
+				2u8.write(writer)?;
+				channel_id.write(writer)?;
+			}
+			Self::InvalidForward { requested_forward_scid } => {
+				3u8.write(writer)?;
+				requested_forward_scid.write(writer)?;
+			}
+			Self::ReceiveFailed { payment_hash } => {
+				4u8.write(writer)?;
+				payment_hash.write(writer)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Readable for PaymentRejectionReason {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		match Readable::read(reader)? {
+			0u8 => {
+				let invoice_amount_msat: u64 = Readable::read(reader)?;
+				Ok(Self::Underpaid { invoice_amount_msat })
+			}
+			1u8 => {
+				let invoice_amount_msat: u64 = Readable::read(reader)?;
+				let max_amount_msat: u64 = Readable::read(reader)?;
+				Ok(Self::Overpaid { invoice_amount_msat, max_amount_msat })
+			}
+			2u8 => Ok(Self::PreimageUnknown),
+			3u8 => Ok(Self::Abandoned),
+			_ => Err(lightning::ln::msgs::DecodeError::InvalidValue),
+		}
+	}
+}
+
+impl Writeable for PaymentRejectionReason {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		match self {
+			Self::Underpaid { invoice_amount_msat } => {
+				0u8.write(writer)?;
+				invoice_amount_msat.write(writer)?;
+			}
+			Self::Overpaid { invoice_amount_msat, max_amount_msat } => {
+				1u8.write(writer)?;
+				invoice_amount_msat.write(writer)?;
+				max_amount_msat.write(writer)?;
+			}
+			Self::PreimageUnknown => 2u8.write(writer)?,
+			Self::Abandoned => 3u8.write(writer)?,
+		}
+		Ok(())
+	}
 }
 
 // TODO: Figure out serialization more concretely - see issue #30
@@ -70,17 +433,26 @@ impl Readable for Event {
 	) -> Result<Self, lightning::ln::msgs::DecodeError> {
 		match Readable::read(reader)? {
 			0u8 => {
+				let payment_id: PaymentId = Readable::read(reader)?;
 				let payment_hash: PaymentHash = Readable::read(reader)?;
-				Ok(Self::PaymentSuccessful { payment_hash })
+				Ok(Self::PaymentSuccessful { payment_id, payment_hash })
 			}
 			1u8 => {
-				let payment_hash: PaymentHash = Readable::read(reader)?;
-				Ok(Self::PaymentFailed { payment_hash })
+				let payment_id: PaymentId = Readable::read(reader)?;
+				let payment_hash: Option<PaymentHash> = Readable::read(reader)?;
+				Ok(Self::PaymentFailed { payment_id, payment_hash })
 			}
 			2u8 => {
 				let payment_hash: PaymentHash = Readable::read(reader)?;
 				let amount_msat: u64 = Readable::read(reader)?;
-				Ok(Self::PaymentReceived { payment_hash, amount_msat })
+				let tlvs_len: u16 = Readable::read(reader)?;
+				let mut custom_tlvs = Vec::with_capacity(tlvs_len as usize);
+				for _ in 0..tlvs_len {
+					let tlv_type: u64 = Readable::read(reader)?;
+					let tlv_value: Vec<u8> = Readable::read(reader)?;
+					custom_tlvs.push((tlv_type, tlv_value));
+				}
+				Ok(Self::PaymentReceived { payment_hash, amount_msat, custom_tlvs })
 			}
 			3u8 => {
 				let channel_id: [u8; 32] = Readable::read(reader)?;
@@ -90,7 +462,60 @@ impl Readable for Event {
 			4u8 => {
 				let channel_id: [u8; 32] = Readable::read(reader)?;
 				let user_channel_id: u128 = Readable::read(reader)?;
-				Ok(Self::ChannelClosed { channel_id, user_channel_id })
+				let reason: ClosureReason = Readable::read(reader)?;
+				Ok(Self::ChannelClosed { channel_id, user_channel_id, reason })
+			}
+			5u8 => {
+				let channel_id: [u8; 32] = Readable::read(reader)?;
+				let user_channel_id: u128 = Readable::read(reader)?;
+				Ok(Self::ChannelResolved { channel_id, user_channel_id })
+			}
+			6u8 => {
+				let payment_id: PaymentId = Readable::read(reader)?;
+				let payment_hash: PaymentHash = Readable::read(reader)?;
+				let refunded_payment_hash: PaymentHash = Readable::read(reader)?;
+				Ok(Self::RefundSuccessful { payment_id, payment_hash, refunded_payment_hash })
+			}
+			7u8 => {
+				let payment_id: PaymentId = Readable::read(reader)?;
+				let refunded_payment_hash: PaymentHash = Readable::read(reader)?;
+				Ok(Self::RefundFailed { payment_id, refunded_payment_hash })
+			}
+			8u8 => {
+				let tlv_type: u64 = Readable::read(reader)?;
+				let data: Vec<u8> = Readable::read(reader)?;
+				Ok(Self::OnionMessageReceived { tlv_type, data })
+			}
+			9u8 => {
+				let payment_hash: PaymentHash = Readable::read(reader)?;
+				let amount_msat: u64 = Readable::read(reader)?;
+				let claim_deadline: Option<u32> = Readable::read(reader)?;
+				Ok(Self::PaymentClaimable { payment_hash, amount_msat, claim_deadline })
+			}
+			10u8 => {
+				let payment_hash: PaymentHash = Readable::read(reader)?;
+				let amount_msat: u64 = Readable::read(reader)?;
+				let reason: PaymentRejectionReason = Readable::read(reader)?;
+				Ok(Self::PaymentRejected { payment_hash, amount_msat, reason })
+			}
+			11u8 => {
+				let prev_channel_id: Option<[u8; 32]> = Readable::read(reader)?;
+				let next_channel_id: Option<[u8; 32]> = Readable::read(reader)?;
+				let outbound_amount_msat: Option<u64> = Readable::read(reader)?;
+				let fee_earned_msat: Option<u64> = Readable::read(reader)?;
+				let claim_from_onchain_tx: bool = Readable::read(reader)?;
+				Ok(Self::PaymentForwarded {
+					prev_channel_id,
+					next_channel_id,
+					outbound_amount_msat,
+					fee_earned_msat,
+					claim_from_onchain_tx,
+				})
+			}
+			12u8 => {
+				let prev_channel_id: [u8; 32] = Readable::read(reader)?;
+				let reason: HtlcFailureReason = Readable::read(reader)?;
+				Ok(Self::HtlcHandlingFailed { prev_channel_id, reason })
 			}
 			_ => Err(lightning::ln::msgs::DecodeError::InvalidValue),
 		}
@@ -100,20 +525,27 @@ impl Readable for Event {
 impl Writeable for Event {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
 		match self {
-			Self::PaymentSuccessful { payment_hash } => {
+			Self::PaymentSuccessful { payment_id, payment_hash } => {
 				0u8.write(writer)?;
+				payment_id.write(writer)?;
 				payment_hash.write(writer)?;
 				Ok(())
 			}
-			Self::PaymentFailed { payment_hash } => {
+			Self::PaymentFailed { payment_id, payment_hash } => {
 				1u8.write(writer)?;
+				payment_id.write(writer)?;
 				payment_hash.write(writer)?;
 				Ok(())
 			}
-			Self::PaymentReceived { payment_hash, amount_msat } => {
+			Self::PaymentReceived { payment_hash, amount_msat, custom_tlvs } => {
 				2u8.write(writer)?;
 				payment_hash.write(writer)?;
 				amount_msat.write(writer)?;
+				(custom_tlvs.len() as u16).write(writer)?;
+				for (tlv_type, tlv_value) in custom_tlvs.iter() {
+					tlv_type.write(writer)?;
+					tlv_value.write(writer)?;
+				}
 				Ok(())
 			}
 			Self::ChannelReady { channel_id, user_channel_id } => {
@@ -122,10 +554,71 @@ impl Writeable for Event {
 				user_channel_id.write(writer)?;
 				Ok(())
 			}
-			Self::ChannelClosed { channel_id, user_channel_id } => {
+			Self::ChannelClosed { channel_id, user_channel_id, reason } => {
 				4u8.write(writer)?;
 				channel_id.write(writer)?;
 				user_channel_id.write(writer)?;
+				reason.write(writer)?;
+				Ok(())
+			}
+			Self::ChannelResolved { channel_id, user_channel_id } => {
+				5u8.write(writer)?;
+				channel_id.write(writer)?;
+				user_channel_id.write(writer)?;
+				Ok(())
+			}
+			Self::RefundSuccessful { payment_id, payment_hash, refunded_payment_hash } => {
+				6u8.write(writer)?;
+				payment_id.write(writer)?;
+				payment_hash.write(writer)?;
+				refunded_payment_hash.write(writer)?;
+				Ok(())
+			}
+			Self::RefundFailed { payment_id, refunded_payment_hash } => {
+				7u8.write(writer)?;
+				payment_id.write(writer)?;
+				refunded_payment_hash.write(writer)?;
+				Ok(())
+			}
+			Self::OnionMessageReceived { tlv_type, data } => {
+				8u8.write(writer)?;
+				tlv_type.write(writer)?;
+				data.write(writer)?;
+				Ok(())
+			}
+			Self::PaymentClaimable { payment_hash, amount_msat, claim_deadline } => {
+				9u8.write(writer)?;
+				payment_hash.write(writer)?;
+				amount_msat.write(writer)?;
+				claim_deadline.write(writer)?;
+				Ok(())
+			}
+			Self::PaymentRejected { payment_hash, amount_msat, reason } => {
+				10u8.write(writer)?;
+				payment_hash.write(writer)?;
+				amount_msat.write(writer)?;
+				reason.write(writer)?;
+				Ok(())
+			}
+			Self::PaymentForwarded {
+				prev_channel_id,
+				next_channel_id,
+				outbound_amount_msat,
+				fee_earned_msat,
+				claim_from_onchain_tx,
+			} => {
+				11u8.write(writer)?;
+				prev_channel_id.write(writer)?;
+				next_channel_id.write(writer)?;
+				outbound_amount_msat.write(writer)?;
+				fee_earned_msat.write(writer)?;
+				claim_from_onchain_tx.write(writer)?;
+				Ok(())
+			}
+			Self::HtlcHandlingFailed { prev_channel_id, reason } => {
+				12u8.write(writer)?;
+				prev_channel_id.write(writer)?;
+				reason.write(writer)?;
 				Ok(())
 			}
 		}
@@ -238,11 +731,19 @@ where
 	channel_manager: Arc<ChannelManager>,
 	network_graph: Arc<NetworkGraph>,
 	keys_manager: Arc<KeysManager>,
-	inbound_payments: Arc<PaymentInfoStorage>,
-	outbound_payments: Arc<PaymentInfoStorage>,
-	tokio_runtime: Arc<tokio::runtime::Runtime>,
+	payment_store: Arc<PaymentStore<K>>,
+	resolution_tracker: Arc<ResolutionTracker<K, L>>,
+	probe_handler: Arc<ProbeHandler<K, L>>,
+	routing_scorer: Arc<RoutingScorer<K>>,
+	forwarding_history: Arc<ForwardingHistory<K>>,
+	forwarding_policy_manager: Arc<ForwardingPolicyManager<K, L>>,
+	htlc_failure_tracker: Arc<HtlcFailureTracker>,
+	lsp_handler: Option<Arc<LspHandler<K, L>>>,
+	lsps_client: Option<Arc<LspsClient<K, L>>>,
+	forwarding_scheduler: Arc<ForwardingScheduler>,
+	inbound_amount_policy: InboundAmountPolicy,
 	logger: L,
-	_config: Arc<Config>,
+	config: Arc<Config>,
 }
 
 impl<K: Deref, L: Deref> EventHandler<K, L>
@@ -253,9 +754,13 @@ where
 	pub fn new(
 		wallet: Arc<Wallet<bdk::sled::Tree>>, event_queue: Arc<EventQueue<K>>,
 		channel_manager: Arc<ChannelManager>, network_graph: Arc<NetworkGraph>,
-		keys_manager: Arc<KeysManager>, inbound_payments: Arc<PaymentInfoStorage>,
-		outbound_payments: Arc<PaymentInfoStorage>, tokio_runtime: Arc<tokio::runtime::Runtime>,
-		logger: L, _config: Arc<Config>,
+		keys_manager: Arc<KeysManager>, payment_store: Arc<PaymentStore<K>>,
+		resolution_tracker: Arc<ResolutionTracker<K, L>>, probe_handler: Arc<ProbeHandler<K, L>>,
+		routing_scorer: Arc<RoutingScorer<K>>, forwarding_history: Arc<ForwardingHistory<K>>,
+		forwarding_policy_manager: Arc<ForwardingPolicyManager<K, L>>,
+		htlc_failure_tracker: Arc<HtlcFailureTracker>, lsp_handler: Option<Arc<LspHandler<K, L>>>,
+		lsps_client: Option<Arc<LspsClient<K, L>>>, forwarding_scheduler: Arc<ForwardingScheduler>,
+		inbound_amount_policy: InboundAmountPolicy, logger: L, config: Arc<Config>,
 	) -> Self {
 		Self {
 			event_queue,
@@ -263,11 +768,82 @@ where
 			channel_manager,
 			network_graph,
 			keys_manager,
-			inbound_payments,
-			outbound_payments,
+			payment_store,
+			resolution_tracker,
+			probe_handler,
+			routing_scorer,
+			forwarding_history,
+			forwarding_policy_manager,
+			htlc_failure_tracker,
+			lsp_handler,
+			lsps_client,
+			forwarding_scheduler,
+			inbound_amount_policy,
 			logger,
-			tokio_runtime,
-			_config,
+			config,
+		}
+	}
+}
+
+impl<K: Deref, L: Deref> EventHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	fn update_payment_status(&self, payment_id: &PaymentId, status: PaymentStatus) {
+		match self.payment_store.update(payment_id, |p| (p.status, p.set_status(status))) {
+			Ok(Some((_, true))) | Ok(None) => {}
+			Ok(Some((current_status, false))) => {
+				log_warn!(
+					self.logger,
+					"Ignoring invalid status transition of payment {} from {:?} to {:?}.",
+					hex_utils::to_string(&payment_id.0),
+					current_status,
+					status,
+				);
+			}
+			Err(e) => {
+				log_error!(self.logger, "Failed to update payment status: {}", e);
+			}
+		}
+	}
+
+	fn refunded_payment_hash(&self, payment_id: &PaymentId) -> Option<PaymentHash> {
+		self.payment_store.get(payment_id).and_then(|p| p.refund_for)
+	}
+
+	fn payment_failed(&self, payment_id: PaymentId, payment_hash: Option<PaymentHash>) {
+		let event = match self.refunded_payment_hash(&payment_id) {
+			Some(refunded_payment_hash) => {
+				Event::RefundFailed { payment_id, refunded_payment_hash }
+			}
+			None => Event::PaymentFailed { payment_id, payment_hash },
+		};
+		self.event_queue.add_event(event).expect("Failed to push to event queue");
+	}
+
+	fn reject_payment(
+		&self, payment_hash: PaymentHash, amount_msat: u64, reason: PaymentRejectionReason,
+	) {
+		log_warn!(
+			self.logger,
+			"Rejecting payment with hash {} of {} msats: {:?}",
+			hex_utils::to_string(&payment_hash.0),
+			amount_msat,
+			reason,
+		);
+		self.channel_manager.fail_htlc_backwards(&payment_hash);
+		if reason != PaymentRejectionReason::Abandoned {
+			self.update_payment_status(&PaymentId(payment_hash.0), PaymentStatus::Failed);
+		}
+		self.event_queue
+			.add_event(Event::PaymentRejected { payment_hash, amount_msat, reason })
+			.expect("Failed to push to event queue");
+	}
+
+	fn update_resolutions(&self) {
+		if let Err(e) = self.resolution_tracker.update() {
+			log_error!(self.logger, "Failed to update channel resolutions: {}", e);
 		}
 	}
 }
@@ -341,8 +917,11 @@ where
 				purpose,
 				amount_msat,
 				receiver_node_id: _,
-				via_channel_id: _,
+				via_channel_id,
 				via_user_channel_id: _,
+				onion_fields,
+				claim_deadline,
+				counterparty_skimmed_fee_msat,
 			} => {
 				log_info!(
 					self.logger,
@@ -350,6 +929,119 @@ where
 					hex_utils::to_string(&payment_hash.0),
 					amount_msat,
 				);
+
+				let payment_id = PaymentId(payment_hash.0);
+				let stored_payment = self
+					.payment_store
+					.get(&payment_id)
+					.filter(|p| p.direction == PaymentDirection::Inbound);
+
+				if stored_payment.as_ref().map_or(false, |p| p.status == PaymentStatus::Abandoned) {
+					self.reject_payment(
+						payment_hash,
+						amount_msat,
+						PaymentRejectionReason::Abandoned,
+					);
+					return;
+				}
+
+				// Payments via JIT channels arrive with the LSP's opening fee deducted. We only
+				// accept that for invoices routed via a JIT channel we bought from the LSP, up to
+				// the fee agreed for that channel.
+				let sent_amount_msat = amount_msat + counterparty_skimmed_fee_msat;
+				let mut jit_channel_scid = None;
+				if counterparty_skimmed_fee_msat > 0 {
+					let lsp_node_id = via_channel_id.and_then(|channel_id| {
+						self.channel_manager
+							.list_channels()
+							.into_iter()
+							.find(|c| c.channel_id == channel_id)
+							.map(|c| c.counterparty.node_id)
+					});
+					jit_channel_scid = match (self.lsps_client.as_ref(), lsp_node_id) {
+						(Some(lsps_client), Some(lsp_node_id)) => lsps_client
+							.jit_channel_for_skimmed_fee(
+								&lsp_node_id,
+								&payment_hash,
+								stored_payment.as_ref().and_then(|p| p.invoice.as_deref()),
+								sent_amount_msat,
+								counterparty_skimmed_fee_msat,
+							),
+						_ => None,
+					};
+					if jit_channel_scid.is_none() {
+						let invoice_amount_msat = stored_payment
+							.as_ref()
+							.and_then(|p| p.amount_msat)
+							.unwrap_or(sent_amount_msat);
+						let reason = PaymentRejectionReason::Underpaid { invoice_amount_msat };
+						self.reject_payment(payment_hash, amount_msat, reason);
+						return;
+					}
+				}
+
+				// Check the amount against the one requested by the invoice, if any. For hold
+				// invoices, we overwrite the stored amount with the received one once we've
+				// accepted it, so a replayed event will pass the check again.
+				if let Some(invoice_amount_msat) =
+					stored_payment.as_ref().and_then(|p| p.amount_msat)
+				{
+					if let Err(reason) =
+						self.inbound_amount_policy.check(invoice_amount_msat, sent_amount_msat)
+					{
+						self.reject_payment(payment_hash, amount_msat, reason);
+						return;
+					}
+				}
+
+				// The JIT channel is consumed by this payment and can't authorize another fee.
+				if let (Some(lsps_client), Some(intercept_scid)) =
+					(self.lsps_client.as_ref(), jit_channel_scid)
+				{
+					lsps_client
+						.mark_jit_channel_used(intercept_scid, payment_hash)
+						.expect("Failed to persist JIT channels");
+				}
+
+				// Payments for hold invoices are only claimed once the user supplies the preimage.
+				if stored_payment.map_or(false, |p| p.hold_invoice) {
+					self.payment_store
+						.update(&payment_id, |p| {
+							p.amount_msat = Some(amount_msat);
+							p.claim_deadline = claim_deadline;
+						})
+						.expect("Failed to persist payment");
+					self.update_payment_status(&payment_id, PaymentStatus::InFlight);
+					self.event_queue
+						.add_event(Event::PaymentClaimable {
+							payment_hash,
+							amount_msat,
+							claim_deadline,
+						})
+						.expect("Failed to push to event queue");
+					return;
+				}
+
+				// Remember any custom TLVs so we can report them once the payment is claimed.
+				let custom_tlvs = onion_fields.map(|f| f.custom_tlvs().clone()).unwrap_or_default();
+				let updated = self
+					.payment_store
+					.update(&payment_id, |p| p.custom_tlvs = custom_tlvs.clone())
+					.expect("Failed to persist payment");
+				if updated.is_none() {
+					if let PaymentPurpose::SpontaneousPayment(preimage) = purpose {
+						let mut payment = PaymentInfo::new(
+							payment_id,
+							Some(payment_hash),
+							PaymentDirection::Inbound,
+							Some(amount_msat),
+							PaymentStatus::InFlight,
+						);
+						payment.preimage = Some(preimage);
+						payment.custom_tlvs = custom_tlvs;
+						self.payment_store.insert(payment).expect("Failed to persist payment");
+					}
+				}
 				let payment_preimage = match purpose {
 					PaymentPurpose::InvoicePayment { payment_preimage, payment_secret } => {
 						if payment_preimage.is_some() {
@@ -366,13 +1058,11 @@ where
 				if let Some(preimage) = payment_preimage {
 					self.channel_manager.claim_funds(preimage);
 				} else {
-					log_error!(
-						self.logger,
-						"Failed to claim payment with hash {}: preimage unknown.",
-						hex_utils::to_string(&payment_hash.0),
+					self.reject_payment(
+						payment_hash,
+						amount_msat,
+						PaymentRejectionReason::PreimageUnknown,
 					);
-					self.channel_manager.fail_htlc_backwards(&payment_hash);
-					self.inbound_payments.lock().unwrap().remove(&payment_hash);
 				}
 			}
 			LdkEvent::PaymentClaimed {
@@ -393,33 +1083,60 @@ where
 					}
 					PaymentPurpose::SpontaneousPayment(preimage) => (Some(preimage), None),
 				};
-				let mut payments = self.inbound_payments.lock().unwrap();
-				match payments.entry(payment_hash) {
-					hash_map::Entry::Occupied(mut e) => {
-						let payment = e.get_mut();
-						payment.status = PaymentStatus::Succeeded;
+
+				// The inbound side of a rebalance is our own payment arriving back. It is tracked
+				// via its outbound side, which completes on `PaymentSent`.
+				if self.payment_store.get_by_hash(&payment_hash).map_or(false, |p| p.rebalance) {
+					log_info!(
+						self.logger,
+						"Rebalance payment with hash {} arrived back.",
+						hex_utils::to_string(&payment_hash.0)
+					);
+					return;
+				}
+
+				let payment_id = PaymentId(payment_hash.0);
+				let updated = self
+					.payment_store
+					.update(&payment_id, |payment| {
 						payment.preimage = payment_preimage;
 						payment.secret = payment_secret;
-					}
-					hash_map::Entry::Vacant(e) => {
-						e.insert(PaymentInfo {
-							preimage: payment_preimage,
-							secret: payment_secret,
-							status: PaymentStatus::Succeeded,
-							amount_msat: Some(amount_msat),
-						});
-					}
+						payment.amount_msat = Some(amount_msat);
+					})
+					.expect("Failed to persist payment");
+				if updated.is_some() {
+					self.update_payment_status(&payment_id, PaymentStatus::Succeeded);
+				} else {
+					let mut payment = PaymentInfo::new(
+						payment_id,
+						Some(payment_hash),
+						PaymentDirection::Inbound,
+						Some(amount_msat),
+						PaymentStatus::Succeeded,
+					);
+					payment.preimage = payment_preimage;
+					payment.secret = payment_secret;
+					self.payment_store.insert(payment).expect("Failed to persist payment");
 				}
+				let custom_tlvs =
+					self.payment_store.get(&payment_id).map(|p| p.custom_tlvs).unwrap_or_default();
 				self.event_queue
-					.add_event(Event::PaymentReceived { payment_hash, amount_msat })
+					.add_event(Event::PaymentReceived { payment_hash, amount_msat, custom_tlvs })
 					.expect("Failed to push to event queue");
 			}
-			LdkEvent::PaymentSent { payment_preimage, payment_hash, fee_paid_msat, .. } => {
-				let mut payments = self.outbound_payments.lock().unwrap();
-				for (hash, payment) in payments.iter_mut() {
-					if *hash == payment_hash {
+			LdkEvent::PaymentSent {
+				payment_id,
+				payment_preimage,
+				payment_hash,
+				fee_paid_msat,
+				..
+			} => {
+				let payment_id = payment_id.unwrap_or(PaymentId(payment_hash.0));
+				self.payment_store
+					.update(&payment_id, |payment| {
+						payment.payment_hash = Some(payment_hash);
 						payment.preimage = Some(payment_preimage);
-						payment.status = PaymentStatus::Succeeded;
+						payment.fee_msat = fee_paid_msat;
 						log_info!(
 							self.logger,
 							"Successfully sent payment of {} msats{} from \
@@ -433,46 +1150,107 @@ where
 							hex_utils::to_string(&payment_hash.0),
 							hex_utils::to_string(&payment_preimage.0)
 						);
-						break;
+					})
+					.expect("Failed to persist payment");
+				self.update_payment_status(&payment_id, PaymentStatus::Succeeded);
+				let event = match self.refunded_payment_hash(&payment_id) {
+					Some(refunded_payment_hash) => {
+						Event::RefundSuccessful { payment_id, payment_hash, refunded_payment_hash }
 					}
-				}
-				self.event_queue
-					.add_event(Event::PaymentSuccessful { payment_hash })
-					.expect("Failed to push to event queue");
+					None => Event::PaymentSuccessful { payment_id, payment_hash },
+				};
+				self.event_queue.add_event(event).expect("Failed to push to event queue");
 			}
-			LdkEvent::PaymentFailed { payment_hash, .. } => {
+			LdkEvent::PaymentFailed { payment_id, payment_hash, .. } => {
 				log_info!(
 					self.logger,
 					"Failed to send payment to payment hash {:?}.",
 					hex_utils::to_string(&payment_hash.0)
 				);
 
-				let mut payments = self.outbound_payments.lock().unwrap();
-				if payments.contains_key(&payment_hash) {
-					let payment = payments.get_mut(&payment_hash).unwrap();
-					assert_eq!(payment.status, PaymentStatus::Pending);
-					payment.status = PaymentStatus::Failed;
+				self.update_payment_status(&payment_id, PaymentStatus::Failed);
+				self.payment_failed(payment_id, Some(payment_hash));
+			}
+			LdkEvent::InvoiceRequestFailed { payment_id } => {
+				log_info!(
+					self.logger,
+					"Failed to request an invoice for payment {}.",
+					hex_utils::to_string(&payment_id.0)
+				);
+
+				self.update_payment_status(&payment_id, PaymentStatus::Failed);
+				self.payment_failed(payment_id, None);
+			}
+
+			LdkEvent::PaymentPathSuccessful { path, .. } => {
+				self.routing_scorer.payment_path_successful(&path);
+			}
+			LdkEvent::PaymentPathFailed {
+				payment_id,
+				payment_hash,
+				payment_failed_permanently,
+				path,
+				short_channel_id,
+				..
+			} => {
+				if let Some(scid) = short_channel_id {
+					self.routing_scorer.payment_path_failed(&path, scid);
+				} else if payment_failed_permanently {
+					// The destination failed the payment itself, so the path had sufficient
+					// liquidity all the way.
+					self.routing_scorer.probe_successful(&path);
 				}
+
+				if !payment_failed_permanently {
+					let payment_id = payment_id.unwrap_or(PaymentId(payment_hash.0));
+					self.update_payment_status(&payment_id, PaymentStatus::Retrying);
+				}
+			}
+			LdkEvent::ProbeSuccessful { payment_id, path, .. } => {
+				self.probe_handler.probe_successful(payment_id, &path);
+			}
+			LdkEvent::ProbeFailed { payment_id, path, short_channel_id, .. } => {
+				self.probe_handler.probe_failed(payment_id, &path, short_channel_id);
+			}
+			LdkEvent::HTLCHandlingFailed { prev_channel_id, failed_next_destination } => {
+				let reason = match failed_next_destination {
+					HTLCDestination::NextHopChannel { channel_id, .. } => {
+						// LDK doesn't tell us why the channel failed the HTLC, so all we can go by
+						// is whether it's still usable.
+						let is_usable = self
+							.channel_manager
+							.list_usable_channels()
+							.iter()
+							.any(|c| c.channel_id == channel_id);
+						if is_usable {
+							HtlcFailureReason::NextChannelFailed { channel_id }
+						} else {
+							HtlcFailureReason::NextChannelUnavailable { channel_id }
+						}
+					}
+					HTLCDestination::UnknownNextHop { requested_forward_scid } => {
+						HtlcFailureReason::UnknownNextHop { requested_forward_scid }
+					}
+					HTLCDestination::InvalidForward { requested_forward_scid } => {
+						HtlcFailureReason::InvalidForward { requested_forward_scid }
+					}
+					HTLCDestination::FailedPayment { payment_hash } => {
+						HtlcFailureReason::ReceiveFailed { payment_hash }
+					}
+				};
+				log_warn!(
+					self.logger,
+					"Failed HTLC that arrived on channel {}: {:?}",
+					hex_utils::to_string(&prev_channel_id),
+					reason
+				);
+				self.htlc_failure_tracker.record_failure(prev_channel_id, &reason);
 				self.event_queue
-					.add_event(Event::PaymentFailed { payment_hash })
+					.add_event(Event::HtlcHandlingFailed { prev_channel_id, reason })
 					.expect("Failed to push to event queue");
 			}
-
-			LdkEvent::PaymentPathSuccessful { .. } => {}
-			LdkEvent::PaymentPathFailed { .. } => {}
-			LdkEvent::ProbeSuccessful { .. } => {}
-			LdkEvent::ProbeFailed { .. } => {}
-			LdkEvent::HTLCHandlingFailed { .. } => {}
 			LdkEvent::PendingHTLCsForwardable { time_forwardable } => {
-				let forwarding_channel_manager = self.channel_manager.clone();
-				let min = time_forwardable.as_millis() as u64;
-
-				self.tokio_runtime.spawn(async move {
-					let millis_to_sleep = thread_rng().gen_range(min..min * 5) as u64;
-					tokio::time::sleep(Duration::from_millis(millis_to_sleep)).await;
-
-					forwarding_channel_manager.process_pending_htlc_forwards();
-				});
+				self.forwarding_scheduler.forwards_pending(time_forwardable);
 			}
 			LdkEvent::SpendableOutputs { outputs } => {
 				// TODO: We should eventually remember the outputs and supply them to the wallet's coin selection, once BDK allows us to do so.
@@ -491,14 +1269,60 @@ where
 					)
 					.unwrap();
 				self.wallet.broadcast_transaction(&spending_tx);
+
+				self.update_resolutions();
+			}
+			LdkEvent::OpenChannelRequest { temporary_channel_id, counterparty_node_id, .. } => {
+				let user_channel_id: u128 = thread_rng().gen();
+				// LSPs we bought JIT channels from are trusted to open zero-conf channels to us.
+				let is_lsp =
+					self.lsps_client.as_ref().map_or(false, |c| c.is_lsp(&counterparty_node_id));
+				let res = if is_lsp {
+					self.channel_manager.accept_inbound_channel_from_trusted_peer_0conf(
+						&temporary_channel_id,
+						&counterparty_node_id,
+						user_channel_id,
+					)
+				} else {
+					self.channel_manager.accept_inbound_channel(
+						&temporary_channel_id,
+						&counterparty_node_id,
+						user_channel_id,
+					)
+				};
+				if let Err(e) = res {
+					log_error!(
+						self.logger,
+						"Failed to accept inbound channel from {}: {:?}",
+						counterparty_node_id,
+						e
+					);
+				}
 			}
-			LdkEvent::OpenChannelRequest { .. } => {}
 			LdkEvent::PaymentForwarded {
 				prev_channel_id,
 				next_channel_id,
 				fee_earned_msat,
 				claim_from_onchain_tx,
+				outbound_amount_forwarded_msat,
 			} => {
+				self.forwarding_history.record_forward(
+					prev_channel_id,
+					next_channel_id,
+					outbound_amount_forwarded_msat,
+					fee_earned_msat,
+					claim_from_onchain_tx,
+				);
+				self.event_queue
+					.add_event(Event::PaymentForwarded {
+						prev_channel_id,
+						next_channel_id,
+						outbound_amount_msat: outbound_amount_forwarded_msat,
+						fee_earned_msat,
+						claim_from_onchain_tx,
+					})
+					.expect("Failed to push to event queue");
+
 				let read_only_network_graph = self.network_graph.read_only();
 				let nodes = read_only_network_graph.nodes();
 				let channels = self.channel_manager.list_channels();
@@ -560,6 +1384,12 @@ where
 					hex_utils::to_string(&channel_id),
 					counterparty_node_id,
 				);
+				if let Err(e) = self.forwarding_policy_manager.channel_ready(channel_id) {
+					log_error!(self.logger, "Failed to apply forwarding policy: {}", e);
+				}
+				if let Some(lsp_handler) = self.lsp_handler.as_ref() {
+					lsp_handler.channel_ready(user_channel_id, channel_id);
+				}
 				self.event_queue
 					.add_event(Event::ChannelReady { channel_id, user_channel_id })
 					.expect("Failed to push to event queue");
@@ -571,12 +1401,48 @@ where
 					hex_utils::to_string(&channel_id),
 					reason
 				);
+				self.resolution_tracker
+					.channel_closed(channel_id, user_channel_id, reason.clone().into())
+					.expect("Failed to persist closed channel");
+				self.forwarding_policy_manager
+					.channel_closed(channel_id)
+					.expect("Failed to persist forwarding policies");
+				if let Some(lsp_handler) = self.lsp_handler.as_ref() {
+					lsp_handler.channel_closed(user_channel_id);
+				}
 				self.event_queue
-					.add_event(Event::ChannelClosed { channel_id, user_channel_id })
+					.add_event(Event::ChannelClosed {
+						channel_id,
+						user_channel_id,
+						reason: reason.into(),
+					})
 					.expect("Failed to push to event queue");
+				self.update_resolutions();
 			}
 			LdkEvent::DiscardFunding { .. } => {}
-			LdkEvent::HTLCIntercepted { .. } => {}
+			LdkEvent::HTLCIntercepted {
+				intercept_id,
+				requested_next_hop_scid,
+				payment_hash,
+				expected_outbound_amount_msat,
+				..
+			} => match self.lsp_handler.as_ref() {
+				Some(lsp_handler) => lsp_handler.htlc_intercepted(
+					intercept_id,
+					requested_next_hop_scid,
+					payment_hash,
+					expected_outbound_amount_msat,
+				),
+				None => {
+					log_warn!(
+						self.logger,
+						"Failing intercepted HTLC as we're not running in LSP mode."
+					);
+					if let Err(e) = self.channel_manager.fail_intercepted_htlc(intercept_id) {
+						log_error!(self.logger, "Failed to fail intercepted HTLC: {:?}", e);
+					}
+				}
+			},
 		}
 	}
 }
@@ -0,0 +1,441 @@
+use crate::forwarding::{ForwardingHistory, ForwardingQuery};
+use crate::forwarding_policy::ForwardingPolicyManager;
+use crate::hex_utils;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::payment_store::unix_time_secs;
+use crate::{ChannelManager, Error};
+
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The fee adjustments will be persisted under this key.
+pub(crate) const FEE_ADJUSTMENTS_PERSISTENCE_KEY: &str = "fee_adjustments";
+
+/// The maximum number of fee adjustments we keep in the history.
+const MAX_FEE_ADJUSTMENTS: usize = 10_000;
+
+/// The configuration of the [`FeeAutopilot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeAutopilotConfig {
+	/// The interval in which fees are adjusted.
+	pub interval: Duration,
+	/// The lowest proportional fee the autopilot will set, used when all of a channel's
+	/// liquidity is on our side.
+	pub min_fee_proportional_millionths: u32,
+	/// The highest proportional fee the autopilot will set, used when all of a channel's
+	/// liquidity is on our counterparty's side.
+	pub max_fee_proportional_millionths: u32,
+	/// The time window, in seconds, of forwarding volume that is considered.
+	///
+	/// The more of a channel's capacity was forwarded over it in this window, the further its fee
+	/// is raised towards the maximum.
+	pub volume_window_secs: u64,
+	/// The minimum change, in millionths, for which the fee of a channel is updated.
+	///
+	/// Every update is broadcast to the network, so updates should be limited to significant
+	/// changes.
+	pub min_fee_change_millionths: u32,
+}
+
+impl Default for FeeAutopilotConfig {
+	fn default() -> Self {
+		Self {
+			interval: Duration::from_secs(60 * 60),
+			min_fee_proportional_millionths: 1,
+			max_fee_proportional_millionths: 2_000,
+			volume_window_secs: 7 * 24 * 60 * 60,
+			min_fee_change_millionths: 10,
+		}
+	}
+}
+
+/// A change of a channel's fee made by the [`FeeAutopilot`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeAdjustment {
+	/// The `channel_id` of the channel.
+	pub channel_id: [u8; 32],
+	/// The proportional fee before the change.
+	pub old_fee_proportional_millionths: u32,
+	/// The proportional fee after the change.
+	pub new_fee_proportional_millionths: u32,
+	/// The share of the channel's liquidity on our side at the time of the change, in
+	/// millionths.
+	pub local_balance_millionths: u32,
+	/// The amount forwarded over the channel in the considered time window, in thousandths of a
+	/// satoshi.
+	pub outbound_volume_msat: u64,
+	/// The time of the change, in seconds since the UNIX epoch.
+	pub timestamp: u64,
+}
+
+impl Readable for FeeAdjustment {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let channel_id: [u8; 32] = Readable::read(reader)?;
+		let old_fee_proportional_millionths: u32 = Readable::read(reader)?;
+		let new_fee_proportional_millionths: u32 = Readable::read(reader)?;
+		let local_balance_millionths: u32 = Readable::read(reader)?;
+		let outbound_volume_msat: u64 = Readable::read(reader)?;
+		let timestamp: u64 = Readable::read(reader)?;
+		Ok(Self {
+			channel_id,
+			old_fee_proportional_millionths,
+			new_fee_proportional_millionths,
+			local_balance_millionths,
+			outbound_volume_msat,
+			timestamp,
+		})
+	}
+}
+
+impl Writeable for FeeAdjustment {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		self.channel_id.write(writer)?;
+		self.old_fee_proportional_millionths.write(writer)?;
+		self.new_fee_proportional_millionths.write(writer)?;
+		self.local_balance_millionths.write(writer)?;
+		self.outbound_volume_msat.write(writer)?;
+		self.timestamp.write(writer)?;
+		Ok(())
+	}
+}
+
+/// Periodically adjusts the forwarding fees of our channels based on their liquidity balance and
+/// recent forwarding volume.
+///
+/// Channels that are depleted on our side, or see a lot of outbound volume, get more expensive,
+/// while channels with plenty of local liquidity get cheaper. Every change is recorded and can be
+/// retrieved via [`list_adjustments`].
+///
+/// Channels for which a proportional fee was set via
+/// [`ForwardingPolicyManager::set_channel_policy`] are left alone.
+///
+/// [`list_adjustments`]: Self::list_adjustments
+pub struct FeeAutopilot<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	channel_manager: Arc<ChannelManager>,
+	forwarding_history: Arc<ForwardingHistory<K>>,
+	forwarding_policy_manager: Arc<ForwardingPolicyManager<K, L>>,
+	config: FeeAutopilotConfig,
+	adjustments: Arc<Mutex<Vec<FeeAdjustment>>>,
+	persister: K,
+	logger: L,
+}
+
+impl<K: Deref + Clone + Send + Sync + 'static, L: Deref + Clone + Send + Sync + 'static>
+	FeeAutopilot<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, forwarding_history: Arc<ForwardingHistory<K>>,
+		forwarding_policy_manager: Arc<ForwardingPolicyManager<K, L>>, config: FeeAutopilotConfig,
+		persister: K, tokio_runtime: Arc<tokio::runtime::Runtime>, logger: L,
+	) -> Self {
+		let adjustments = Arc::new(Mutex::new(Vec::new()));
+		let autopilot = Self {
+			channel_manager,
+			forwarding_history,
+			forwarding_policy_manager,
+			config,
+			adjustments,
+			persister,
+			logger,
+		};
+		autopilot.start(&tokio_runtime);
+		autopilot
+	}
+
+	// Starts adjusting fees every `config.interval`.
+	fn start(&self, tokio_runtime: &tokio::runtime::Runtime) {
+		let adjust_channel_manager = Arc::clone(&self.channel_manager);
+		let adjust_forwarding_history = Arc::clone(&self.forwarding_history);
+		let adjust_forwarding_policy_manager = Arc::clone(&self.forwarding_policy_manager);
+		let adjust_config = self.config.clone();
+		let adjust_adjustments = Arc::clone(&self.adjustments);
+		let adjust_persister = self.persister.clone();
+		let adjust_logger = self.logger.clone();
+		tokio_runtime.spawn(async move {
+			let mut interval = tokio::time::interval(adjust_config.interval);
+			loop {
+				interval.tick().await;
+				if let Err(e) = adjust_fees(
+					&adjust_channel_manager,
+					&adjust_forwarding_history,
+					&adjust_forwarding_policy_manager,
+					&adjust_config,
+					&adjust_adjustments,
+					&adjust_persister,
+					&adjust_logger,
+				) {
+					log_error!(adjust_logger, "Failed to adjust channel fees: {}", e);
+				}
+			}
+		});
+	}
+}
+
+impl<K: Deref, L: Deref> FeeAutopilot<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	/// Returns the fee adjustments made, newest first, optionally only those of the channel with
+	/// the given `channel_id` or those made at or after the given time.
+	pub fn list_adjustments(
+		&self, channel_id: Option<[u8; 32]>, made_after: Option<u64>,
+	) -> Vec<FeeAdjustment> {
+		let locked_adjustments = self.adjustments.lock().unwrap();
+		locked_adjustments
+			.iter()
+			.rev()
+			.filter(|a| channel_id.map_or(true, |id| a.channel_id == id))
+			.filter(|a| made_after.map_or(true, |t| a.timestamp >= t))
+			.cloned()
+			.collect()
+	}
+}
+
+impl<K: Deref + Clone + Send + Sync + 'static, L: Deref + Clone + Send + Sync + 'static>
+	ReadableArgs<(
+		Arc<ChannelManager>,
+		Arc<ForwardingHistory<K>>,
+		Arc<ForwardingPolicyManager<K, L>>,
+		FeeAutopilotConfig,
+		K,
+		Arc<tokio::runtime::Runtime>,
+		L,
+	)> for FeeAutopilot<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+		args: (
+			Arc<ChannelManager>,
+			Arc<ForwardingHistory<K>>,
+			Arc<ForwardingPolicyManager<K, L>>,
+			FeeAutopilotConfig,
+			K,
+			Arc<tokio::runtime::Runtime>,
+			L,
+		),
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let (
+			channel_manager,
+			forwarding_history,
+			forwarding_policy_manager,
+			config,
+			persister,
+			tokio_runtime,
+			logger,
+		) = args;
+		let read_adjustments: FeeAdjustmentsDeserWrapper = Readable::read(reader)?;
+		let adjustments = Arc::new(Mutex::new(read_adjustments.0));
+		let autopilot = Self {
+			channel_manager,
+			forwarding_history,
+			forwarding_policy_manager,
+			config,
+			adjustments,
+			persister,
+			logger,
+		};
+		autopilot.start(&tokio_runtime);
+		Ok(autopilot)
+	}
+}
+
+struct FeeAdjustmentsDeserWrapper(Vec<FeeAdjustment>);
+
+impl Readable for FeeAdjustmentsDeserWrapper {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let len: u64 = Readable::read(reader)?;
+		let mut adjustments = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			adjustments.push(Readable::read(reader)?);
+		}
+		Ok(Self(adjustments))
+	}
+}
+
+struct FeeAdjustmentsSerWrapper<'a>(&'a [FeeAdjustment]);
+
+impl Writeable for FeeAdjustmentsSerWrapper<'_> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		(self.0.len() as u64).write(writer)?;
+		for adjustment in self.0.iter() {
+			adjustment.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+/// Adjusts the fees of all usable channels without a user-set proportional fee.
+///
+/// Returns the adjustments that were made.
+fn adjust_fees<K: Deref, L: Deref>(
+	channel_manager: &ChannelManager, forwarding_history: &ForwardingHistory<K>,
+	forwarding_policy_manager: &ForwardingPolicyManager<K, L>, config: &FeeAutopilotConfig,
+	adjustments: &Mutex<Vec<FeeAdjustment>>, persister: &K, logger: &L,
+) -> Result<Vec<FeeAdjustment>, Error>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	let now = unix_time_secs();
+	let volume_query = ForwardingQuery {
+		completed_after: Some(now.saturating_sub(config.volume_window_secs)),
+		..Default::default()
+	};
+	let outbound_volumes = forwarding_history
+		.channel_summaries(&volume_query)
+		.into_iter()
+		.map(|s| (s.channel_id, s.outbound_amount_msat))
+		.collect::<HashMap<_, _>>();
+
+	let mut new_adjustments = Vec::new();
+	for channel in channel_manager.list_usable_channels() {
+		if forwarding_policy_manager.has_fee_override(&channel.channel_id) {
+			continue;
+		}
+		let current_fee = match channel.config {
+			Some(config) => config.forwarding_fee_proportional_millionths,
+			None => continue,
+		};
+
+		let local_msat = channel.outbound_capacity_msat;
+		let remote_msat = channel.inbound_capacity_msat;
+		let outbound_volume_msat = outbound_volumes.get(&channel.channel_id).copied().unwrap_or(0);
+		let target_fee = target_fee_proportional_millionths(
+			config,
+			local_msat,
+			remote_msat,
+			outbound_volume_msat,
+		);
+		if target_fee.abs_diff(current_fee) < config.min_fee_change_millionths {
+			continue;
+		}
+
+		if let Err(e) = forwarding_policy_manager.set_autopilot_fee(channel.channel_id, target_fee)
+		{
+			log_error!(
+				logger,
+				"Failed to adjust fee of channel {}: {}",
+				hex_utils::to_string(&channel.channel_id),
+				e
+			);
+			continue;
+		}
+
+		let local_balance_millionths = local_balance_millionths(local_msat, remote_msat);
+		log_info!(
+			logger,
+			"Adjusted fee of channel {} from {} to {} ppm, with {} ppm of its liquidity local and {} msats of outbound volume.",
+			hex_utils::to_string(&channel.channel_id),
+			current_fee,
+			target_fee,
+			local_balance_millionths,
+			outbound_volume_msat,
+		);
+		new_adjustments.push(FeeAdjustment {
+			channel_id: channel.channel_id,
+			old_fee_proportional_millionths: current_fee,
+			new_fee_proportional_millionths: target_fee,
+			local_balance_millionths,
+			outbound_volume_msat,
+			timestamp: now,
+		});
+	}
+
+	if !new_adjustments.is_empty() {
+		let mut locked_adjustments = adjustments.lock().unwrap();
+		locked_adjustments.extend(new_adjustments.iter().cloned());
+		let num_excess = locked_adjustments.len().saturating_sub(MAX_FEE_ADJUSTMENTS);
+		locked_adjustments.drain(..num_excess);
+		persist_adjustments(persister, &locked_adjustments)?;
+	}
+	Ok(new_adjustments)
+}
+
+fn persist_adjustments<K: Deref>(persister: &K, adjustments: &[FeeAdjustment]) -> Result<(), Error>
+where
+	K::Target: KVStorePersister,
+{
+	persister
+		.persist(FEE_ADJUSTMENTS_PERSISTENCE_KEY, &FeeAdjustmentsSerWrapper(adjustments))
+		.map_err(|_| Error::PersistenceFailed)?;
+	Ok(())
+}
+
+fn local_balance_millionths(local_msat: u64, remote_msat: u64) -> u32 {
+	let total_msat = local_msat as u128 + remote_msat as u128;
+	if total_msat == 0 {
+		return 0;
+	}
+	(local_msat as u128 * 1_000_000 / total_msat) as u32
+}
+
+// Interpolates the fee between the configured bounds according to how much of the channel's
+// liquidity is on our side, then raises it further the more volume we forwarded over the channel.
+fn target_fee_proportional_millionths(
+	config: &FeeAutopilotConfig, local_msat: u64, remote_msat: u64, outbound_volume_msat: u64,
+) -> u32 {
+	let min_fee = config.min_fee_proportional_millionths as u64;
+	let max_fee =
+		config.max_fee_proportional_millionths.max(config.min_fee_proportional_millionths) as u64;
+	let fee_range = max_fee - min_fee;
+
+	let local_share = local_balance_millionths(local_msat, remote_msat) as u64;
+	let balance_fee = max_fee - fee_range * local_share / 1_000_000;
+
+	let capacity_msat = local_msat.saturating_add(remote_msat);
+	let volume_share = if capacity_msat == 0 {
+		0
+	} else {
+		(outbound_volume_msat as u128 * 1_000_000 / capacity_msat as u128).min(1_000_000) as u64
+	};
+	// Volume can at most take us halfway from the balance-based fee to the maximum.
+	let volume_premium = (max_fee - balance_fee) * volume_share / 2_000_000;
+
+	(balance_fee + volume_premium) as u32
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn target_fee_within_bounds() {
+		let config = FeeAutopilotConfig {
+			min_fee_proportional_millionths: 100,
+			max_fee_proportional_millionths: 1_100,
+			..Default::default()
+		};
+
+		// Depleted channels are most expensive, full ones cheapest.
+		assert_eq!(target_fee_proportional_millionths(&config, 0, 1_000_000, 0), 1_100);
+		assert_eq!(target_fee_proportional_millionths(&config, 1_000_000, 0, 0), 100);
+		assert_eq!(target_fee_proportional_millionths(&config, 500_000, 500_000, 0), 600);
+
+		// Outbound volume raises the fee towards the maximum.
+		assert_eq!(target_fee_proportional_millionths(&config, 500_000, 500_000, 500_000), 725);
+		assert_eq!(target_fee_proportional_millionths(&config, 500_000, 500_000, u64::MAX), 850);
+
+		assert_eq!(target_fee_proportional_millionths(&config, 0, 0, 0), 1_100);
+	}
+}
@@ -0,0 +1,453 @@
+use crate::logger::{log_error, log_given_level, log_internal, FilesystemLogger, Logger};
+use crate::payment_store::unix_time_secs;
+use crate::Error;
+
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The forwarding history will be persisted under this key.
+pub(crate) const FORWARDING_HISTORY_PERSISTENCE_KEY: &str = "forwarding_history";
+
+/// The maximum number of forwards we keep in the history.
+const MAX_FORWARDS: usize = 100_000;
+
+/// The interval in which newly recorded forwards are persisted.
+const FORWARDING_HISTORY_PERSISTENCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A payment we forwarded from one of our channels to another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedPayment {
+	/// The `channel_id` of the channel the payment arrived on, if known.
+	pub prev_channel_id: Option<[u8; 32]>,
+	/// The `channel_id` of the channel the payment was forwarded over, if known.
+	pub next_channel_id: Option<[u8; 32]>,
+	/// The amount forwarded to the next hop, in thousandths of a satoshi, if known.
+	pub outbound_amount_msat: Option<u64>,
+	/// The fee we earned, in thousandths of a satoshi, if known.
+	pub fee_earned_msat: Option<u64>,
+	/// Whether we claimed the inbound HTLC on-chain, after the channel it arrived on was closed.
+	pub claim_from_onchain_tx: bool,
+	/// The time at which the forward was completed, in seconds since the UNIX epoch.
+	pub timestamp: u64,
+}
+
+impl ForwardedPayment {
+	/// Returns the amount that arrived on the previous channel, in thousandths of a satoshi, if
+	/// known.
+	pub fn inbound_amount_msat(&self) -> Option<u64> {
+		Some(self.outbound_amount_msat? + self.fee_earned_msat?)
+	}
+}
+
+impl Readable for ForwardedPayment {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let prev_channel_id: Option<[u8; 32]> = Readable::read(reader)?;
+		let next_channel_id: Option<[u8; 32]> = Readable::read(reader)?;
+		let outbound_amount_msat: Option<u64> = Readable::read(reader)?;
+		let fee_earned_msat: Option<u64> = Readable::read(reader)?;
+		let claim_from_onchain_tx: bool = Readable::read(reader)?;
+		let timestamp: u64 = Readable::read(reader)?;
+		Ok(Self {
+			prev_channel_id,
+			next_channel_id,
+			outbound_amount_msat,
+			fee_earned_msat,
+			claim_from_onchain_tx,
+			timestamp,
+		})
+	}
+}
+
+impl Writeable for ForwardedPayment {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		self.prev_channel_id.write(writer)?;
+		self.next_channel_id.write(writer)?;
+		self.outbound_amount_msat.write(writer)?;
+		self.fee_earned_msat.write(writer)?;
+		self.claim_from_onchain_tx.write(writer)?;
+		self.timestamp.write(writer)?;
+		Ok(())
+	}
+}
+
+/// Selects forwarded payments from the [`ForwardingHistory`].
+///
+/// Matching forwards are returned newest first. The default query returns all forwards.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardingQuery {
+	/// Only select forwards that arrived on or left via the channel with the given `channel_id`.
+	pub channel_id: Option<[u8; 32]>,
+	/// Only select forwards completed at or after the given time, in seconds since the UNIX
+	/// epoch.
+	pub completed_after: Option<u64>,
+	/// Only select forwards completed before the given time, in seconds since the UNIX epoch.
+	pub completed_before: Option<u64>,
+	/// The number of matching forwards to skip.
+	pub offset: usize,
+	/// The maximum number of forwards to return.
+	pub limit: Option<usize>,
+}
+
+impl ForwardingQuery {
+	fn matches(&self, forward: &ForwardedPayment) -> bool {
+		self.channel_id.map_or(true, |id| {
+			forward.prev_channel_id == Some(id) || forward.next_channel_id == Some(id)
+		}) && self.matches_time(forward)
+	}
+
+	fn matches_time(&self, forward: &ForwardedPayment) -> bool {
+		self.completed_after.map_or(true, |t| forward.timestamp >= t)
+			&& self.completed_before.map_or(true, |t| forward.timestamp < t)
+	}
+}
+
+/// Aggregated forwarding totals of a single channel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelForwardingSummary {
+	/// The `channel_id` of the channel.
+	pub channel_id: [u8; 32],
+	/// The number of forwards that arrived on the channel.
+	pub num_inbound_forwards: u64,
+	/// The number of forwards that left via the channel.
+	pub num_outbound_forwards: u64,
+	/// The total amount that arrived on the channel, in thousandths of a satoshi.
+	pub inbound_amount_msat: u64,
+	/// The total amount that left via the channel, in thousandths of a satoshi.
+	pub outbound_amount_msat: u64,
+	/// The total fees earned from forwards that arrived on the channel, in thousandths of a
+	/// satoshi.
+	pub fees_earned_msat: u64,
+}
+
+/// Aggregated forwarding totals over all channels.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardingTotals {
+	/// The number of forwards.
+	pub num_forwards: u64,
+	/// The number of forwards we had to claim on-chain.
+	pub num_onchain_claims: u64,
+	/// The total amount forwarded, in thousandths of a satoshi.
+	pub amount_forwarded_msat: u64,
+	/// The total fees earned, in thousandths of a satoshi.
+	pub fees_earned_msat: u64,
+}
+
+struct Forwards {
+	entries: Vec<ForwardedPayment>,
+	// Whether forwards were recorded since the history was last persisted.
+	needs_persist: bool,
+}
+
+/// A persisted history of the payments we forwarded.
+///
+/// Only the latest [`MAX_FORWARDS`] forwards are kept. As the history may grow large, newly
+/// recorded forwards are persisted in batches every [`FORWARDING_HISTORY_PERSISTENCE_INTERVAL`],
+/// rather than on each forward, and one last time when the history is dropped.
+pub struct ForwardingHistory<K: Deref>
+where
+	K::Target: KVStorePersister,
+{
+	forwards: Arc<Mutex<Forwards>>,
+	persister: K,
+	logger: Arc<FilesystemLogger>,
+}
+
+impl<K: Deref + Clone + Send + Sync + 'static> ForwardingHistory<K>
+where
+	K::Target: KVStorePersister,
+{
+	pub(crate) fn new(
+		persister: K, tokio_runtime: Arc<tokio::runtime::Runtime>, logger: Arc<FilesystemLogger>,
+	) -> Self {
+		let forwards = Arc::new(Mutex::new(Forwards { entries: Vec::new(), needs_persist: false }));
+		let history = Self { forwards, persister, logger };
+		history.start(&tokio_runtime);
+		history
+	}
+
+	// Starts persisting newly recorded forwards every `FORWARDING_HISTORY_PERSISTENCE_INTERVAL`.
+	fn start(&self, tokio_runtime: &tokio::runtime::Runtime) {
+		let persist_forwards = Arc::clone(&self.forwards);
+		let persist_persister = self.persister.clone();
+		let persist_logger = Arc::clone(&self.logger);
+		tokio_runtime.spawn(async move {
+			let mut interval = tokio::time::interval(FORWARDING_HISTORY_PERSISTENCE_INTERVAL);
+			loop {
+				interval.tick().await;
+				if let Err(e) = persist_pending_forwards(&persist_forwards, &persist_persister) {
+					log_error!(persist_logger, "Failed to persist forwarding history: {}", e);
+				}
+			}
+		});
+	}
+}
+
+impl<K: Deref> ForwardingHistory<K>
+where
+	K::Target: KVStorePersister,
+{
+	pub(crate) fn record_forward(
+		&self, prev_channel_id: Option<[u8; 32]>, next_channel_id: Option<[u8; 32]>,
+		outbound_amount_msat: Option<u64>, fee_earned_msat: Option<u64>,
+		claim_from_onchain_tx: bool,
+	) -> ForwardedPayment {
+		let forward = ForwardedPayment {
+			prev_channel_id,
+			next_channel_id,
+			outbound_amount_msat,
+			fee_earned_msat,
+			claim_from_onchain_tx,
+			timestamp: unix_time_secs(),
+		};
+		let mut locked_forwards = self.forwards.lock().unwrap();
+		locked_forwards.entries.push(forward.clone());
+		let num_excess = locked_forwards.entries.len().saturating_sub(MAX_FORWARDS);
+		locked_forwards.entries.drain(..num_excess);
+		locked_forwards.needs_persist = true;
+		forward
+	}
+
+	/// Returns all forwards matching the given query, newest first.
+	pub fn list_forwards(&self, query: &ForwardingQuery) -> Vec<ForwardedPayment> {
+		let locked_forwards = self.forwards.lock().unwrap();
+		// Forwards are recorded in order, so we only need to walk the history backwards.
+		locked_forwards
+			.entries
+			.iter()
+			.rev()
+			.filter(|f| query.matches(f))
+			.skip(query.offset)
+			.take(query.limit.unwrap_or(usize::MAX))
+			.cloned()
+			.collect()
+	}
+
+	/// Returns the forwarding totals of each channel, for all forwards completed in the time
+	/// window of the given query.
+	///
+	/// The query's `channel_id`, `offset` and `limit` are ignored.
+	pub fn channel_summaries(&self, query: &ForwardingQuery) -> Vec<ChannelForwardingSummary> {
+		let locked_forwards = self.forwards.lock().unwrap();
+		let mut summaries: HashMap<[u8; 32], ChannelForwardingSummary> = HashMap::new();
+		for forward in locked_forwards.entries.iter().filter(|f| query.matches_time(f)) {
+			if let Some(channel_id) = forward.prev_channel_id {
+				let summary = summaries.entry(channel_id).or_insert_with(|| {
+					ChannelForwardingSummary { channel_id, ..Default::default() }
+				});
+				summary.num_inbound_forwards += 1;
+				summary.inbound_amount_msat += forward.inbound_amount_msat().unwrap_or(0);
+				summary.fees_earned_msat += forward.fee_earned_msat.unwrap_or(0);
+			}
+			if let Some(channel_id) = forward.next_channel_id {
+				let summary = summaries.entry(channel_id).or_insert_with(|| {
+					ChannelForwardingSummary { channel_id, ..Default::default() }
+				});
+				summary.num_outbound_forwards += 1;
+				summary.outbound_amount_msat += forward.outbound_amount_msat.unwrap_or(0);
+			}
+		}
+		summaries.into_values().collect()
+	}
+
+	/// Returns the forwarding totals over all channels, for all forwards matching the given
+	/// query.
+	///
+	/// The query's `offset` and `limit` are ignored.
+	pub fn totals(&self, query: &ForwardingQuery) -> ForwardingTotals {
+		let locked_forwards = self.forwards.lock().unwrap();
+		let mut totals = ForwardingTotals::default();
+		for forward in locked_forwards.entries.iter().filter(|f| query.matches(f)) {
+			totals.num_forwards += 1;
+			if forward.claim_from_onchain_tx {
+				totals.num_onchain_claims += 1;
+			}
+			totals.amount_forwarded_msat += forward.outbound_amount_msat.unwrap_or(0);
+			totals.fees_earned_msat += forward.fee_earned_msat.unwrap_or(0);
+		}
+		totals
+	}
+}
+
+impl<K: Deref> Drop for ForwardingHistory<K>
+where
+	K::Target: KVStorePersister,
+{
+	fn drop(&mut self) {
+		// Forwards recorded since the last persistence would otherwise be lost on shutdown.
+		if let Err(e) = persist_pending_forwards(&self.forwards, &self.persister) {
+			log_error!(self.logger, "Failed to persist forwarding history: {}", e);
+		}
+	}
+}
+
+impl<K: Deref + Clone + Send + Sync + 'static>
+	ReadableArgs<(K, Arc<tokio::runtime::Runtime>, Arc<FilesystemLogger>)> for ForwardingHistory<K>
+where
+	K::Target: KVStorePersister,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R, args: (K, Arc<tokio::runtime::Runtime>, Arc<FilesystemLogger>),
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let (persister, tokio_runtime, logger) = args;
+		let read_forwards: ForwardsDeserWrapper = Readable::read(reader)?;
+		let forwards =
+			Arc::new(Mutex::new(Forwards { entries: read_forwards.0, needs_persist: false }));
+		let history = Self { forwards, persister, logger };
+		history.start(&tokio_runtime);
+		Ok(history)
+	}
+}
+
+// Persists the forwarding history if forwards were recorded since it was last persisted.
+fn persist_pending_forwards<K: Deref>(
+	forwards: &Mutex<Forwards>, persister: &K,
+) -> Result<(), Error>
+where
+	K::Target: KVStorePersister,
+{
+	let mut locked_forwards = forwards.lock().unwrap();
+	if !locked_forwards.needs_persist {
+		return Ok(());
+	}
+	persister
+		.persist(FORWARDING_HISTORY_PERSISTENCE_KEY, &ForwardsSerWrapper(&locked_forwards.entries))
+		.map_err(|_| Error::PersistenceFailed)?;
+	locked_forwards.needs_persist = false;
+	Ok(())
+}
+
+struct ForwardsDeserWrapper(Vec<ForwardedPayment>);
+
+impl Readable for ForwardsDeserWrapper {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let len: u64 = Readable::read(reader)?;
+		let mut forwards = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			forwards.push(Readable::read(reader)?);
+		}
+		Ok(Self(forwards))
+	}
+}
+
+struct ForwardsSerWrapper<'a>(&'a [ForwardedPayment]);
+
+impl Writeable for ForwardsSerWrapper<'_> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		(self.0.len() as u64).write(writer)?;
+		for forward in self.0.iter() {
+			forward.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::test_utils::{test_filesystem_logger, TestPersister};
+
+	// The returned runtime is never driven, so the history is only persisted when we ask for it.
+	fn test_history(
+		test_name: &str,
+	) -> (ForwardingHistory<Arc<TestPersister>>, Arc<TestPersister>, Arc<tokio::runtime::Runtime>)
+	{
+		let test_persister = Arc::new(TestPersister::new());
+		let tokio_runtime =
+			Arc::new(tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap());
+		let history = ForwardingHistory::new(
+			Arc::clone(&test_persister),
+			Arc::clone(&tokio_runtime),
+			test_filesystem_logger(test_name),
+		);
+		(history, test_persister, tokio_runtime)
+	}
+
+	#[test]
+	fn forwarding_history_aggregates() {
+		let (history, _, _tokio_runtime) = test_history("forwarding_history_aggregates");
+
+		let (chan_a, chan_b, chan_c) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+		history.record_forward(Some(chan_a), Some(chan_b), Some(10_000), Some(10), false);
+		history.record_forward(Some(chan_a), Some(chan_c), Some(20_000), Some(20), true);
+		history.record_forward(Some(chan_b), Some(chan_a), Some(5_000), Some(5), false);
+
+		let totals = history.totals(&ForwardingQuery::default());
+		assert_eq!(
+			totals,
+			ForwardingTotals {
+				num_forwards: 3,
+				num_onchain_claims: 1,
+				amount_forwarded_msat: 35_000,
+				fees_earned_msat: 35,
+			}
+		);
+
+		let query = ForwardingQuery { channel_id: Some(chan_c), ..Default::default() };
+		assert_eq!(history.list_forwards(&query).len(), 1);
+		assert_eq!(history.totals(&query).fees_earned_msat, 20);
+
+		let summaries = history.channel_summaries(&ForwardingQuery::default());
+		let summary_a = summaries.iter().find(|s| s.channel_id == chan_a).unwrap();
+		assert_eq!(summary_a.num_inbound_forwards, 2);
+		assert_eq!(summary_a.num_outbound_forwards, 1);
+		assert_eq!(summary_a.inbound_amount_msat, 30_030);
+		assert_eq!(summary_a.outbound_amount_msat, 5_000);
+		assert_eq!(summary_a.fees_earned_msat, 30);
+
+		// Newest forwards are returned first.
+		let forwards = history.list_forwards(&ForwardingQuery::default());
+		assert_eq!(forwards[0].prev_channel_id, Some(chan_b));
+
+		let query = ForwardingQuery { completed_after: Some(u64::MAX), ..Default::default() };
+		assert!(history.list_forwards(&query).is_empty());
+		assert!(history.channel_summaries(&query).is_empty());
+	}
+
+	#[test]
+	fn forwards_are_persisted_in_batches() {
+		let (history, test_persister, _tokio_runtime) =
+			test_history("forwards_are_persisted_in_batches");
+
+		// Nothing is persisted on the event path, or if nothing was recorded.
+		persist_pending_forwards(&history.forwards, &test_persister).unwrap();
+		assert!(!test_persister.get_and_clear_pending_persist());
+		history.record_forward(Some([1u8; 32]), Some([2u8; 32]), Some(10_000), Some(10), false);
+		history.record_forward(Some([2u8; 32]), Some([1u8; 32]), Some(5_000), Some(5), false);
+		assert!(!test_persister.get_and_clear_pending_persist());
+
+		persist_pending_forwards(&history.forwards, &test_persister).unwrap();
+		assert!(test_persister.get_and_clear_pending_persist());
+		persist_pending_forwards(&history.forwards, &test_persister).unwrap();
+		assert!(!test_persister.get_and_clear_pending_persist());
+
+		// Forwards recorded since the last persistence are persisted on drop.
+		history.record_forward(None, None, Some(1_000), None, false);
+		drop(history);
+		assert!(test_persister.get_and_clear_pending_persist());
+	}
+
+	#[test]
+	fn forwarding_history_is_capped() {
+		let (history, _, _tokio_runtime) = test_history("forwarding_history_is_capped");
+
+		for amount_msat in 0..MAX_FORWARDS as u64 + 10 {
+			history.record_forward(None, None, Some(amount_msat), None, false);
+		}
+
+		// The oldest forwards are dropped first.
+		let forwards = history.list_forwards(&ForwardingQuery::default());
+		assert_eq!(forwards.len(), MAX_FORWARDS);
+		assert_eq!(forwards[0].outbound_amount_msat, Some(MAX_FORWARDS as u64 + 9));
+		assert_eq!(forwards[MAX_FORWARDS - 1].outbound_amount_msat, Some(10));
+	}
+}
@@ -0,0 +1,584 @@
+use crate::hex_utils;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::{ChannelManager, Error};
+
+use lightning::util::config::{ChannelConfig, UserConfig};
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// The forwarding policies will be persisted under this key.
+pub(crate) const FORWARDING_POLICIES_PERSISTENCE_KEY: &str = "forwarding_policies";
+
+/// The policy applied when forwarding payments over our channels, set globally.
+///
+/// Fields that are `None` are left unchanged, i.e., they fall back to LDK's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ForwardingPolicy {
+	/// The fixed fee charged per forwarded payment, in thousandths of a satoshi.
+	pub fee_base_msat: Option<u32>,
+	/// The fee charged per millionth of the forwarded amount.
+	pub fee_proportional_millionths: Option<u32>,
+	/// The difference in CLTV expiry we require between incoming and outgoing HTLCs.
+	pub cltv_expiry_delta: Option<u16>,
+	/// The minimum value of HTLCs we accept, in thousandths of a satoshi.
+	///
+	/// Note that this limit is negotiated when a channel is opened. It hence only applies to
+	/// channels opened after it was set.
+	pub htlc_minimum_msat: Option<u64>,
+	/// The maximum total value of HTLCs we accept over a channel at once, as a percentage between
+	/// 1 and 100 of the channel value.
+	///
+	/// As LDK doesn't support limiting the value of individual HTLCs, this limit on the HTLCs in
+	/// flight also serves as the maximum value of a single HTLC. Like the minimum, it is
+	/// negotiated when a channel is opened and only applies to channels opened after it was set.
+	pub htlc_maximum_percent_of_channel: Option<u8>,
+}
+
+impl ForwardingPolicy {
+	/// Returns this policy with all fields that are unset taken from `fallback`.
+	fn or(&self, fallback: &ForwardingPolicy) -> ForwardingPolicy {
+		ForwardingPolicy {
+			fee_base_msat: self.fee_base_msat.or(fallback.fee_base_msat),
+			fee_proportional_millionths: self
+				.fee_proportional_millionths
+				.or(fallback.fee_proportional_millionths),
+			cltv_expiry_delta: self.cltv_expiry_delta.or(fallback.cltv_expiry_delta),
+			htlc_minimum_msat: self.htlc_minimum_msat.or(fallback.htlc_minimum_msat),
+			htlc_maximum_percent_of_channel: self
+				.htlc_maximum_percent_of_channel
+				.or(fallback.htlc_maximum_percent_of_channel),
+		}
+	}
+
+	fn check(&self) -> Result<(), Error> {
+		match self.htlc_maximum_percent_of_channel {
+			Some(percent) if percent == 0 || percent > 100 => Err(Error::InvalidForwardingPolicy),
+			_ => Ok(()),
+		}
+	}
+
+	/// Returns the part of this policy which may be updated on existing channels.
+	fn channel_policy(&self) -> ChannelForwardingPolicy {
+		ChannelForwardingPolicy {
+			fee_base_msat: self.fee_base_msat,
+			fee_proportional_millionths: self.fee_proportional_millionths,
+			cltv_expiry_delta: self.cltv_expiry_delta,
+		}
+	}
+
+	fn apply_to_user_config(&self, user_config: &mut UserConfig) {
+		self.channel_policy().apply_to_channel_config(&mut user_config.channel_config);
+		if let Some(htlc_minimum_msat) = self.htlc_minimum_msat {
+			user_config.channel_handshake_config.our_htlc_minimum_msat = htlc_minimum_msat;
+		}
+		if let Some(percent) = self.htlc_maximum_percent_of_channel {
+			user_config
+				.channel_handshake_config
+				.max_inbound_htlc_value_in_flight_percent_of_channel = percent;
+		}
+	}
+}
+
+impl Readable for ForwardingPolicy {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let fee_base_msat: Option<u32> = Readable::read(reader)?;
+		let fee_proportional_millionths: Option<u32> = Readable::read(reader)?;
+		let cltv_expiry_delta: Option<u16> = Readable::read(reader)?;
+		let htlc_minimum_msat: Option<u64> = Readable::read(reader)?;
+		let htlc_maximum_percent_of_channel: Option<u8> = Readable::read(reader)?;
+		Ok(Self {
+			fee_base_msat,
+			fee_proportional_millionths,
+			cltv_expiry_delta,
+			htlc_minimum_msat,
+			htlc_maximum_percent_of_channel,
+		})
+	}
+}
+
+impl Writeable for ForwardingPolicy {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		self.fee_base_msat.write(writer)?;
+		self.fee_proportional_millionths.write(writer)?;
+		self.cltv_expiry_delta.write(writer)?;
+		self.htlc_minimum_msat.write(writer)?;
+		self.htlc_maximum_percent_of_channel.write(writer)?;
+		Ok(())
+	}
+}
+
+/// The policy applied when forwarding payments over a single one of our channels.
+///
+/// Fields that are `None` are left unchanged, i.e., they fall back to the global
+/// [`ForwardingPolicy`]. HTLC limits are negotiated when a channel is opened, so they can only be
+/// set in the global policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelForwardingPolicy {
+	/// The fixed fee charged per forwarded payment, in thousandths of a satoshi.
+	pub fee_base_msat: Option<u32>,
+	/// The fee charged per millionth of the forwarded amount.
+	pub fee_proportional_millionths: Option<u32>,
+	/// The difference in CLTV expiry we require between incoming and outgoing HTLCs.
+	pub cltv_expiry_delta: Option<u16>,
+}
+
+impl ChannelForwardingPolicy {
+	/// Returns this policy with all fields that are unset taken from `fallback`.
+	fn or(&self, fallback: &ChannelForwardingPolicy) -> ChannelForwardingPolicy {
+		ChannelForwardingPolicy {
+			fee_base_msat: self.fee_base_msat.or(fallback.fee_base_msat),
+			fee_proportional_millionths: self
+				.fee_proportional_millionths
+				.or(fallback.fee_proportional_millionths),
+			cltv_expiry_delta: self.cltv_expiry_delta.or(fallback.cltv_expiry_delta),
+		}
+	}
+
+	fn apply_to_channel_config(&self, config: &mut ChannelConfig) {
+		if let Some(fee_base_msat) = self.fee_base_msat {
+			config.forwarding_fee_base_msat = fee_base_msat;
+		}
+		if let Some(fee_proportional_millionths) = self.fee_proportional_millionths {
+			config.forwarding_fee_proportional_millionths = fee_proportional_millionths;
+		}
+		if let Some(cltv_expiry_delta) = self.cltv_expiry_delta {
+			config.cltv_expiry_delta = cltv_expiry_delta;
+		}
+	}
+}
+
+impl Readable for ChannelForwardingPolicy {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let fee_base_msat: Option<u32> = Readable::read(reader)?;
+		let fee_proportional_millionths: Option<u32> = Readable::read(reader)?;
+		let cltv_expiry_delta: Option<u16> = Readable::read(reader)?;
+		Ok(Self { fee_base_msat, fee_proportional_millionths, cltv_expiry_delta })
+	}
+}
+
+impl Writeable for ChannelForwardingPolicy {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		self.fee_base_msat.write(writer)?;
+		self.fee_proportional_millionths.write(writer)?;
+		self.cltv_expiry_delta.write(writer)?;
+		Ok(())
+	}
+}
+
+struct ForwardingPolicies {
+	global: ForwardingPolicy,
+	per_channel: HashMap<[u8; 32], ChannelForwardingPolicy>,
+	// The proportional fees set by the `FeeAutopilot`, which yield to per-channel policies.
+	autopilot_fees: HashMap<[u8; 32], u32>,
+}
+
+impl ForwardingPolicies {
+	fn channel_policy(&self, channel_id: &[u8; 32]) -> ChannelForwardingPolicy {
+		let autopilot_policy = ChannelForwardingPolicy {
+			fee_proportional_millionths: self.autopilot_fees.get(channel_id).copied(),
+			..Default::default()
+		};
+		let channel_policy = self.per_channel.get(channel_id).cloned().unwrap_or_default();
+		channel_policy.or(&autopilot_policy).or(&self.global.channel_policy())
+	}
+}
+
+impl Readable for ForwardingPolicies {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let global: ForwardingPolicy = Readable::read(reader)?;
+		let len: u64 = Readable::read(reader)?;
+		let mut per_channel = HashMap::with_capacity(len as usize);
+		for _ in 0..len {
+			let channel_id: [u8; 32] = Readable::read(reader)?;
+			let policy: ChannelForwardingPolicy = Readable::read(reader)?;
+			per_channel.insert(channel_id, policy);
+		}
+		let len: u64 = Readable::read(reader)?;
+		let mut autopilot_fees = HashMap::with_capacity(len as usize);
+		for _ in 0..len {
+			let channel_id: [u8; 32] = Readable::read(reader)?;
+			let fee_proportional_millionths: u32 = Readable::read(reader)?;
+			autopilot_fees.insert(channel_id, fee_proportional_millionths);
+		}
+		Ok(Self { global, per_channel, autopilot_fees })
+	}
+}
+
+impl Writeable for ForwardingPolicies {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		self.global.write(writer)?;
+		(self.per_channel.len() as u64).write(writer)?;
+		for (channel_id, policy) in self.per_channel.iter() {
+			channel_id.write(writer)?;
+			policy.write(writer)?;
+		}
+		(self.autopilot_fees.len() as u64).write(writer)?;
+		for (channel_id, fee_proportional_millionths) in self.autopilot_fees.iter() {
+			channel_id.write(writer)?;
+			fee_proportional_millionths.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+/// Manages the forwarding fees and CLTV deltas of our channels, either globally or per channel,
+/// and the HTLC limits of newly opened channels.
+///
+/// Policies are persisted and re-applied to channels when they become ready. Any change is
+/// announced to the network via a channel update.
+///
+/// Fields that are `None` in a policy passed to [`set_global_policy`] or [`set_channel_policy`]
+/// keep their current value, so a field can't be unset once it was set. Set a global field to
+/// LDK's default to revert it, or use [`clear_channel_policy`] to let a channel follow the
+/// global policy again.
+///
+/// The proportional fees set by the [`FeeAutopilot`] take precedence over the global policy, but
+/// never over a proportional fee set in a per-channel policy.
+///
+/// [`set_global_policy`]: Self::set_global_policy
+/// [`set_channel_policy`]: Self::set_channel_policy
+/// [`clear_channel_policy`]: Self::clear_channel_policy
+/// [`FeeAutopilot`]: crate::fee_autopilot::FeeAutopilot
+pub struct ForwardingPolicyManager<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	channel_manager: Arc<ChannelManager>,
+	policies: Mutex<ForwardingPolicies>,
+	persister: K,
+	logger: L,
+}
+
+impl<K: Deref, L: Deref> ForwardingPolicyManager<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	/// Creates a new manager, starting out with the global policy from the [`Config`].
+	///
+	/// [`Config`]: crate::Config
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, global_policy: ForwardingPolicy, persister: K,
+		logger: L,
+	) -> Self {
+		let policies = Mutex::new(ForwardingPolicies {
+			global: global_policy,
+			per_channel: HashMap::new(),
+			autopilot_fees: HashMap::new(),
+		});
+		Self { channel_manager, policies, persister, logger }
+	}
+
+	/// Updates the global policy and applies it to all ready channels.
+	///
+	/// Fields set in per-channel policies take precedence over the global policy. Channels that
+	/// aren't ready yet get the policy applied once they are, as do channels we failed to update,
+	/// in which case an error is returned after all other channels were updated.
+	pub fn set_global_policy(&self, policy: ForwardingPolicy) -> Result<(), Error> {
+		policy.check()?;
+		let mut locked_policies = self.policies.lock().unwrap();
+		locked_policies.global = policy.or(&locked_policies.global);
+		self.persist_policies(&locked_policies)?;
+
+		let channel_ids = self
+			.channel_manager
+			.list_channels()
+			.into_iter()
+			.filter(|c| c.is_channel_ready)
+			.map(|c| c.channel_id)
+			.collect();
+		self.apply_policies(&locked_policies, channel_ids)
+	}
+
+	/// Updates the policy of the channel with the given `channel_id` and applies it.
+	pub fn set_channel_policy(
+		&self, channel_id: [u8; 32], policy: ChannelForwardingPolicy,
+	) -> Result<(), Error> {
+		if !self.channel_manager.list_channels().iter().any(|c| c.channel_id == channel_id) {
+			log_error!(
+				self.logger,
+				"Failed to set forwarding policy: unknown channel {}",
+				hex_utils::to_string(&channel_id)
+			);
+			return Err(Error::ChannelConfigUpdateFailed);
+		}
+
+		let mut locked_policies = self.policies.lock().unwrap();
+		let channel_policy = locked_policies.per_channel.entry(channel_id).or_default();
+		*channel_policy = policy.or(channel_policy);
+		self.persist_policies(&locked_policies)?;
+		self.apply_policies(&locked_policies, vec![channel_id])
+	}
+
+	/// Removes the policy of the channel with the given `channel_id`, so it follows the global
+	/// policy, or the fee set by the [`FeeAutopilot`], again.
+	///
+	/// Settings the global policy leaves unset keep their current value.
+	///
+	/// [`FeeAutopilot`]: crate::fee_autopilot::FeeAutopilot
+	pub fn clear_channel_policy(&self, channel_id: [u8; 32]) -> Result<(), Error> {
+		let mut locked_policies = self.policies.lock().unwrap();
+		if locked_policies.per_channel.remove(&channel_id).is_none() {
+			return Ok(());
+		}
+		self.persist_policies(&locked_policies)?;
+		self.apply_policies(&locked_policies, vec![channel_id])
+	}
+
+	/// Returns the global policy.
+	pub fn global_policy(&self) -> ForwardingPolicy {
+		self.policies.lock().unwrap().global.clone()
+	}
+
+	/// Returns the policy in effect for the channel with the given `channel_id`.
+	pub fn channel_policy(&self, channel_id: &[u8; 32]) -> ChannelForwardingPolicy {
+		self.policies.lock().unwrap().channel_policy(channel_id)
+	}
+
+	/// Sets the proportional fee chosen by the [`FeeAutopilot`] for the channel with the given
+	/// `channel_id` and applies it, unless a per-channel policy overrides it.
+	///
+	/// [`FeeAutopilot`]: crate::fee_autopilot::FeeAutopilot
+	pub(crate) fn set_autopilot_fee(
+		&self, channel_id: [u8; 32], fee_proportional_millionths: u32,
+	) -> Result<(), Error> {
+		let mut locked_policies = self.policies.lock().unwrap();
+		locked_policies.autopilot_fees.insert(channel_id, fee_proportional_millionths);
+		self.persist_policies(&locked_policies)?;
+		self.apply_policies(&locked_policies, vec![channel_id])
+	}
+
+	/// Returns whether the user set a proportional fee for the channel with the given
+	/// `channel_id`, which the [`FeeAutopilot`] must not touch.
+	///
+	/// [`FeeAutopilot`]: crate::fee_autopilot::FeeAutopilot
+	pub(crate) fn has_fee_override(&self, channel_id: &[u8; 32]) -> bool {
+		let locked_policies = self.policies.lock().unwrap();
+		locked_policies
+			.per_channel
+			.get(channel_id)
+			.map_or(false, |p| p.fee_proportional_millionths.is_some())
+	}
+
+	/// Applies the global policy, including its HTLC limits, to the config used for opening new
+	/// channels.
+	pub(crate) fn apply_to_user_config(&self, user_config: &mut UserConfig) {
+		self.policies.lock().unwrap().global.apply_to_user_config(user_config);
+	}
+
+	pub(crate) fn channel_ready(&self, channel_id: [u8; 32]) -> Result<(), Error> {
+		let locked_policies = self.policies.lock().unwrap();
+		self.apply_policies(&locked_policies, vec![channel_id])
+	}
+
+	pub(crate) fn channel_closed(&self, channel_id: [u8; 32]) -> Result<(), Error> {
+		let mut locked_policies = self.policies.lock().unwrap();
+		let removed_policy = locked_policies.per_channel.remove(&channel_id).is_some();
+		let removed_autopilot_fee = locked_policies.autopilot_fees.remove(&channel_id).is_some();
+		if removed_policy || removed_autopilot_fee {
+			self.persist_policies(&locked_policies)?;
+		}
+		Ok(())
+	}
+
+	// Applies the policies to the given channels. Channels that fail to update are logged and
+	// skipped, so the others are still updated, and an error is returned at the end.
+	fn apply_policies(
+		&self, locked_policies: &ForwardingPolicies, channel_ids: Vec<[u8; 32]>,
+	) -> Result<(), Error> {
+		let channels = self.channel_manager.list_channels();
+		let mut result = Ok(());
+		for channel_id in channel_ids {
+			let channel = match channels.iter().find(|c| c.channel_id == channel_id) {
+				Some(channel) => channel,
+				None => {
+					log_error!(
+						self.logger,
+						"Failed to apply forwarding policy: unknown channel {}",
+						hex_utils::to_string(&channel_id)
+					);
+					result = Err(Error::ChannelConfigUpdateFailed);
+					continue;
+				}
+			};
+
+			let policy = locked_policies.channel_policy(&channel_id);
+			let mut config = channel.config.unwrap_or_default();
+			policy.apply_to_channel_config(&mut config);
+			if channel.config == Some(config) {
+				continue;
+			}
+
+			if let Err(e) = self.channel_manager.update_channel_config(
+				&channel.counterparty.node_id,
+				&[channel_id],
+				&config,
+			) {
+				log_error!(
+					self.logger,
+					"Failed to update config of channel {}: {:?}",
+					hex_utils::to_string(&channel_id),
+					e
+				);
+				result = Err(Error::ChannelConfigUpdateFailed);
+				continue;
+			}
+			log_info!(
+				self.logger,
+				"Updated forwarding policy of channel {}: base fee {} msat, proportional fee {} ppm, CLTV delta {}.",
+				hex_utils::to_string(&channel_id),
+				config.forwarding_fee_base_msat,
+				config.forwarding_fee_proportional_millionths,
+				config.cltv_expiry_delta,
+			);
+		}
+		result
+	}
+
+	fn persist_policies(&self, locked_policies: &ForwardingPolicies) -> Result<(), Error> {
+		self.persister
+			.persist(FORWARDING_POLICIES_PERSISTENCE_KEY, locked_policies)
+			.map_err(|_| Error::PersistenceFailed)?;
+		Ok(())
+	}
+}
+
+impl<K: Deref, L: Deref> ReadableArgs<(Arc<ChannelManager>, K, L)> for ForwardingPolicyManager<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R, args: (Arc<ChannelManager>, K, L),
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let (channel_manager, persister, logger) = args;
+		let policies = Mutex::new(Readable::read(reader)?);
+		Ok(Self { channel_manager, policies, persister, logger })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn policy_fallback() {
+		let global = ForwardingPolicy {
+			fee_base_msat: Some(1000),
+			fee_proportional_millionths: Some(100),
+			cltv_expiry_delta: Some(72),
+			htlc_minimum_msat: Some(5_000),
+			htlc_maximum_percent_of_channel: Some(50),
+		};
+		let channel_policy = ChannelForwardingPolicy {
+			fee_proportional_millionths: Some(500),
+			..Default::default()
+		};
+		let effective = channel_policy.or(&global.channel_policy());
+		assert_eq!(effective.fee_base_msat, Some(1000));
+		assert_eq!(effective.fee_proportional_millionths, Some(500));
+		assert_eq!(effective.cltv_expiry_delta, Some(72));
+
+		let mut config = ChannelConfig::default();
+		effective.apply_to_channel_config(&mut config);
+		assert_eq!(config.forwarding_fee_base_msat, 1000);
+		assert_eq!(config.forwarding_fee_proportional_millionths, 500);
+		assert_eq!(config.cltv_expiry_delta, 72);
+	}
+
+	#[test]
+	fn htlc_limits_apply_to_new_channels() {
+		let policy = ForwardingPolicy {
+			fee_base_msat: Some(1000),
+			htlc_minimum_msat: Some(5_000),
+			htlc_maximum_percent_of_channel: Some(50),
+			..Default::default()
+		};
+		assert!(policy.check().is_ok());
+
+		let mut user_config = UserConfig::default();
+		policy.apply_to_user_config(&mut user_config);
+		assert_eq!(user_config.channel_config.forwarding_fee_base_msat, 1000);
+		assert_eq!(user_config.channel_handshake_config.our_htlc_minimum_msat, 5_000);
+		assert_eq!(
+			user_config
+				.channel_handshake_config
+				.max_inbound_htlc_value_in_flight_percent_of_channel,
+			50
+		);
+
+		// Unset limits keep LDK's defaults.
+		let default_config = UserConfig::default();
+		let mut user_config = UserConfig::default();
+		ForwardingPolicy::default().apply_to_user_config(&mut user_config);
+		let (handshake_config, default_handshake_config) =
+			(user_config.channel_handshake_config, default_config.channel_handshake_config);
+		assert_eq!(
+			handshake_config.our_htlc_minimum_msat,
+			default_handshake_config.our_htlc_minimum_msat
+		);
+		assert_eq!(
+			handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel,
+			default_handshake_config.max_inbound_htlc_value_in_flight_percent_of_channel
+		);
+
+		for percent in [0u8, 101].iter() {
+			let policy = ForwardingPolicy {
+				htlc_maximum_percent_of_channel: Some(*percent),
+				..Default::default()
+			};
+			assert!(matches!(policy.check(), Err(Error::InvalidForwardingPolicy)));
+		}
+	}
+
+	#[test]
+	fn autopilot_fees_yield_to_channel_policies() {
+		let global = ForwardingPolicy {
+			fee_base_msat: Some(1000),
+			fee_proportional_millionths: Some(100),
+			..Default::default()
+		};
+		let (chan_a, chan_b, chan_c) = ([1u8; 32], [2u8; 32], [3u8; 32]);
+		let mut policies = ForwardingPolicies {
+			global,
+			per_channel: HashMap::new(),
+			autopilot_fees: HashMap::new(),
+		};
+		policies.autopilot_fees.insert(chan_a, 200);
+		policies.autopilot_fees.insert(chan_b, 200);
+		policies.per_channel.insert(
+			chan_b,
+			ChannelForwardingPolicy {
+				fee_proportional_millionths: Some(300),
+				..Default::default()
+			},
+		);
+		policies.per_channel.insert(
+			chan_c,
+			ChannelForwardingPolicy { fee_base_msat: Some(0), ..Default::default() },
+		);
+
+		assert_eq!(policies.channel_policy(&chan_a).fee_proportional_millionths, Some(200));
+		assert_eq!(policies.channel_policy(&chan_a).fee_base_msat, Some(1000));
+		assert_eq!(policies.channel_policy(&chan_b).fee_proportional_millionths, Some(300));
+		assert_eq!(policies.channel_policy(&chan_c).fee_proportional_millionths, Some(100));
+		assert_eq!(policies.channel_policy(&chan_c).fee_base_msat, Some(0));
+
+		// Autopilot fees survive a serialization round trip.
+		let decoded: ForwardingPolicies = Readable::read(&mut &policies.encode()[..]).unwrap();
+		assert_eq!(decoded.autopilot_fees, policies.autopilot_fees);
+		assert_eq!(decoded.per_channel, policies.per_channel);
+	}
+}
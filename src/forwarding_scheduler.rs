@@ -0,0 +1,180 @@
+use crate::ChannelManager;
+
+use rand::{thread_rng, Rng};
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long we wait before processing pending HTLC forwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardingDelay {
+	/// Process forwards right away, minimizing latency, e.g., for LSPs.
+	Immediate,
+	/// Wait a fixed time, so that forwards arriving in the meantime are processed in one batch.
+	Fixed(Duration),
+	/// Wait a random time between the delay suggested by LDK and `max_multiplier` times that.
+	///
+	/// This makes it harder for observers to correlate incoming and outgoing HTLCs by timing.
+	Randomized {
+		/// The maximum multiple of LDK's suggested delay to wait.
+		max_multiplier: u32,
+	},
+}
+
+impl Default for ForwardingDelay {
+	fn default() -> Self {
+		Self::Randomized { max_multiplier: 5 }
+	}
+}
+
+impl ForwardingDelay {
+	fn delay(&self, time_forwardable: Duration) -> Duration {
+		match self {
+			Self::Immediate => Duration::ZERO,
+			Self::Fixed(delay) => *delay,
+			Self::Randomized { max_multiplier } => {
+				let min = time_forwardable;
+				let max = time_forwardable * (*max_multiplier).max(1);
+				if min >= max {
+					return min;
+				}
+				thread_rng().gen_range(min..max)
+			}
+		}
+	}
+}
+
+/// Metrics on the latency of HTLC forwarding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ForwardingMetrics {
+	/// The number of batches of pending forwards processed.
+	pub batches_processed: u64,
+	/// The number of times LDK signaled pending forwards, including those coalesced into an
+	/// already scheduled batch.
+	pub forward_signals: u64,
+	/// The total time forwards waited before being processed, summed over all batches.
+	pub total_delay: Duration,
+	/// The longest time forwards waited before being processed.
+	pub max_delay: Duration,
+	/// The total time it took to process the batches.
+	pub total_processing_time: Duration,
+}
+
+impl ForwardingMetrics {
+	/// Returns the average time forwards waited before being processed.
+	pub fn average_delay(&self) -> Duration {
+		if self.batches_processed == 0 {
+			return Duration::ZERO;
+		}
+		self.total_delay / self.batches_processed as u32
+	}
+
+	fn record_batch(&mut self, delay: Duration, processing_time: Duration) {
+		self.batches_processed += 1;
+		self.total_delay += delay;
+		self.max_delay = self.max_delay.max(delay);
+		self.total_processing_time += processing_time;
+	}
+}
+
+/// Processes pending HTLC forwards according to the configured [`ForwardingDelay`].
+///
+/// Forwards that become pending while a batch is already scheduled are coalesced into it, so at
+/// most one batch is waiting at any time.
+pub struct ForwardingScheduler {
+	channel_manager: Arc<ChannelManager>,
+	delay: ForwardingDelay,
+	// The time the currently scheduled batch became pending, if any.
+	pending_since: Arc<Mutex<Option<Instant>>>,
+	metrics: Arc<Mutex<ForwardingMetrics>>,
+	tokio_runtime: Arc<tokio::runtime::Runtime>,
+}
+
+impl ForwardingScheduler {
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, delay: ForwardingDelay,
+		tokio_runtime: Arc<tokio::runtime::Runtime>,
+	) -> Self {
+		let pending_since = Arc::new(Mutex::new(None));
+		let metrics = Arc::new(Mutex::new(ForwardingMetrics::default()));
+		Self { channel_manager, delay, pending_since, metrics, tokio_runtime }
+	}
+
+	/// Schedules processing the pending forwards, unless a batch is already scheduled.
+	pub(crate) fn forwards_pending(&self, time_forwardable: Duration) {
+		self.metrics.lock().unwrap().forward_signals += 1;
+
+		let now = Instant::now();
+		{
+			let mut locked_pending_since = self.pending_since.lock().unwrap();
+			if locked_pending_since.is_some() {
+				return;
+			}
+			*locked_pending_since = Some(now);
+		}
+
+		let delay = self.delay.delay(time_forwardable);
+		if delay == Duration::ZERO {
+			process_forwards(&self.channel_manager, &self.pending_since, &self.metrics);
+			return;
+		}
+
+		let channel_manager = Arc::clone(&self.channel_manager);
+		let pending_since = Arc::clone(&self.pending_since);
+		let metrics = Arc::clone(&self.metrics);
+		self.tokio_runtime.spawn(async move {
+			tokio::time::sleep(delay).await;
+			process_forwards(&channel_manager, &pending_since, &metrics);
+		});
+	}
+
+	/// Returns the forwarding latency metrics collected since startup.
+	pub fn metrics(&self) -> ForwardingMetrics {
+		*self.metrics.lock().unwrap()
+	}
+}
+
+fn process_forwards(
+	channel_manager: &ChannelManager, pending_since: &Mutex<Option<Instant>>,
+	metrics: &Mutex<ForwardingMetrics>,
+) {
+	// We clear the pending state before processing, so forwards becoming pending in the
+	// meantime get a batch of their own.
+	let delay = match pending_since.lock().unwrap().take() {
+		Some(pending_since) => pending_since.elapsed(),
+		None => return,
+	};
+
+	let processing_start = Instant::now();
+	channel_manager.process_pending_htlc_forwards();
+	metrics.lock().unwrap().record_batch(delay, processing_start.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn delays_and_metrics() {
+		let time_forwardable = Duration::from_millis(100);
+		assert_eq!(ForwardingDelay::Immediate.delay(time_forwardable), Duration::ZERO);
+		let fixed = Duration::from_millis(42);
+		assert_eq!(ForwardingDelay::Fixed(fixed).delay(time_forwardable), fixed);
+		for _ in 0..100 {
+			let delay = ForwardingDelay::default().delay(time_forwardable);
+			assert!(delay >= time_forwardable && delay < time_forwardable * 5);
+		}
+		let no_spread = ForwardingDelay::Randomized { max_multiplier: 1 };
+		assert_eq!(no_spread.delay(time_forwardable), time_forwardable);
+		assert_eq!(ForwardingDelay::default().delay(Duration::ZERO), Duration::ZERO);
+
+		let mut metrics = ForwardingMetrics::default();
+		assert_eq!(metrics.average_delay(), Duration::ZERO);
+		metrics.record_batch(Duration::from_millis(100), Duration::from_millis(1));
+		metrics.record_batch(Duration::from_millis(300), Duration::from_millis(1));
+		assert_eq!(metrics.batches_processed, 2);
+		assert_eq!(metrics.average_delay(), Duration::from_millis(200));
+		assert_eq!(metrics.max_delay, Duration::from_millis(300));
+		assert_eq!(metrics.total_processing_time, Duration::from_millis(2));
+	}
+}
@@ -0,0 +1,226 @@
+use crate::logger::{log_error, log_given_level, log_info, log_internal, FilesystemLogger, Logger};
+use crate::proxy::ProxyConfig;
+use crate::{Error, NetworkGraph};
+
+use lightning::util::persist::KVStorePersister;
+
+use std::io::Read;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The timestamp of the last rapid gossip sync will be persisted under this key.
+pub(crate) const RGS_SYNC_TIMESTAMP_PERSISTENCE_KEY: &str = "rgs_sync_timestamp";
+
+/// The interval in which we fetch new rapid gossip sync snapshots.
+pub(crate) const RGS_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The time after which we give up on fetching a snapshot.
+const RGS_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The maximum size of a snapshot we're willing to load, in bytes.
+const MAX_SNAPSHOT_SIZE: u64 = 64 * 1024 * 1024;
+
+pub(crate) type RapidGossipSync =
+	lightning_rapid_gossip_sync::RapidGossipSync<Arc<NetworkGraph>, Arc<FilesystemLogger>>;
+
+/// The source of the snapshots used to sync the network graph via rapid gossip sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RgsSnapshotSource {
+	/// Fetch snapshots from a rapid gossip sync server at the given base URL.
+	///
+	/// The timestamp of the last sync will be appended to the URL, so that the server only
+	/// returns updates we haven't seen yet.
+	Url(String),
+	/// Load a snapshot from the given local file.
+	File(PathBuf),
+}
+
+/// Keeps the network graph up-to-date using rapid gossip sync snapshots, as an alternative to
+/// syncing gossip from our peers.
+///
+/// The graph is synced right after startup and then every [`RGS_SYNC_INTERVAL`]. Snapshots are
+/// fetched via the proxy if the [`ProxyConfig`] routes Esplora traffic via it.
+pub struct RgsSyncer<K: Deref>
+where
+	K::Target: KVStorePersister,
+{
+	rapid_gossip_sync: Arc<RapidGossipSync>,
+	source: RgsSnapshotSource,
+	proxy_url: Option<String>,
+	last_sync_timestamp: Arc<Mutex<u32>>,
+	persister: K,
+	logger: Arc<FilesystemLogger>,
+}
+
+impl<K: Deref + Clone + Send + Sync + 'static> RgsSyncer<K>
+where
+	K::Target: KVStorePersister,
+{
+	/// Creates a new syncer, resuming from the given `last_sync_timestamp`, or starting from
+	/// scratch if it is 0.
+	pub(crate) fn new(
+		rapid_gossip_sync: Arc<RapidGossipSync>, source: RgsSnapshotSource,
+		proxy: Option<&ProxyConfig>, last_sync_timestamp: u32, persister: K,
+		tokio_runtime: Arc<tokio::runtime::Runtime>, logger: Arc<FilesystemLogger>,
+	) -> Self {
+		let proxy_url = proxy.and_then(|p| p.esplora_proxy_url());
+		let last_sync_timestamp = Arc::new(Mutex::new(last_sync_timestamp));
+
+		let sync_rapid_gossip_sync = Arc::clone(&rapid_gossip_sync);
+		let sync_source = source.clone();
+		let sync_proxy_url = proxy_url.clone();
+		let sync_timestamp = Arc::clone(&last_sync_timestamp);
+		let sync_persister = persister.clone();
+		let sync_logger = Arc::clone(&logger);
+		tokio_runtime.spawn(async move {
+			let mut interval = tokio::time::interval(RGS_SYNC_INTERVAL);
+			loop {
+				interval.tick().await;
+				let rapid_gossip_sync = Arc::clone(&sync_rapid_gossip_sync);
+				let source = sync_source.clone();
+				let proxy_url = sync_proxy_url.clone();
+				let last_sync_timestamp = Arc::clone(&sync_timestamp);
+				let persister = sync_persister.clone();
+				let logger = Arc::clone(&sync_logger);
+				// Errors are logged by `sync_network_graph`.
+				let _ = tokio::task::spawn_blocking(move || {
+					sync_network_graph(
+						&rapid_gossip_sync,
+						&source,
+						proxy_url.as_deref(),
+						&last_sync_timestamp,
+						&persister,
+						&logger,
+					)
+				})
+				.await;
+			}
+		});
+
+		Self { rapid_gossip_sync, source, proxy_url, last_sync_timestamp, persister, logger }
+	}
+}
+
+impl<K: Deref> RgsSyncer<K>
+where
+	K::Target: KVStorePersister,
+{
+	/// Fetches the latest snapshot from the configured source and applies it to the network
+	/// graph.
+	///
+	/// Note that this blocks while fetching the snapshot.
+	pub(crate) fn sync(&self) -> Result<u32, Error> {
+		sync_network_graph(
+			&self.rapid_gossip_sync,
+			&self.source,
+			self.proxy_url.as_deref(),
+			&self.last_sync_timestamp,
+			&self.persister,
+			&self.logger,
+		)
+	}
+
+	/// Returns the timestamp up to which the network graph has been synced, or 0 if it has never
+	/// been synced.
+	pub fn last_sync_timestamp(&self) -> u32 {
+		*self.last_sync_timestamp.lock().unwrap()
+	}
+
+	/// Returns whether the network graph has been synced at least once.
+	pub fn is_initial_sync_complete(&self) -> bool {
+		self.rapid_gossip_sync.is_initial_sync_complete()
+	}
+}
+
+fn sync_network_graph<K: Deref>(
+	rapid_gossip_sync: &RapidGossipSync, source: &RgsSnapshotSource, proxy_url: Option<&str>,
+	last_sync_timestamp: &Mutex<u32>, persister: &K, logger: &FilesystemLogger,
+) -> Result<u32, Error>
+where
+	K::Target: KVStorePersister,
+{
+	let mut locked_timestamp = last_sync_timestamp.lock().unwrap();
+	let snapshot = match source {
+		RgsSnapshotSource::Url(base_url) => {
+			let url = format!("{}/{}", base_url.trim_end_matches('/'), *locked_timestamp);
+			fetch_snapshot(&url, proxy_url).map_err(|e| {
+				log_error!(logger, "Failed to fetch RGS snapshot from {}: {}", url, e);
+				Error::GossipSyncFailed
+			})?
+		}
+		RgsSnapshotSource::File(path) => {
+			std::fs::File::open(path).and_then(read_snapshot).map_err(|e| {
+				log_error!(logger, "Failed to read RGS snapshot from {}: {}", path.display(), e);
+				Error::GossipSyncFailed
+			})?
+		}
+	};
+
+	let new_timestamp = rapid_gossip_sync.update_network_graph(&snapshot).map_err(|e| {
+		log_error!(logger, "Failed to apply RGS snapshot: {:?}", e);
+		Error::GossipSyncFailed
+	})?;
+
+	if new_timestamp != *locked_timestamp {
+		persister
+			.persist(RGS_SYNC_TIMESTAMP_PERSISTENCE_KEY, &new_timestamp)
+			.map_err(|_| Error::PersistenceFailed)?;
+		*locked_timestamp = new_timestamp;
+	}
+
+	log_info!(
+		logger,
+		"Applied RGS snapshot of {} bytes, graph is now synced up to timestamp {}.",
+		snapshot.len(),
+		new_timestamp
+	);
+	Ok(new_timestamp)
+}
+
+fn fetch_snapshot(url: &str, proxy_url: Option<&str>) -> Result<Vec<u8>, std::io::Error> {
+	let mut agent_builder = ureq::AgentBuilder::new().timeout(RGS_FETCH_TIMEOUT);
+	if let Some(proxy_url) = proxy_url {
+		let proxy = ureq::Proxy::new(proxy_url)
+			.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+		agent_builder = agent_builder.proxy(proxy);
+	}
+	let response = agent_builder
+		.build()
+		.get(url)
+		.call()
+		.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+	read_snapshot(response.into_reader())
+}
+
+// Reads a snapshot, refusing to load more than `MAX_SNAPSHOT_SIZE` bytes into memory.
+fn read_snapshot<R: Read>(reader: R) -> Result<Vec<u8>, std::io::Error> {
+	let mut snapshot = Vec::new();
+	reader.take(MAX_SNAPSHOT_SIZE + 1).read_to_end(&mut snapshot)?;
+	if snapshot.len() as u64 > MAX_SNAPSHOT_SIZE {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			"snapshot exceeds the maximum size",
+		));
+	}
+	Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn oversized_snapshots_are_rejected() {
+		let snapshot = read_snapshot(&[1u8, 2, 3][..]).unwrap();
+		assert_eq!(snapshot, vec![1u8, 2, 3]);
+
+		let snapshot = read_snapshot(std::io::repeat(0).take(MAX_SNAPSHOT_SIZE)).unwrap();
+		assert_eq!(snapshot.len() as u64, MAX_SNAPSHOT_SIZE);
+
+		let err = read_snapshot(std::io::repeat(0)).unwrap_err();
+		assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+	}
+}
@@ -0,0 +1,326 @@
+use crate::hex_utils;
+use crate::net_address::format_address;
+use crate::NetworkGraph;
+
+use lightning::routing::gossip::{ChannelInfo, ChannelUpdateInfo, NodeId, NodeInfo};
+
+use serde_json::{json, Value};
+
+use std::sync::Arc;
+
+/// Details of a node in the network graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphNodeDetails {
+	/// The id of the node.
+	pub node_id: NodeId,
+	/// The alias the node announced, if any.
+	pub alias: Option<String>,
+	/// The features the node announced, as little-endian flags.
+	pub features: Vec<u8>,
+	/// The addresses the node announced it is reachable at.
+	pub addresses: Vec<String>,
+	/// The `short_channel_id`s of the node's channels.
+	pub channels: Vec<u64>,
+	/// The timestamp of the node's latest announcement, if we have seen one.
+	pub last_update: Option<u32>,
+}
+
+/// Details of a channel in the network graph.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphChannelDetails {
+	/// The `short_channel_id` of the channel.
+	pub short_channel_id: u64,
+	/// The node with the lexicographically lesser id.
+	pub node_one: NodeId,
+	/// The node with the lexicographically greater id.
+	pub node_two: NodeId,
+	/// The capacity of the channel, in satoshis, if known.
+	pub capacity_sats: Option<u64>,
+	/// The policy for forwarding from `node_one` to `node_two`, if announced.
+	pub one_to_two: Option<ChannelPolicy>,
+	/// The policy for forwarding from `node_two` to `node_one`, if announced.
+	pub two_to_one: Option<ChannelPolicy>,
+}
+
+/// The policy a node announced for forwarding payments over one direction of a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelPolicy {
+	/// Whether the direction is currently enabled.
+	pub enabled: bool,
+	/// The fixed fee charged per forwarded payment, in thousandths of a satoshi.
+	pub fee_base_msat: u32,
+	/// The fee charged per millionth of the forwarded amount.
+	pub fee_proportional_millionths: u32,
+	/// The difference in CLTV expiry required between incoming and outgoing HTLCs.
+	pub cltv_expiry_delta: u16,
+	/// The minimum HTLC value accepted, in thousandths of a satoshi.
+	pub htlc_minimum_msat: u64,
+	/// The maximum HTLC value accepted, in thousandths of a satoshi.
+	pub htlc_maximum_msat: u64,
+	/// The timestamp of the latest update of the policy.
+	pub last_update: u32,
+}
+
+impl From<&ChannelUpdateInfo> for ChannelPolicy {
+	fn from(info: &ChannelUpdateInfo) -> Self {
+		Self {
+			enabled: info.enabled,
+			fee_base_msat: info.fees.base_msat,
+			fee_proportional_millionths: info.fees.proportional_millionths,
+			cltv_expiry_delta: info.cltv_expiry_delta,
+			htlc_minimum_msat: info.htlc_minimum_msat,
+			htlc_maximum_msat: info.htlc_maximum_msat,
+			last_update: info.last_update,
+		}
+	}
+}
+
+impl GraphNodeDetails {
+	fn new(node_id: NodeId, info: &NodeInfo) -> Self {
+		let announcement = info.announcement_info.as_ref();
+		Self {
+			node_id,
+			alias: announcement.map(|a| a.alias.to_string()),
+			features: announcement.map(|a| a.features.le_flags().to_vec()).unwrap_or_default(),
+			addresses: announcement
+				.map(|a| a.addresses.iter().map(format_address).collect())
+				.unwrap_or_default(),
+			channels: info.channels.clone(),
+			last_update: announcement.map(|a| a.last_update),
+		}
+	}
+
+	fn to_json(&self) -> Value {
+		json!({
+			"node_id": self.node_id.to_string(),
+			"alias": self.alias,
+			"features": hex_utils::to_string(&self.features),
+			"addresses": self.addresses,
+			"channels": self.channels,
+			"last_update": self.last_update,
+		})
+	}
+}
+
+impl GraphChannelDetails {
+	fn new(short_channel_id: u64, info: &ChannelInfo) -> Self {
+		Self {
+			short_channel_id,
+			node_one: info.node_one,
+			node_two: info.node_two,
+			capacity_sats: info.capacity_sats,
+			one_to_two: info.one_to_two.as_ref().map(ChannelPolicy::from),
+			two_to_one: info.two_to_one.as_ref().map(ChannelPolicy::from),
+		}
+	}
+
+	fn to_json(&self) -> Value {
+		let policy_to_json = |policy: &ChannelPolicy| {
+			json!({
+				"enabled": policy.enabled,
+				"fee_base_msat": policy.fee_base_msat,
+				"fee_proportional_millionths": policy.fee_proportional_millionths,
+				"cltv_expiry_delta": policy.cltv_expiry_delta,
+				"htlc_minimum_msat": policy.htlc_minimum_msat,
+				"htlc_maximum_msat": policy.htlc_maximum_msat,
+				"last_update": policy.last_update,
+			})
+		};
+		json!({
+			"short_channel_id": self.short_channel_id,
+			"node_one": self.node_one.to_string(),
+			"node_two": self.node_two.to_string(),
+			"capacity_sats": self.capacity_sats,
+			"one_to_two": self.one_to_two.as_ref().map(policy_to_json),
+			"two_to_one": self.two_to_one.as_ref().map(policy_to_json),
+		})
+	}
+}
+
+/// Provides read access to the nodes and channels in our view of the network graph.
+pub struct NetworkGraphExplorer {
+	network_graph: Arc<NetworkGraph>,
+}
+
+impl NetworkGraphExplorer {
+	pub(crate) fn new(network_graph: Arc<NetworkGraph>) -> Self {
+		Self { network_graph }
+	}
+
+	/// Returns the details of all nodes in the graph.
+	pub fn list_nodes(&self) -> Vec<GraphNodeDetails> {
+		let read_only_graph = self.network_graph.read_only();
+		read_only_graph
+			.nodes()
+			.unordered_iter()
+			.map(|(node_id, info)| GraphNodeDetails::new(*node_id, info))
+			.collect()
+	}
+
+	/// Returns the details of the node with the given id, if it is known.
+	pub fn node(&self, node_id: &NodeId) -> Option<GraphNodeDetails> {
+		let read_only_graph = self.network_graph.read_only();
+		read_only_graph.node(node_id).map(|info| GraphNodeDetails::new(*node_id, info))
+	}
+
+	/// Returns the details of all nodes whose alias contains the given string, ignoring case.
+	pub fn search_nodes_by_alias(&self, alias: &str) -> Vec<GraphNodeDetails> {
+		let alias = alias.to_lowercase();
+		self.list_nodes()
+			.into_iter()
+			.filter(|n| n.alias.as_ref().map_or(false, |a| a.to_lowercase().contains(&alias)))
+			.collect()
+	}
+
+	/// Returns the details of all channels in the graph.
+	pub fn list_channels(&self) -> Vec<GraphChannelDetails> {
+		let read_only_graph = self.network_graph.read_only();
+		read_only_graph
+			.channels()
+			.unordered_iter()
+			.map(|(scid, info)| GraphChannelDetails::new(*scid, info))
+			.collect()
+	}
+
+	/// Returns the details of the channel with the given `short_channel_id`, if it is known.
+	pub fn channel(&self, short_channel_id: u64) -> Option<GraphChannelDetails> {
+		let read_only_graph = self.network_graph.read_only();
+		read_only_graph
+			.channel(short_channel_id)
+			.map(|info| GraphChannelDetails::new(short_channel_id, info))
+	}
+
+	/// Returns the details of all channels of the node with the given id.
+	pub fn channels_of_node(&self, node_id: &NodeId) -> Vec<GraphChannelDetails> {
+		let read_only_graph = self.network_graph.read_only();
+		let channels = match read_only_graph.node(node_id) {
+			Some(info) => &info.channels,
+			None => return Vec::new(),
+		};
+		channels
+			.iter()
+			.filter_map(|scid| {
+				read_only_graph.channel(*scid).map(|info| GraphChannelDetails::new(*scid, info))
+			})
+			.collect()
+	}
+
+	/// Exports all nodes and channels of the graph as a JSON string.
+	pub fn export_json(&self) -> String {
+		let nodes = self.list_nodes().iter().map(|n| n.to_json()).collect::<Vec<_>>();
+		let channels = self.list_channels().iter().map(|c| c.to_json()).collect::<Vec<_>>();
+		json!({ "nodes": nodes, "channels": channels }).to_string()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::test_utils::{
+		add_test_channel, announce_test_node, test_filesystem_logger, test_node_id,
+	};
+
+	use lightning::ln::msgs::NetAddress;
+
+	use bitcoin::Network;
+
+	// Returns a graph of the channels 1 between nodes 1 and 2, and 2 between nodes 2 and 3, where
+	// only nodes 1 and 2 announced themselves.
+	fn test_explorer(test_name: &str) -> NetworkGraphExplorer {
+		let logger = test_filesystem_logger(test_name);
+		let network_graph = Arc::new(NetworkGraph::new(Network::Regtest, logger));
+		add_test_channel(&network_graph, 1, &test_node_id(1), &test_node_id(2));
+		add_test_channel(&network_graph, 2, &test_node_id(2), &test_node_id(3));
+		let address = NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 };
+		announce_test_node(&network_graph, &test_node_id(1), "Alice's Node", vec![address]);
+		announce_test_node(&network_graph, &test_node_id(2), "BOB", Vec::new());
+		NetworkGraphExplorer::new(network_graph)
+	}
+
+	#[test]
+	fn nodes_are_searched_by_alias() {
+		let explorer = test_explorer("nodes_are_searched_by_alias");
+		assert_eq!(explorer.list_nodes().len(), 3);
+
+		let nodes = explorer.search_nodes_by_alias("alice");
+		assert_eq!(nodes.len(), 1);
+		assert_eq!(nodes[0].node_id, NodeId::from_pubkey(&test_node_id(1)));
+		assert_eq!(nodes[0].alias.as_deref(), Some("Alice's Node"));
+		assert_eq!(nodes[0].addresses, vec!["127.0.0.1:9735".to_string()]);
+		assert_eq!(nodes[0].channels, vec![1]);
+
+		assert_eq!(explorer.search_nodes_by_alias("S NO").len(), 1);
+		assert_eq!(explorer.search_nodes_by_alias("bob")[0].alias.as_deref(), Some("BOB"));
+		// Every announced node matches the empty string, unlike those that didn't announce.
+		assert_eq!(explorer.search_nodes_by_alias("").len(), 2);
+		assert!(explorer.search_nodes_by_alias("carol").is_empty());
+	}
+
+	#[test]
+	fn channels_of_node_are_listed() {
+		let explorer = test_explorer("channels_of_node_are_listed");
+		assert_eq!(explorer.list_channels().len(), 2);
+
+		let mut scids = explorer
+			.channels_of_node(&NodeId::from_pubkey(&test_node_id(2)))
+			.iter()
+			.map(|c| c.short_channel_id)
+			.collect::<Vec<_>>();
+		scids.sort_unstable();
+		assert_eq!(scids, vec![1, 2]);
+
+		let channels = explorer.channels_of_node(&NodeId::from_pubkey(&test_node_id(3)));
+		assert_eq!(channels.len(), 1);
+		assert_eq!(Some(channels[0].clone()), explorer.channel(2));
+		let policy = channels[0].one_to_two.as_ref().unwrap();
+		assert!(policy.enabled);
+		assert_eq!(policy.fee_base_msat, 1_000);
+		assert_eq!(policy.cltv_expiry_delta, 40);
+
+		assert!(explorer.channels_of_node(&NodeId::from_pubkey(&test_node_id(4))).is_empty());
+	}
+
+	#[test]
+	fn graph_is_exported_as_json() {
+		let explorer = test_explorer("graph_is_exported_as_json");
+		let json: Value = serde_json::from_str(&explorer.export_json()).unwrap();
+		assert_eq!(json["nodes"].as_array().unwrap().len(), 3);
+		assert_eq!(json["channels"].as_array().unwrap().len(), 2);
+
+		let node_id = NodeId::from_pubkey(&test_node_id(1)).to_string();
+		let node = json["nodes"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.find(|n| n["node_id"] == Value::String(node_id.clone()))
+			.unwrap();
+		assert_eq!(node["alias"], "Alice's Node");
+		assert_eq!(node["addresses"], json!(["127.0.0.1:9735"]));
+		assert_eq!(node["channels"], json!([1]));
+		assert!(node["features"].is_string());
+		assert!(node["last_update"].is_u64());
+
+		// Nodes that didn't announce themselves have no alias.
+		let node_id = NodeId::from_pubkey(&test_node_id(3)).to_string();
+		let node = json["nodes"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.find(|n| n["node_id"] == Value::String(node_id.clone()))
+			.unwrap();
+		assert!(node["alias"].is_null());
+		assert_eq!(node["addresses"], json!([]));
+
+		let channel = json["channels"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.find(|c| c["short_channel_id"] == 1)
+			.unwrap();
+		assert!(channel["capacity_sats"].is_null());
+		assert_eq!(channel["one_to_two"]["fee_base_msat"], 1_000);
+		assert_eq!(channel["one_to_two"]["fee_proportional_millionths"], 100);
+		assert_eq!(channel["two_to_one"]["htlc_maximum_msat"], 100_000_000);
+		assert_eq!(channel["two_to_one"]["enabled"], true);
+	}
+}
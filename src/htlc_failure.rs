@@ -0,0 +1,133 @@
+use crate::event::HtlcFailureReason;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The number of failed HTLCs of a channel, by reason.
+///
+/// The reasons are heuristics, as LDK doesn't report why an HTLC was failed. See
+/// [`HtlcFailureReason`] for what the individual counters cover.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HtlcFailureCounters {
+	/// The number of HTLCs failed as their next hop was unknown.
+	pub unknown_next_hop: u64,
+	/// The number of HTLCs failed while the next channel was closed or its counterparty
+	/// disconnected.
+	pub next_channel_unavailable: u64,
+	/// The number of HTLCs the next channel couldn't take for an unknown reason, e.g., a lack of
+	/// liquidity.
+	pub next_channel_failed: u64,
+	/// The number of HTLCs rejected before forwarding, e.g., for paying too little in fees or
+	/// expiring too soon.
+	pub invalid_forward: u64,
+	/// The number of HTLCs destined to us that were failed.
+	pub receive_failed: u64,
+}
+
+impl HtlcFailureCounters {
+	/// Returns the total number of failed HTLCs.
+	pub fn total(&self) -> u64 {
+		self.unknown_next_hop
+			+ self.next_channel_unavailable
+			+ self.next_channel_failed
+			+ self.invalid_forward
+			+ self.receive_failed
+	}
+
+	fn increment(&mut self, reason: &HtlcFailureReason) {
+		match reason {
+			HtlcFailureReason::UnknownNextHop { .. } => self.unknown_next_hop += 1,
+			HtlcFailureReason::NextChannelUnavailable { .. } => self.next_channel_unavailable += 1,
+			HtlcFailureReason::NextChannelFailed { .. } => self.next_channel_failed += 1,
+			HtlcFailureReason::InvalidForward { .. } => self.invalid_forward += 1,
+			HtlcFailureReason::ReceiveFailed { .. } => self.receive_failed += 1,
+		}
+	}
+}
+
+/// Counts the HTLCs we failed back since startup, per channel and reason.
+///
+/// Failures to forward over one of our channels are attributed to that channel, all others to
+/// the channel the HTLC arrived on.
+pub struct HtlcFailureTracker {
+	counters: Mutex<HashMap<[u8; 32], HtlcFailureCounters>>,
+}
+
+impl HtlcFailureTracker {
+	pub(crate) fn new() -> Self {
+		let counters = Mutex::new(HashMap::new());
+		Self { counters }
+	}
+
+	pub(crate) fn record_failure(&self, prev_channel_id: [u8; 32], reason: &HtlcFailureReason) {
+		let channel_id = match reason {
+			HtlcFailureReason::NextChannelUnavailable { channel_id }
+			| HtlcFailureReason::NextChannelFailed { channel_id } => *channel_id,
+			_ => prev_channel_id,
+		};
+		self.counters.lock().unwrap().entry(channel_id).or_default().increment(reason);
+	}
+
+	/// Returns the failure counters of the channel with the given `channel_id`.
+	pub fn channel_counters(&self, channel_id: &[u8; 32]) -> HtlcFailureCounters {
+		self.counters.lock().unwrap().get(channel_id).copied().unwrap_or_default()
+	}
+
+	/// Returns the failure counters of all channels that saw failed HTLCs.
+	pub fn list_counters(&self) -> Vec<([u8; 32], HtlcFailureCounters)> {
+		self.counters.lock().unwrap().iter().map(|(id, counters)| (*id, *counters)).collect()
+	}
+
+	/// Returns the failure counters summed up over all channels.
+	pub fn totals(&self) -> HtlcFailureCounters {
+		let locked_counters = self.counters.lock().unwrap();
+		locked_counters.values().fold(HtlcFailureCounters::default(), |mut totals, counters| {
+			totals.unknown_next_hop += counters.unknown_next_hop;
+			totals.next_channel_unavailable += counters.next_channel_unavailable;
+			totals.next_channel_failed += counters.next_channel_failed;
+			totals.invalid_forward += counters.invalid_forward;
+			totals.receive_failed += counters.receive_failed;
+			totals
+		})
+	}
+
+	/// Resets all counters.
+	pub fn reset(&self) {
+		self.counters.lock().unwrap().clear();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn failures_are_attributed_to_channels() {
+		let tracker = HtlcFailureTracker::new();
+		let (prev_channel_id, next_channel_id) = ([1u8; 32], [2u8; 32]);
+
+		tracker.record_failure(
+			prev_channel_id,
+			&HtlcFailureReason::UnknownNextHop { requested_forward_scid: 42 },
+		);
+		tracker.record_failure(
+			prev_channel_id,
+			&HtlcFailureReason::NextChannelFailed { channel_id: next_channel_id },
+		);
+		tracker.record_failure(
+			prev_channel_id,
+			&HtlcFailureReason::NextChannelFailed { channel_id: next_channel_id },
+		);
+
+		let prev_counters = tracker.channel_counters(&prev_channel_id);
+		assert_eq!(prev_counters.unknown_next_hop, 1);
+		assert_eq!(prev_counters.total(), 1);
+		let next_counters = tracker.channel_counters(&next_channel_id);
+		assert_eq!(next_counters.next_channel_failed, 2);
+		assert_eq!(next_counters.total(), 2);
+		assert_eq!(tracker.totals().total(), 3);
+
+		tracker.reset();
+		assert_eq!(tracker.totals(), HtlcFailureCounters::default());
+	}
+}
@@ -0,0 +1,634 @@
+use crate::event::PaymentRejectionReason;
+use crate::hex_utils;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::payment_store::{
+	unix_time_secs, PaymentDirection, PaymentInfo, PaymentQuery, PaymentStatus, PaymentStore,
+};
+use crate::{ChannelManager, Config, Error, KeysManager};
+
+use lightning::chain::keysinterface::{NodeSigner, Recipient};
+use lightning::ln::channelmanager::{ChannelDetails, PaymentId, MIN_FINAL_CLTV_EXPIRY_DELTA};
+use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
+use lightning::routing::gossip::RoutingFees;
+use lightning::routing::router::{RouteHint, RouteHintHop};
+use lightning::util::persist::KVStorePersister;
+use lightning_invoice::{
+	Bolt11Invoice, CreationError, Currency, Fallback, InvoiceBuilder, SignOrCreationError,
+};
+
+use bitcoin::bech32::ToBase32;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::util::address::Payload;
+use bitcoin::Address;
+
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The default time after which an invoice expires.
+pub const DEFAULT_INVOICE_EXPIRY_SECS: u32 = 3600;
+
+/// The interval in which we check for payments that expired and held payments to fail back.
+const PAYMENT_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The number of blocks before its claim deadline at which we fail back an unclaimed held payment.
+pub const HOLD_INVOICE_CLAIM_DEADLINE_SAFETY_BLOCKS: u32 = 6;
+
+/// The default maximum overpayment of an invoice, in millionths of the invoice amount.
+///
+/// BOLT 4 recommends accepting payments of up to twice the requested amount, as payers may add
+/// to the amount to obscure the final hop of a route.
+pub const DEFAULT_MAX_OVERPAYMENT_PPM: u32 = 1_000_000;
+
+/// The policy applied to inbound payments for invoices which requested a specific amount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InboundAmountPolicy {
+	/// Whether to reject payments which pay less than the invoice amount.
+	pub reject_underpayment: bool,
+	/// The maximum amount, in millionths of the invoice amount, by which a payment may exceed
+	/// the invoice amount. If `None`, overpayments of any size are accepted.
+	pub max_overpayment_ppm: Option<u32>,
+}
+
+impl Default for InboundAmountPolicy {
+	fn default() -> Self {
+		Self { reject_underpayment: true, max_overpayment_ppm: Some(DEFAULT_MAX_OVERPAYMENT_PPM) }
+	}
+}
+
+impl InboundAmountPolicy {
+	/// Checks the received amount of a payment against the amount requested by its invoice.
+	pub(crate) fn check(
+		&self, invoice_amount_msat: u64, received_msat: u64,
+	) -> Result<(), PaymentRejectionReason> {
+		if received_msat < invoice_amount_msat {
+			if self.reject_underpayment {
+				return Err(PaymentRejectionReason::Underpaid { invoice_amount_msat });
+			}
+			return Ok(());
+		}
+
+		if let Some(max_overpayment_ppm) = self.max_overpayment_ppm {
+			let max_overpayment_msat =
+				(invoice_amount_msat as u128 * max_overpayment_ppm as u128 / 1_000_000) as u64;
+			let max_amount_msat = invoice_amount_msat.saturating_add(max_overpayment_msat);
+			if received_msat > max_amount_msat {
+				return Err(PaymentRejectionReason::Overpaid {
+					invoice_amount_msat,
+					max_amount_msat,
+				});
+			}
+		}
+		Ok(())
+	}
+}
+
+/// The description of an invoice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvoiceDescription {
+	/// The description is included in the invoice.
+	Direct(String),
+	/// Only the SHA256 hash of the description is included in the invoice.
+	Hash([u8; 32]),
+}
+
+/// Parameters of an invoice to be created via [`InvoiceHandler::create_invoice`].
+#[derive(Debug, Clone)]
+pub struct InvoiceParams {
+	/// The amount to be paid, in thousandths of a satoshi. If `None`, the payer may choose the
+	/// amount.
+	pub amount_msat: Option<u64>,
+	/// The description of the invoice.
+	pub description: InvoiceDescription,
+	/// The time, in seconds, after which the invoice expires.
+	pub expiry_secs: u32,
+	/// The minimum CLTV delta the final hop of the payment has to adhere to.
+	///
+	/// Has to be at least LDK's `MIN_FINAL_CLTV_EXPIRY_DELTA`, less the few blocks senders add,
+	/// so we can claim payments safely.
+	pub min_final_cltv_expiry_delta: u16,
+	/// Whether to include route hints for our unannounced channels.
+	pub include_route_hints: bool,
+	/// Additional route hints to include, e.g., the one handed out by an LSP that opens channels
+	/// to us just in time.
+	pub extra_route_hints: Vec<RouteHint>,
+	/// An on-chain address the payer may fall back to.
+	pub fallback_address: Option<Address>,
+}
+
+impl InvoiceParams {
+	/// Creates parameters for a variable-amount invoice with the given description and default
+	/// settings.
+	pub fn new(description: InvoiceDescription) -> Self {
+		Self {
+			amount_msat: None,
+			description,
+			expiry_secs: DEFAULT_INVOICE_EXPIRY_SECS,
+			min_final_cltv_expiry_delta: MIN_FINAL_CLTV_EXPIRY_DELTA,
+			include_route_hints: true,
+			extra_route_hints: Vec::new(),
+			fallback_address: None,
+		}
+	}
+}
+
+/// Creates BOLT11 invoices, including hold invoices, for receiving payments.
+///
+/// Payments that weren't paid before their expiry are regularly marked as expired, and held
+/// payments are failed back once their claim deadline approaches.
+pub struct InvoiceHandler<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	channel_manager: Arc<ChannelManager>,
+	keys_manager: Arc<KeysManager>,
+	payment_store: Arc<PaymentStore<K>>,
+	config: Arc<Config>,
+	logger: L,
+}
+
+impl<K: Deref + Send + Sync + 'static, L: Deref + Clone + Send + Sync + 'static>
+	InvoiceHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, keys_manager: Arc<KeysManager>,
+		payment_store: Arc<PaymentStore<K>>, config: Arc<Config>,
+		tokio_runtime: Arc<tokio::runtime::Runtime>, logger: L,
+	) -> Self {
+		let sweep_channel_manager = Arc::clone(&channel_manager);
+		let sweep_payment_store = Arc::clone(&payment_store);
+		let sweep_logger = logger.clone();
+		tokio_runtime.spawn(async move {
+			let mut interval = tokio::time::interval(PAYMENT_EXPIRY_CHECK_INTERVAL);
+			loop {
+				interval.tick().await;
+				if let Err(e) = sweep_payment_store.expire_stale_payments() {
+					log_error!(sweep_logger, "Failed to expire stale payments: {}", e);
+				}
+				if let Err(e) = fail_expiring_hold_invoices(
+					&sweep_channel_manager,
+					&sweep_payment_store,
+					&sweep_logger,
+				) {
+					log_error!(sweep_logger, "Failed to fail back expiring held payments: {}", e);
+				}
+			}
+		});
+		Self { channel_manager, keys_manager, payment_store, config, logger }
+	}
+}
+
+impl<K: Deref, L: Deref> InvoiceHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	/// Creates an invoice according to the given parameters and records it as an inbound payment.
+	pub fn create_invoice(&self, params: InvoiceParams) -> Result<Bolt11Invoice, Error> {
+		check_min_final_cltv_expiry_delta(&params)?;
+		let (payment_hash, payment_secret) = self
+			.channel_manager
+			.create_inbound_payment(
+				params.amount_msat,
+				params.expiry_secs,
+				Some(params.min_final_cltv_expiry_delta),
+			)
+			.map_err(|()| {
+				log_error!(self.logger, "Failed to register inbound payment.");
+				SignOrCreationError::CreationError(CreationError::InvalidAmount)
+			})?;
+
+		self.create_and_record_invoice(params, payment_hash, payment_secret, false)
+	}
+
+	/// Creates a hold invoice for the given externally supplied `payment_hash`.
+	///
+	/// Once a payment for the invoice arrives, an [`Event::PaymentClaimable`] is emitted and the
+	/// payment is held until it is either claimed via [`claim_hold_invoice`] or cancelled via
+	/// [`cancel_hold_invoice`]. If neither happens, it is automatically failed back shortly before
+	/// the HTLCs would expire.
+	///
+	/// [`Event::PaymentClaimable`]: crate::event::Event::PaymentClaimable
+	/// [`claim_hold_invoice`]: Self::claim_hold_invoice
+	/// [`cancel_hold_invoice`]: Self::cancel_hold_invoice
+	pub fn create_hold_invoice(
+		&self, payment_hash: PaymentHash, params: InvoiceParams,
+	) -> Result<Bolt11Invoice, Error> {
+		if self.payment_store.contains(&PaymentId(payment_hash.0)) {
+			return Err(Error::NonUniquePaymentHash);
+		}
+
+		check_min_final_cltv_expiry_delta(&params)?;
+		let payment_secret = self
+			.channel_manager
+			.create_inbound_payment_for_hash(
+				payment_hash,
+				params.amount_msat,
+				params.expiry_secs,
+				Some(params.min_final_cltv_expiry_delta),
+			)
+			.map_err(|()| {
+				log_error!(self.logger, "Failed to register inbound payment.");
+				SignOrCreationError::CreationError(CreationError::InvalidAmount)
+			})?;
+
+		self.create_and_record_invoice(params, payment_hash, payment_secret, true)
+	}
+
+	/// Claims a held payment by revealing its `preimage`.
+	pub fn claim_hold_invoice(&self, preimage: PaymentPreimage) -> Result<(), Error> {
+		let payment_hash = PaymentHash(Sha256::hash(&preimage.0).into_inner());
+		self.check_hold_invoice_claimable(&payment_hash)?;
+
+		self.payment_store.update(&PaymentId(payment_hash.0), |p| p.preimage = Some(preimage))?;
+		self.channel_manager.claim_funds(preimage);
+		log_info!(
+			self.logger,
+			"Claiming held payment with payment hash {}",
+			hex_utils::to_string(&payment_hash.0)
+		);
+		Ok(())
+	}
+
+	/// Cancels a held payment, failing it back to the payer.
+	pub fn cancel_hold_invoice(&self, payment_hash: PaymentHash) -> Result<(), Error> {
+		self.check_hold_invoice_claimable(&payment_hash)?;
+
+		self.channel_manager.fail_htlc_backwards(&payment_hash);
+		self.payment_store
+			.update(&PaymentId(payment_hash.0), |p| p.set_status(PaymentStatus::Failed))?;
+		log_info!(
+			self.logger,
+			"Cancelled held payment with payment hash {}",
+			hex_utils::to_string(&payment_hash.0)
+		);
+		Ok(())
+	}
+
+	fn check_hold_invoice_claimable(&self, payment_hash: &PaymentHash) -> Result<(), Error> {
+		let payment =
+			self.payment_store.get(&PaymentId(payment_hash.0)).ok_or(Error::PaymentNotFound)?;
+		if !payment.hold_invoice || payment.status != PaymentStatus::InFlight {
+			return Err(Error::PaymentNotClaimable);
+		}
+		Ok(())
+	}
+
+	fn create_and_record_invoice(
+		&self, params: InvoiceParams, payment_hash: PaymentHash, payment_secret: PaymentSecret,
+		hold_invoice: bool,
+	) -> Result<Bolt11Invoice, Error> {
+		let invoice = build_invoice(
+			Currency::from(self.config.network),
+			&params,
+			payment_hash,
+			payment_secret,
+			&self.channel_manager.list_usable_channels(),
+			&*self.keys_manager,
+		)?;
+
+		let mut payment = PaymentInfo::new(
+			PaymentId(payment_hash.0),
+			Some(payment_hash),
+			PaymentDirection::Inbound,
+			params.amount_msat,
+			PaymentStatus::Created,
+		);
+		payment.secret = Some(payment_secret);
+		payment.description = match params.description {
+			InvoiceDescription::Direct(description) => Some(description),
+			InvoiceDescription::Hash(_) => None,
+		};
+		payment.invoice = Some(invoice.to_string());
+		payment.expires_at = Some(unix_time_secs() + params.expiry_secs as u64);
+		payment.hold_invoice = hold_invoice;
+		self.payment_store.insert(payment)?;
+
+		log_info!(
+			self.logger,
+			"Created invoice for payment hash {}",
+			hex_utils::to_string(&payment_hash.0)
+		);
+		Ok(invoice)
+	}
+}
+
+/// Fails back all held payments whose claim deadline is less than
+/// [`HOLD_INVOICE_CLAIM_DEADLINE_SAFETY_BLOCKS`] blocks away.
+///
+/// Returns the hashes of the failed payments.
+fn fail_expiring_hold_invoices<K: Deref, L: Deref>(
+	channel_manager: &ChannelManager, payment_store: &PaymentStore<K>, logger: &L,
+) -> Result<Vec<PaymentHash>, Error>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	let best_block_height = channel_manager.current_best_block().height();
+	let query = PaymentQuery {
+		direction: Some(PaymentDirection::Inbound),
+		status: Some(PaymentStatus::InFlight),
+		..Default::default()
+	};
+
+	let mut failed = Vec::new();
+	for payment in payment_store.list_payments(&query) {
+		let expiring = payment.claim_deadline.map_or(false, |deadline| {
+			best_block_height + HOLD_INVOICE_CLAIM_DEADLINE_SAFETY_BLOCKS >= deadline
+		});
+		if !payment.hold_invoice || !expiring {
+			continue;
+		}
+
+		if let Some(payment_hash) = payment.payment_hash {
+			log_info!(
+				logger,
+				"Failing back held payment with payment hash {} as its claim deadline is near.",
+				hex_utils::to_string(&payment_hash.0)
+			);
+			channel_manager.fail_htlc_backwards(&payment_hash);
+			payment_store.update(&payment.payment_id, |p| p.set_status(PaymentStatus::Failed))?;
+			failed.push(payment_hash);
+		}
+	}
+	Ok(failed)
+}
+
+// LDK refuses to register inbound payments whose final CLTV expiry delta, plus the few blocks
+// added by senders, is too short for us to claim them safely.
+fn check_min_final_cltv_expiry_delta(params: &InvoiceParams) -> Result<(), Error> {
+	if params.min_final_cltv_expiry_delta.saturating_add(3) < MIN_FINAL_CLTV_EXPIRY_DELTA {
+		return Err(SignOrCreationError::CreationError(
+			CreationError::MinFinalCltvExpiryDeltaTooShort,
+		)
+		.into());
+	}
+	Ok(())
+}
+
+// Builds and signs an invoice for the given parameters, including route hints for the given
+// usable channels if requested.
+fn build_invoice<S: NodeSigner>(
+	currency: Currency, params: &InvoiceParams, payment_hash: PaymentHash,
+	payment_secret: PaymentSecret, usable_channels: &[ChannelDetails], node_signer: &S,
+) -> Result<Bolt11Invoice, Error> {
+	let invoice_builder = InvoiceBuilder::new(currency);
+	let mut invoice_builder = match params.description {
+		InvoiceDescription::Direct(ref description) => {
+			invoice_builder.description(description.clone())
+		}
+		InvoiceDescription::Hash(hash) => {
+			invoice_builder.description_hash(Sha256::from_inner(hash))
+		}
+	}
+	.payment_hash(Sha256::from_inner(payment_hash.0))
+	.payment_secret(payment_secret)
+	.current_timestamp()
+	.min_final_cltv_expiry_delta(params.min_final_cltv_expiry_delta as u64)
+	.expiry_time(Duration::from_secs(params.expiry_secs as u64))
+	.basic_mpp();
+
+	if let Some(amount_msat) = params.amount_msat {
+		invoice_builder = invoice_builder.amount_milli_satoshis(amount_msat);
+	}
+
+	if let Some(fallback) = params.fallback_address.as_ref().map(to_fallback) {
+		invoice_builder = invoice_builder.fallback(fallback);
+	}
+
+	if params.include_route_hints {
+		for route_hint in private_route_hints(usable_channels) {
+			invoice_builder = invoice_builder.private_route(route_hint);
+		}
+	}
+	for route_hint in params.extra_route_hints.iter() {
+		invoice_builder = invoice_builder.private_route(route_hint.clone());
+	}
+
+	let raw_invoice = invoice_builder.build_raw().map_err(SignOrCreationError::CreationError)?;
+	let hrp_str = raw_invoice.hrp.to_string();
+	let hrp_bytes = hrp_str.as_bytes();
+	let invoice_data = raw_invoice.data.to_base32();
+	let signed_raw_invoice = raw_invoice
+		.sign(|_| node_signer.sign_invoice(hrp_bytes, &invoice_data, Recipient::Node))
+		.map_err(|()| SignOrCreationError::SignError(()))?;
+
+	// The builder only builds semantically valid invoices, and we just signed it.
+	Ok(Bolt11Invoice::from_signed(signed_raw_invoice).expect("the invoice is valid"))
+}
+
+/// Returns route hints for our usable unannounced channels with inbound capacity.
+fn private_route_hints(channels: &[ChannelDetails]) -> Vec<RouteHint> {
+	channels
+		.iter()
+		.filter(|c| !c.is_public && c.inbound_capacity_msat > 0)
+		.filter_map(|c| {
+			let forwarding_info = c.counterparty.forwarding_info.as_ref()?;
+			let short_channel_id = c.get_inbound_payment_scid()?;
+			Some(RouteHint(vec![RouteHintHop {
+				src_node_id: c.counterparty.node_id,
+				short_channel_id,
+				fees: RoutingFees {
+					base_msat: forwarding_info.fee_base_msat,
+					proportional_millionths: forwarding_info.fee_proportional_millionths,
+				},
+				cltv_expiry_delta: forwarding_info.cltv_expiry_delta,
+				htlc_minimum_msat: c.inbound_htlc_minimum_msat,
+				htlc_maximum_msat: c.inbound_htlc_maximum_msat,
+			}]))
+		})
+		.collect()
+}
+
+fn to_fallback(address: &Address) -> Fallback {
+	match address.payload {
+		Payload::PubkeyHash(pubkey_hash) => Fallback::PubKeyHash(pubkey_hash),
+		Payload::ScriptHash(script_hash) => Fallback::ScriptHash(script_hash),
+		Payload::WitnessProgram { version, ref program } => {
+			Fallback::SegWitProgram { version, program: program.clone() }
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use lightning::chain::keysinterface::KeysManager as LdkKeysManager;
+	use lightning::ln::channelmanager::{ChannelCounterparty, CounterpartyForwardingInfo};
+	use lightning::ln::features::InitFeatures;
+
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+	use bitcoin::Script;
+
+	use std::str::FromStr;
+
+	#[test]
+	fn inbound_amount_policy() {
+		let policy = InboundAmountPolicy::default();
+		assert_eq!(policy.check(1000, 1000), Ok(()));
+		assert_eq!(policy.check(1000, 2000), Ok(()));
+		assert_eq!(
+			policy.check(1000, 999),
+			Err(PaymentRejectionReason::Underpaid { invoice_amount_msat: 1000 })
+		);
+		assert_eq!(
+			policy.check(1000, 2001),
+			Err(PaymentRejectionReason::Overpaid {
+				invoice_amount_msat: 1000,
+				max_amount_msat: 2000
+			})
+		);
+
+		let lenient = InboundAmountPolicy { reject_underpayment: false, max_overpayment_ppm: None };
+		assert_eq!(lenient.check(1000, 999), Ok(()));
+		assert_eq!(lenient.check(1000, u64::MAX), Ok(()));
+
+		let strict =
+			InboundAmountPolicy { reject_underpayment: true, max_overpayment_ppm: Some(0) };
+		assert!(strict.check(1000, 1001).is_err());
+	}
+
+	fn channel_details(
+		short_channel_id: u64, is_public: bool, inbound_capacity_msat: u64,
+		forwarding_info: Option<CounterpartyForwardingInfo>,
+	) -> ChannelDetails {
+		let secp_ctx = Secp256k1::new();
+		let node_id = PublicKey::from_secret_key(
+			&secp_ctx,
+			&SecretKey::from_slice(&[short_channel_id as u8; 32]).unwrap(),
+		);
+		ChannelDetails {
+			channel_id: [short_channel_id as u8; 32],
+			counterparty: ChannelCounterparty {
+				node_id,
+				features: InitFeatures::empty(),
+				unspendable_punishment_reserve: 0,
+				forwarding_info,
+				outbound_htlc_minimum_msat: None,
+				outbound_htlc_maximum_msat: None,
+			},
+			funding_txo: None,
+			channel_type: None,
+			short_channel_id: Some(short_channel_id),
+			outbound_scid_alias: None,
+			inbound_scid_alias: None,
+			channel_value_satoshis: 1_000_000,
+			unspendable_punishment_reserve: None,
+			user_channel_id: 0,
+			feerate_sat_per_1000_weight: None,
+			balance_msat: 0,
+			outbound_capacity_msat: 0,
+			next_outbound_htlc_limit_msat: 0,
+			next_outbound_htlc_minimum_msat: 0,
+			inbound_capacity_msat,
+			confirmations_required: None,
+			confirmations: None,
+			force_close_spend_delay: None,
+			is_outbound: false,
+			is_channel_ready: true,
+			channel_shutdown_state: None,
+			is_usable: true,
+			is_public,
+			inbound_htlc_minimum_msat: Some(1_000),
+			inbound_htlc_maximum_msat: Some(500_000_000),
+			config: None,
+		}
+	}
+
+	#[test]
+	fn route_hints_only_cover_private_channels_with_inbound_capacity() {
+		let forwarding_info = CounterpartyForwardingInfo {
+			fee_base_msat: 1_000,
+			fee_proportional_millionths: 100,
+			cltv_expiry_delta: 40,
+		};
+		let channels = vec![
+			channel_details(1, false, 100_000, Some(forwarding_info.clone())),
+			channel_details(2, true, 100_000, Some(forwarding_info.clone())),
+			channel_details(3, false, 0, Some(forwarding_info.clone())),
+			channel_details(4, false, 100_000, None),
+		];
+
+		// Public channels are found via the graph, while channels without inbound capacity or
+		// the counterparty's forwarding info are no use to the sender.
+		let route_hints = private_route_hints(&channels);
+		assert_eq!(
+			route_hints,
+			vec![RouteHint(vec![RouteHintHop {
+				src_node_id: channels[0].counterparty.node_id,
+				short_channel_id: 1,
+				fees: RoutingFees { base_msat: 1_000, proportional_millionths: 100 },
+				cltv_expiry_delta: 40,
+				htlc_minimum_msat: Some(1_000),
+				htlc_maximum_msat: Some(500_000_000),
+			}])]
+		);
+	}
+
+	#[test]
+	fn invoice_from_default_params() {
+		let mut params = InvoiceParams::new(InvoiceDescription::Direct("coffee".to_string()));
+		params.amount_msat = Some(100_000);
+		check_min_final_cltv_expiry_delta(&params).unwrap();
+
+		let keys_manager = LdkKeysManager::new(&[42u8; 32], 42, 42);
+		let forwarding_info = CounterpartyForwardingInfo {
+			fee_base_msat: 1_000,
+			fee_proportional_millionths: 100,
+			cltv_expiry_delta: 40,
+		};
+		let channels = vec![channel_details(1, false, 100_000, Some(forwarding_info))];
+		let invoice = build_invoice(
+			Currency::Regtest,
+			&params,
+			PaymentHash([1u8; 32]),
+			PaymentSecret([2u8; 32]),
+			&channels,
+			&keys_manager,
+		)
+		.unwrap();
+
+		assert_eq!(invoice.amount_milli_satoshis(), Some(100_000));
+		assert_eq!(invoice.min_final_cltv_expiry_delta(), MIN_FINAL_CLTV_EXPIRY_DELTA as u64);
+		assert_eq!(invoice.expiry_time(), Duration::from_secs(DEFAULT_INVOICE_EXPIRY_SECS as u64));
+		assert_eq!(invoice.route_hints().len(), 1);
+		assert_eq!(
+			invoice.recover_payee_pub_key(),
+			keys_manager.get_node_id(Recipient::Node).unwrap()
+		);
+
+		params.min_final_cltv_expiry_delta = MIN_FINAL_CLTV_EXPIRY_DELTA - 4;
+		assert!(matches!(
+			check_min_final_cltv_expiry_delta(&params),
+			Err(Error::LdkInvoiceCreation(SignOrCreationError::CreationError(
+				CreationError::MinFinalCltvExpiryDeltaTooShort
+			)))
+		));
+	}
+
+	#[test]
+	fn fallback_addresses_are_converted() {
+		let addresses = [
+			"1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2",
+			"3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+			"bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq",
+		];
+		for address in addresses.iter() {
+			let address = Address::from_str(address).unwrap();
+			let script_pubkey = match to_fallback(&address) {
+				Fallback::PubKeyHash(pubkey_hash) => Script::new_p2pkh(&pubkey_hash),
+				Fallback::ScriptHash(script_hash) => Script::new_p2sh(&script_hash),
+				Fallback::SegWitProgram { version, program } => {
+					Script::new_witness_program(version, &program)
+				}
+			};
+			assert_eq!(script_pubkey, address.script_pubkey());
+		}
+	}
+}
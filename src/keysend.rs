@@ -0,0 +1,213 @@
+use crate::hex_utils;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::payment_store::{PaymentDirection, PaymentInfo, PaymentStatus, PaymentStore};
+use crate::probing::ProbeHandler;
+use crate::{ChannelManager, Error, KeysManager};
+
+use lightning::chain::keysinterface::EntropySource;
+use lightning::ln::channelmanager::{
+	PaymentId, RecipientOnionFields, Retry, MIN_FINAL_CLTV_EXPIRY_DELTA,
+};
+use lightning::ln::{PaymentHash, PaymentPreimage};
+use lightning::routing::router::{PaymentParameters, RouteParameters};
+use lightning::util::persist::KVStorePersister;
+
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The default time we keep retrying to send a spontaneous payment before we give up.
+pub const DEFAULT_KEYSEND_RETRY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default CLTV expiry delta of the final hop of a spontaneous payment.
+pub const DEFAULT_KEYSEND_FINAL_CLTV_EXPIRY_DELTA: u32 = 144;
+
+/// The lowest TLV type usable for custom records, as lower types are reserved for the protocol.
+const MIN_CUSTOM_TLV_TYPE: u64 = 1 << 16;
+
+/// The configuration of the [`KeysendPayer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeysendConfig {
+	/// The time we keep retrying to send each payment before we give up.
+	pub retry_timeout: Duration,
+	/// If set, the routes to the recipient are probed before each payment, waiting up to this
+	/// long for the results.
+	pub preflight_timeout: Option<Duration>,
+	/// The CLTV expiry delta of the final hop of each payment.
+	///
+	/// As recipients fail back payments that expire too soon to be claimed safely, values below
+	/// LDK's `MIN_FINAL_CLTV_EXPIRY_DELTA` are raised to it.
+	pub final_cltv_expiry_delta: u32,
+}
+
+impl Default for KeysendConfig {
+	fn default() -> Self {
+		Self {
+			retry_timeout: DEFAULT_KEYSEND_RETRY_TIMEOUT,
+			preflight_timeout: None,
+			final_cltv_expiry_delta: DEFAULT_KEYSEND_FINAL_CLTV_EXPIRY_DELTA,
+		}
+	}
+}
+
+impl KeysendConfig {
+	fn final_cltv_expiry_delta(&self) -> u32 {
+		self.final_cltv_expiry_delta.max(MIN_FINAL_CLTV_EXPIRY_DELTA as u32)
+	}
+}
+
+/// Sends spontaneous ("keysend") payments that don't require an invoice.
+pub struct KeysendPayer<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	channel_manager: Arc<ChannelManager>,
+	keys_manager: Arc<KeysManager>,
+	payment_store: Arc<PaymentStore<K>>,
+	probe_handler: Arc<ProbeHandler<K, L>>,
+	config: KeysendConfig,
+	logger: L,
+}
+
+impl<K: Deref, L: Deref> KeysendPayer<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, keys_manager: Arc<KeysManager>,
+		payment_store: Arc<PaymentStore<K>>, probe_handler: Arc<ProbeHandler<K, L>>,
+		config: KeysendConfig, logger: L,
+	) -> Self {
+		Self { channel_manager, keys_manager, payment_store, probe_handler, config, logger }
+	}
+
+	/// Sends a spontaneous payment of `amount_msat` to the node with the given `node_id`.
+	///
+	/// The given `custom_tlvs` will be included in the onion for the recipient. Their types must
+	/// be unique and at least 2^16, as lower types are reserved for the protocol.
+	///
+	/// If preflight probing is enabled, the payment isn't sent if the probes to the recipient
+	/// fail.
+	///
+	/// Returns the hash of the payment, which will be reported in the corresponding
+	/// [`Event::PaymentSuccessful`] or [`Event::PaymentFailed`].
+	///
+	/// [`Event::PaymentSuccessful`]: crate::event::Event::PaymentSuccessful
+	/// [`Event::PaymentFailed`]: crate::event::Event::PaymentFailed
+	pub fn send_spontaneous_payment(
+		&self, node_id: PublicKey, amount_msat: u64, custom_tlvs: Vec<(u64, Vec<u8>)>,
+	) -> Result<PaymentHash, Error> {
+		let custom_tlvs = sorted_custom_tlvs(custom_tlvs)?;
+		let recipient_onion = RecipientOnionFields::spontaneous_empty()
+			.with_custom_tlvs(custom_tlvs.clone())
+			.map_err(|()| Error::InvalidCustomTlvs)?;
+
+		let payment_preimage = PaymentPreimage(self.keys_manager.get_secure_random_bytes());
+		let payment_hash = PaymentHash(Sha256::hash(&payment_preimage.0).into_inner());
+		let payment_id = PaymentId(payment_hash.0);
+		if self.payment_store.contains(&payment_id) {
+			return Err(Error::NonUniquePaymentHash);
+		}
+
+		let final_cltv_expiry_delta = self.config.final_cltv_expiry_delta();
+		if let Some(preflight_timeout) = self.config.preflight_timeout {
+			self.probe_handler.preflight_node(
+				node_id,
+				amount_msat,
+				final_cltv_expiry_delta,
+				preflight_timeout,
+			)?;
+		}
+
+		let route_params = RouteParameters {
+			payment_params: PaymentParameters::for_keysend(node_id, final_cltv_expiry_delta),
+			final_value_msat: amount_msat,
+		};
+
+		let mut payment = PaymentInfo::new(
+			payment_id,
+			Some(payment_hash),
+			PaymentDirection::Outbound,
+			Some(amount_msat),
+			PaymentStatus::InFlight,
+		);
+		payment.preimage = Some(payment_preimage);
+		payment.custom_tlvs = custom_tlvs;
+		self.payment_store.insert(payment)?;
+
+		match self.channel_manager.send_spontaneous_payment_with_retry(
+			Some(payment_preimage),
+			recipient_onion,
+			payment_id,
+			route_params,
+			Retry::Timeout(self.config.retry_timeout),
+		) {
+			Ok(_) => {
+				log_info!(
+					self.logger,
+					"Initiated sending {} msats to {} with payment hash {}.",
+					amount_msat,
+					node_id,
+					hex_utils::to_string(&payment_hash.0),
+				);
+				Ok(payment_hash)
+			}
+			Err(e) => {
+				log_error!(self.logger, "Failed to send spontaneous payment: {:?}", e);
+				self.payment_store.update(&payment_id, |p| p.set_status(PaymentStatus::Failed))?;
+				Err(Error::PaymentSendingFailed)
+			}
+		}
+	}
+}
+
+// Sorts the given custom TLV records by type, as required for the onion, and checks that their
+// types are unique and not reserved.
+fn sorted_custom_tlvs(mut custom_tlvs: Vec<(u64, Vec<u8>)>) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+	custom_tlvs.sort_by_key(|(t, _)| *t);
+	let has_reserved_type = custom_tlvs.first().map_or(false, |(t, _)| *t < MIN_CUSTOM_TLV_TYPE);
+	let has_duplicate_type = custom_tlvs.windows(2).any(|w| w[0].0 == w[1].0);
+	if has_reserved_type || has_duplicate_type {
+		return Err(Error::InvalidCustomTlvs);
+	}
+	Ok(custom_tlvs)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn custom_tlvs_are_sorted_and_validated() {
+		assert_eq!(sorted_custom_tlvs(Vec::new()).unwrap(), Vec::new());
+
+		let custom_tlvs = vec![(70_000, vec![1]), (1 << 16, vec![2]), (u64::MAX, vec![3])];
+		assert_eq!(
+			sorted_custom_tlvs(custom_tlvs).unwrap(),
+			vec![(1 << 16, vec![2]), (70_000, vec![1]), (u64::MAX, vec![3])]
+		);
+
+		// Types below 2^16 are reserved, wherever they appear in the input.
+		let custom_tlvs = vec![(70_000, vec![1]), ((1 << 16) - 1, vec![2])];
+		assert!(matches!(sorted_custom_tlvs(custom_tlvs), Err(Error::InvalidCustomTlvs)));
+
+		// Duplicate types are rejected even if not adjacent in the input.
+		let custom_tlvs = vec![(70_000, vec![1]), (80_000, vec![2]), (70_000, vec![3])];
+		assert!(matches!(sorted_custom_tlvs(custom_tlvs), Err(Error::InvalidCustomTlvs)));
+	}
+
+	#[test]
+	fn final_cltv_expiry_delta_is_at_least_the_minimum() {
+		let config = KeysendConfig::default();
+		assert_eq!(config.final_cltv_expiry_delta(), DEFAULT_KEYSEND_FINAL_CLTV_EXPIRY_DELTA);
+
+		let config = KeysendConfig { final_cltv_expiry_delta: 18, ..Default::default() };
+		assert_eq!(config.final_cltv_expiry_delta(), MIN_FINAL_CLTV_EXPIRY_DELTA as u32);
+	}
+}
@@ -0,0 +1,648 @@
+use crate::forwarding_policy::ForwardingPolicyManager;
+use crate::hex_utils;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, log_warn, Logger};
+use crate::{ChannelManager, Error, KeysManager};
+
+use lightning::chain::keysinterface::EntropySource;
+use lightning::ln::channelmanager::InterceptId;
+use lightning::ln::PaymentHash;
+use lightning::routing::gossip::RoutingFees;
+use lightning::routing::router::{RouteHint, RouteHintHop};
+use lightning::util::config::UserConfig;
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use bitcoin::secp256k1::PublicKey;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The intercept SCIDs issued to our clients will be persisted under this key.
+pub(crate) const LSP_INTERCEPT_SCIDS_PERSISTENCE_KEY: &str = "lsp_intercept_scids";
+
+/// The interval in which we open channels for collected payments and check for timed out ones.
+const PENDING_OPENS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The time we keep forwarding further parts of a payment over the channel opened for it.
+const OPENED_CHANNEL_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+/// The configuration of the LSP mode, in which we open channels to clients just in time.
+///
+/// Note that as the opening fee is deducted from the forwarded HTLCs, clients need to accept
+/// HTLCs that pay less than the amount their invoice requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LspConfig {
+	/// The fixed fee charged for opening a channel, in thousandths of a satoshi.
+	pub channel_opening_fee_base_msat: u64,
+	/// The fee charged for opening a channel per millionth of the forwarded amount.
+	pub channel_opening_fee_proportional_millionths: u32,
+	/// The minimum size of the channels we open, in satoshis.
+	///
+	/// Channels are sized at twice the forwarded amount if that is larger, so there is room left
+	/// for the channel reserve and the commitment transaction fees.
+	pub min_channel_size_sats: u64,
+	/// The time we wait for further parts of a multi-path payment after the first one arrived,
+	/// before opening a channel sized for all of them.
+	pub mpp_collection_window: Duration,
+	/// The time after which we give up on a channel that didn't become ready and fail the
+	/// intercepted HTLCs back.
+	pub channel_open_timeout: Duration,
+	/// The CLTV expiry delta advertised in the route hints for intercept SCIDs.
+	pub cltv_expiry_delta: u16,
+}
+
+impl Default for LspConfig {
+	fn default() -> Self {
+		Self {
+			channel_opening_fee_base_msat: 1_000_000,
+			channel_opening_fee_proportional_millionths: 10_000,
+			min_channel_size_sats: 100_000,
+			mpp_collection_window: Duration::from_secs(2),
+			channel_open_timeout: Duration::from_secs(60),
+			cltv_expiry_delta: 72,
+		}
+	}
+}
+
+// The client and payment hash identifying an intercepted payment.
+type PaymentKey = (PublicKey, PaymentHash);
+
+// An intercepted payment we're opening a channel to a client for, along with the HTLCs to forward
+// once the channel is ready.
+struct PendingChannelOpen {
+	htlcs: Vec<(InterceptId, u64)>,
+	received_at: Instant,
+	// The `user_channel_id` of the channel and the time we started opening it, which we only do
+	// once the parts of the payment were collected.
+	opening: Option<(u128, Instant)>,
+}
+
+// A channel we opened to a client, over which later parts of the same payment are forwarded.
+struct OpenedChannel {
+	channel_id: [u8; 32],
+	user_channel_id: u128,
+	ready_at: Instant,
+}
+
+/// Opens zero-conf channels to clients without inbound liquidity when payments for them arrive.
+///
+/// Clients are handed a route hint via an intercept SCID, i.e., a fake channel from us to them,
+/// which they include in their invoices. HTLCs we're asked to forward over such a channel are
+/// held until we've opened a channel to the client, and are then forwarded over it with the
+/// opening fee deducted.
+///
+/// The parts of multi-path payments are collected for [`LspConfig::mpp_collection_window`] and a
+/// single channel sized for all of them is opened. Parts arriving after the channel became ready
+/// are forwarded over it in full.
+///
+/// Intercepted HTLCs are only held in memory, so HTLCs pending during a restart are failed back
+/// by LDK once they are about to expire.
+pub struct LspHandler<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	channel_manager: Arc<ChannelManager>,
+	keys_manager: Arc<KeysManager>,
+	forwarding_policy_manager: Arc<ForwardingPolicyManager<K, L>>,
+	config: LspConfig,
+	intercept_scids: Mutex<HashMap<u64, PublicKey>>,
+	pending_opens: Arc<Mutex<HashMap<PaymentKey, PendingChannelOpen>>>,
+	opened_channels: Arc<Mutex<HashMap<PaymentKey, OpenedChannel>>>,
+	persister: K,
+	logger: L,
+}
+
+impl<K: Deref + Send + Sync + 'static, L: Deref + Clone + Send + Sync + 'static> LspHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, keys_manager: Arc<KeysManager>,
+		forwarding_policy_manager: Arc<ForwardingPolicyManager<K, L>>, config: LspConfig,
+		persister: K, tokio_runtime: Arc<tokio::runtime::Runtime>, logger: L,
+	) -> Self {
+		let intercept_scids = Mutex::new(HashMap::new());
+		let pending_opens = Arc::new(Mutex::new(HashMap::new()));
+		let opened_channels = Arc::new(Mutex::new(HashMap::new()));
+		let handler = Self {
+			channel_manager,
+			keys_manager,
+			forwarding_policy_manager,
+			config,
+			intercept_scids,
+			pending_opens,
+			opened_channels,
+			persister,
+			logger,
+		};
+		handler.start(&tokio_runtime);
+		handler
+	}
+
+	// Starts regularly opening channels for collected payments and failing those that timed out.
+	fn start(&self, tokio_runtime: &tokio::runtime::Runtime) {
+		let timer_channel_manager = Arc::clone(&self.channel_manager);
+		let timer_keys_manager = Arc::clone(&self.keys_manager);
+		let timer_forwarding_policy_manager = Arc::clone(&self.forwarding_policy_manager);
+		let timer_config = self.config.clone();
+		let timer_pending_opens = Arc::clone(&self.pending_opens);
+		let timer_opened_channels = Arc::clone(&self.opened_channels);
+		let timer_logger = self.logger.clone();
+		tokio_runtime.spawn(async move {
+			let mut interval = tokio::time::interval(PENDING_OPENS_CHECK_INTERVAL);
+			loop {
+				interval.tick().await;
+				process_pending_opens(
+					&timer_channel_manager,
+					&timer_keys_manager,
+					&timer_forwarding_policy_manager,
+					&timer_config,
+					&timer_pending_opens,
+					&timer_opened_channels,
+					&timer_logger,
+				);
+			}
+		});
+	}
+}
+
+impl<K: Deref, L: Deref> LspHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	/// Issues an intercept SCID to the client with the given node id and returns the route hint
+	/// the client should include in its invoices.
+	///
+	/// If the client was already registered, the route hint for its existing SCID is returned.
+	pub fn register_client(&self, client_node_id: PublicKey) -> Result<RouteHint, Error> {
+		let mut locked_scids = self.intercept_scids.lock().unwrap();
+		let existing_scid =
+			locked_scids.iter().find(|(_, node_id)| **node_id == client_node_id).map(|(s, _)| *s);
+		let intercept_scid = match existing_scid {
+			Some(intercept_scid) => intercept_scid,
+			None => {
+				let intercept_scid = self.channel_manager.get_intercept_scid();
+				locked_scids.insert(intercept_scid, client_node_id);
+				self.persist_intercept_scids(&locked_scids)?;
+				log_info!(
+					self.logger,
+					"Issued intercept SCID {} to client {}.",
+					intercept_scid,
+					client_node_id
+				);
+				intercept_scid
+			}
+		};
+
+		Ok(RouteHint(vec![RouteHintHop {
+			src_node_id: self.channel_manager.get_our_node_id(),
+			short_channel_id: intercept_scid,
+			fees: RoutingFees { base_msat: 0, proportional_millionths: 0 },
+			cltv_expiry_delta: self.config.cltv_expiry_delta,
+			htlc_minimum_msat: None,
+			htlc_maximum_msat: None,
+		}]))
+	}
+
+	/// Revokes the intercept SCID issued to the client with the given node id.
+	///
+	/// Payments to invoices including its route hint will fail from then on.
+	pub fn unregister_client(&self, client_node_id: PublicKey) -> Result<(), Error> {
+		let mut locked_scids = self.intercept_scids.lock().unwrap();
+		let len_before = locked_scids.len();
+		locked_scids.retain(|_, node_id| *node_id != client_node_id);
+		if locked_scids.len() != len_before {
+			self.persist_intercept_scids(&locked_scids)?;
+		}
+		Ok(())
+	}
+
+	/// Handles an HTLC we were asked to forward over an intercept SCID.
+	///
+	/// The HTLC is held until a channel to the respective client is ready, unless we already
+	/// opened one for an earlier part of the same payment.
+	pub(crate) fn htlc_intercepted(
+		&self, intercept_id: InterceptId, requested_next_hop_scid: u64, payment_hash: PaymentHash,
+		expected_outbound_amount_msat: u64,
+	) {
+		let client_node_id =
+			match self.intercept_scids.lock().unwrap().get(&requested_next_hop_scid) {
+				Some(client_node_id) => *client_node_id,
+				None => {
+					log_warn!(
+						self.logger,
+						"Intercepted HTLC for unknown SCID {}.",
+						requested_next_hop_scid
+					);
+					fail_htlc(&self.channel_manager, intercept_id, &self.logger);
+					return;
+				}
+			};
+		let payment_key = (client_node_id, payment_hash);
+
+		let opened_channel_id =
+			self.opened_channels.lock().unwrap().get(&payment_key).map(|c| c.channel_id);
+		if let Some(channel_id) = opened_channel_id {
+			// The opening fee was already deducted from the earlier parts.
+			if let Err(e) = self.channel_manager.forward_intercepted_htlc(
+				intercept_id,
+				&channel_id,
+				client_node_id,
+				expected_outbound_amount_msat,
+			) {
+				log_error!(
+					self.logger,
+					"Failed to forward intercepted HTLC to client {}: {:?}",
+					client_node_id,
+					e
+				);
+				fail_htlc(&self.channel_manager, intercept_id, &self.logger);
+			}
+			return;
+		}
+
+		let mut locked_pending_opens = self.pending_opens.lock().unwrap();
+		let pending_open = locked_pending_opens.entry(payment_key).or_insert_with(|| {
+			PendingChannelOpen { htlcs: Vec::new(), received_at: Instant::now(), opening: None }
+		});
+		pending_open.htlcs.push((intercept_id, expected_outbound_amount_msat));
+	}
+
+	/// Forwards the HTLCs waiting for the given channel, if it was opened by us for a client.
+	pub(crate) fn channel_ready(&self, user_channel_id: u128, channel_id: [u8; 32]) {
+		let mut locked_pending_opens = self.pending_opens.lock().unwrap();
+		let payment_key = match locked_pending_opens
+			.iter()
+			.find(|(_, p)| p.opening.map_or(false, |(id, _)| id == user_channel_id))
+		{
+			Some((payment_key, _)) => *payment_key,
+			None => return,
+		};
+		let pending_open = locked_pending_opens.remove(&payment_key).unwrap();
+		let (client_node_id, payment_hash) = payment_key;
+
+		let htlc_amounts_msat =
+			pending_open.htlcs.iter().map(|(_, amount_msat)| *amount_msat).collect::<Vec<_>>();
+		let total_amount_msat = htlc_amounts_msat.iter().sum();
+		let opening_fee_msat = opening_fee_msat(&self.config, total_amount_msat);
+		let htlc_fees_msat = match split_opening_fee(&htlc_amounts_msat, opening_fee_msat) {
+			Some(htlc_fees_msat) => htlc_fees_msat,
+			None => {
+				log_error!(
+					self.logger,
+					"Failed to forward payment with hash {}: {} msats don't cover the opening fee of {} msats.",
+					hex_utils::to_string(&payment_hash.0),
+					total_amount_msat,
+					opening_fee_msat
+				);
+				for (intercept_id, _) in pending_open.htlcs {
+					fail_htlc(&self.channel_manager, intercept_id, &self.logger);
+				}
+				return;
+			}
+		};
+
+		for ((intercept_id, amount_msat), fee_msat) in
+			pending_open.htlcs.into_iter().zip(htlc_fees_msat)
+		{
+			if let Err(e) = self.channel_manager.forward_intercepted_htlc(
+				intercept_id,
+				&channel_id,
+				client_node_id,
+				amount_msat - fee_msat,
+			) {
+				log_error!(
+					self.logger,
+					"Failed to forward intercepted HTLC to client {}: {:?}",
+					client_node_id,
+					e
+				);
+				fail_htlc(&self.channel_manager, intercept_id, &self.logger);
+			}
+		}
+		log_info!(
+			self.logger,
+			"Forwarded payment with hash {} to client {}, deducting an opening fee of {} msats.",
+			hex_utils::to_string(&payment_hash.0),
+			client_node_id,
+			opening_fee_msat
+		);
+
+		self.opened_channels.lock().unwrap().insert(
+			payment_key,
+			OpenedChannel { channel_id, user_channel_id, ready_at: Instant::now() },
+		);
+	}
+
+	/// Fails the HTLCs waiting for the given channel, if it was opened by us for a client.
+	pub(crate) fn channel_closed(&self, user_channel_id: u128) {
+		self.opened_channels.lock().unwrap().retain(|_, c| c.user_channel_id != user_channel_id);
+
+		let mut locked_pending_opens = self.pending_opens.lock().unwrap();
+		let payment_key = match locked_pending_opens
+			.iter()
+			.find(|(_, p)| p.opening.map_or(false, |(id, _)| id == user_channel_id))
+		{
+			Some((payment_key, _)) => *payment_key,
+			None => return,
+		};
+		let pending_open = locked_pending_opens.remove(&payment_key).unwrap();
+		log_error!(
+			self.logger,
+			"Channel to client {} closed before becoming ready.",
+			payment_key.0
+		);
+		for (intercept_id, _) in pending_open.htlcs {
+			fail_htlc(&self.channel_manager, intercept_id, &self.logger);
+		}
+	}
+
+	fn persist_intercept_scids(&self, locked_scids: &HashMap<u64, PublicKey>) -> Result<(), Error> {
+		self.persister
+			.persist(LSP_INTERCEPT_SCIDS_PERSISTENCE_KEY, &InterceptScidsSerWrapper(locked_scids))
+			.map_err(|_| Error::PersistenceFailed)?;
+		Ok(())
+	}
+}
+
+impl<K: Deref + Send + Sync + 'static, L: Deref + Clone + Send + Sync + 'static>
+	ReadableArgs<(
+		Arc<ChannelManager>,
+		Arc<KeysManager>,
+		Arc<ForwardingPolicyManager<K, L>>,
+		LspConfig,
+		K,
+		Arc<tokio::runtime::Runtime>,
+		L,
+	)> for LspHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+		args: (
+			Arc<ChannelManager>,
+			Arc<KeysManager>,
+			Arc<ForwardingPolicyManager<K, L>>,
+			LspConfig,
+			K,
+			Arc<tokio::runtime::Runtime>,
+			L,
+		),
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let (
+			channel_manager,
+			keys_manager,
+			forwarding_policy_manager,
+			config,
+			persister,
+			tokio_runtime,
+			logger,
+		) = args;
+		let read_scids: InterceptScidsDeserWrapper = Readable::read(reader)?;
+		let intercept_scids = Mutex::new(read_scids.0);
+		let pending_opens = Arc::new(Mutex::new(HashMap::new()));
+		let opened_channels = Arc::new(Mutex::new(HashMap::new()));
+		let handler = Self {
+			channel_manager,
+			keys_manager,
+			forwarding_policy_manager,
+			config,
+			intercept_scids,
+			pending_opens,
+			opened_channels,
+			persister,
+			logger,
+		};
+		handler.start(&tokio_runtime);
+		Ok(handler)
+	}
+}
+
+/// Opens channels for the payments whose parts were collected for
+/// [`LspConfig::mpp_collection_window`], fails the HTLCs waiting for channels that didn't become
+/// ready within [`LspConfig::channel_open_timeout`] and closes those channels, and forgets about
+/// channels that became ready more than [`OPENED_CHANNEL_RETENTION`] ago.
+fn process_pending_opens<K: Deref, L: Deref>(
+	channel_manager: &ChannelManager, keys_manager: &KeysManager,
+	forwarding_policy_manager: &ForwardingPolicyManager<K, L>, config: &LspConfig,
+	pending_opens: &Mutex<HashMap<PaymentKey, PendingChannelOpen>>,
+	opened_channels: &Mutex<HashMap<PaymentKey, OpenedChannel>>, logger: &L,
+) where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	opened_channels.lock().unwrap().retain(|_, c| c.ready_at.elapsed() < OPENED_CHANNEL_RETENTION);
+
+	let mut locked_pending_opens = pending_opens.lock().unwrap();
+	let mut failed_payment_keys = Vec::new();
+	for ((client_node_id, payment_hash), pending_open) in locked_pending_opens.iter_mut() {
+		match pending_open.opening {
+			None if pending_open.received_at.elapsed() >= config.mpp_collection_window => {
+				let total_amount_msat = pending_open.htlcs.iter().map(|(_, a)| a).sum();
+				let channel_size_sats = channel_size_sats(config, total_amount_msat);
+				let user_channel_id = u128::from_ne_bytes(
+					keys_manager.get_secure_random_bytes()[..16].try_into().unwrap(),
+				);
+				let mut user_config = UserConfig::default();
+				user_config.channel_handshake_config.announced_channel = false;
+				forwarding_policy_manager.apply_to_user_config(&mut user_config);
+
+				match channel_manager.create_channel(
+					*client_node_id,
+					channel_size_sats,
+					0,
+					user_channel_id,
+					Some(user_config),
+				) {
+					Ok(_) => {
+						log_info!(
+							logger,
+							"Opening channel of {} sats to client {} for payment hash {}.",
+							channel_size_sats,
+							client_node_id,
+							hex_utils::to_string(&payment_hash.0)
+						);
+						pending_open.opening = Some((user_channel_id, Instant::now()));
+					}
+					Err(e) => {
+						log_error!(
+							logger,
+							"Failed to open channel to client {}: {:?}",
+							client_node_id,
+							e
+						);
+						failed_payment_keys.push((*client_node_id, *payment_hash));
+					}
+				}
+			}
+			Some((_, opening_started_at))
+				if opening_started_at.elapsed() > config.channel_open_timeout =>
+			{
+				log_error!(
+					logger,
+					"Timed out opening channel to client {} for payment hash {}.",
+					client_node_id,
+					hex_utils::to_string(&payment_hash.0)
+				);
+				failed_payment_keys.push((*client_node_id, *payment_hash));
+			}
+			_ => {}
+		}
+	}
+
+	for payment_key in failed_payment_keys {
+		let pending_open = locked_pending_opens.remove(&payment_key).unwrap();
+		for (intercept_id, _) in pending_open.htlcs {
+			fail_htlc(channel_manager, intercept_id, logger);
+		}
+
+		let user_channel_id = match pending_open.opening {
+			Some((user_channel_id, _)) => user_channel_id,
+			None => continue,
+		};
+		if let Some(channel) = channel_manager
+			.list_channels()
+			.into_iter()
+			.find(|c| c.user_channel_id == user_channel_id)
+		{
+			// The channel never became ready, so there is nothing to claim on-chain.
+			if let Err(e) = channel_manager
+				.force_close_without_broadcasting_txn(&channel.channel_id, &payment_key.0)
+			{
+				log_error!(
+					logger,
+					"Failed to close timed out channel {}: {:?}",
+					hex_utils::to_string(&channel.channel_id),
+					e
+				);
+			}
+		}
+	}
+}
+
+fn fail_htlc<L: Deref>(channel_manager: &ChannelManager, intercept_id: InterceptId, logger: &L)
+where
+	L::Target: Logger,
+{
+	if let Err(e) = channel_manager.fail_intercepted_htlc(intercept_id) {
+		log_error!(logger, "Failed to fail intercepted HTLC: {:?}", e);
+	}
+}
+
+struct InterceptScidsDeserWrapper(HashMap<u64, PublicKey>);
+
+impl Readable for InterceptScidsDeserWrapper {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let len: u64 = Readable::read(reader)?;
+		let mut scids = HashMap::with_capacity(len as usize);
+		for _ in 0..len {
+			let intercept_scid: u64 = Readable::read(reader)?;
+			let client_node_id: PublicKey = Readable::read(reader)?;
+			scids.insert(intercept_scid, client_node_id);
+		}
+		Ok(Self(scids))
+	}
+}
+
+struct InterceptScidsSerWrapper<'a>(&'a HashMap<u64, PublicKey>);
+
+impl Writeable for InterceptScidsSerWrapper<'_> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		(self.0.len() as u64).write(writer)?;
+		for (intercept_scid, client_node_id) in self.0.iter() {
+			intercept_scid.write(writer)?;
+			client_node_id.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+fn opening_fee_msat(config: &LspConfig, amount_msat: u64) -> u64 {
+	let proportional_fee_msat = amount_msat as u128
+		* config.channel_opening_fee_proportional_millionths as u128
+		/ 1_000_000;
+	config.channel_opening_fee_base_msat.saturating_add(proportional_fee_msat as u64)
+}
+
+// Splits the opening fee across the HTLCs of a payment in proportion to their amounts, leaving
+// each of them at least one msat to forward. Returns `None` if the HTLCs don't cover the fee.
+fn split_opening_fee(htlc_amounts_msat: &[u64], opening_fee_msat: u64) -> Option<Vec<u64>> {
+	let total_amount_msat: u64 = htlc_amounts_msat.iter().sum();
+	if total_amount_msat <= opening_fee_msat
+		|| total_amount_msat - opening_fee_msat < htlc_amounts_msat.len() as u64
+	{
+		return None;
+	}
+
+	let mut fees_msat = htlc_amounts_msat
+		.iter()
+		.map(|amount_msat| {
+			(opening_fee_msat as u128 * *amount_msat as u128 / total_amount_msat as u128) as u64
+		})
+		.collect::<Vec<_>>();
+	// Deduct what's left over from rounding down from the parts that can still afford it.
+	let mut remaining_fee_msat = opening_fee_msat - fees_msat.iter().sum::<u64>();
+	for (fee_msat, amount_msat) in fees_msat.iter_mut().zip(htlc_amounts_msat) {
+		let extra_fee_msat = remaining_fee_msat.min(amount_msat.saturating_sub(*fee_msat + 1));
+		*fee_msat += extra_fee_msat;
+		remaining_fee_msat -= extra_fee_msat;
+	}
+	Some(fees_msat)
+}
+
+fn channel_size_sats(config: &LspConfig, amount_msat: u64) -> u64 {
+	let amount_sats = (amount_msat + 999) / 1000;
+	config.min_channel_size_sats.max(amount_sats.saturating_mul(2))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn opening_fee_and_channel_size() {
+		let config = LspConfig {
+			channel_opening_fee_base_msat: 1000,
+			channel_opening_fee_proportional_millionths: 10_000,
+			min_channel_size_sats: 100_000,
+			..Default::default()
+		};
+		assert_eq!(opening_fee_msat(&config, 0), 1000);
+		assert_eq!(opening_fee_msat(&config, 5_000_000), 51_000);
+
+		assert_eq!(channel_size_sats(&config, 10_000_000), 100_000);
+		assert_eq!(channel_size_sats(&config, 80_000_001), 160_002);
+	}
+
+	#[test]
+	fn opening_fee_is_split_across_parts() {
+		assert_eq!(split_opening_fee(&[10_000], 1_000), Some(vec![1_000]));
+		assert_eq!(split_opening_fee(&[6_000, 3_000, 1_000], 1_000), Some(vec![600, 300, 100]));
+
+		// Small parts that couldn't cover the fee on their own only pay their share.
+		let htlc_amounts_msat = [1_000u64, 1_000, 1_000, 1_000, 100_000];
+		let htlc_fees_msat = split_opening_fee(&htlc_amounts_msat, 10_400).unwrap();
+		assert_eq!(htlc_fees_msat, vec![100, 100, 100, 100, 10_000]);
+
+		// Rounding leftovers are only deducted from parts that can afford them.
+		let htlc_amounts_msat = [3u64, 3, 3];
+		assert_eq!(split_opening_fee(&htlc_amounts_msat, 5), Some(vec![2, 2, 1]));
+		assert_eq!(split_opening_fee(&htlc_amounts_msat, 6), Some(vec![2, 2, 2]));
+
+		// Every part needs to forward something.
+		assert_eq!(split_opening_fee(&[1, 1, 1], 1), None);
+		assert_eq!(split_opening_fee(&[500, 500], 1_000), None);
+		assert_eq!(split_opening_fee(&[500, 500], 2_000), None);
+	}
+}
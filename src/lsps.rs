@@ -0,0 +1,1128 @@
+use crate::hex_utils;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, log_warn, Logger};
+use crate::payment_store::unix_time_secs;
+use crate::{Error, PeerManager};
+
+use lightning::ln::features::{InitFeatures, NodeFeatures};
+use lightning::ln::msgs::{DecodeError, LightningError};
+use lightning::ln::peer_handler::CustomMessageHandler;
+use lightning::ln::wire::{CustomMessageReader, Type};
+use lightning::ln::PaymentHash;
+use lightning::routing::gossip::RoutingFees;
+use lightning::routing::router::{RouteHint, RouteHintHop};
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+use lightning_invoice::Bolt11Invoice;
+
+use bitcoin::secp256k1::PublicKey;
+use chrono::DateTime;
+use rand::{thread_rng, Rng};
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+
+/// The message type used for LSPS messages, as defined by LSPS0.
+pub(crate) const LSPS_MESSAGE_TYPE: u16 = 37913;
+
+/// The JIT channels bought from LSPs will be persisted under this key.
+pub(crate) const LSPS_JIT_CHANNELS_PERSISTENCE_KEY: &str = "lsps_jit_channels";
+
+/// The time we wait for an LSP to respond to a request.
+const LSPS_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The time, in seconds, we keep used and expired JIT channels around after their opening fee
+/// parameters expired, so that replayed payment events still find the channel they used.
+const JIT_CHANNEL_RETENTION_SECS: u64 = 14 * 24 * 60 * 60;
+
+/// A raw LSPS0 message, i.e., a JSON-RPC 2.0 object sent as a custom peer message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LspsMessage {
+	/// The JSON payload of the message.
+	pub payload: String,
+}
+
+impl Type for LspsMessage {
+	fn type_id(&self) -> u16 {
+		LSPS_MESSAGE_TYPE
+	}
+}
+
+impl Writeable for LspsMessage {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		writer.write_all(self.payload.as_bytes())
+	}
+}
+
+/// The options an LSP supports for channels bought via LSPS1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lsps1Options {
+	/// The minimum number of confirmations we may require before the channel is usable.
+	pub min_required_channel_confirmations: u16,
+	/// The minimum number of blocks we may require the funding transaction to confirm within.
+	pub min_funding_confirms_within_blocks: u16,
+	/// The minimum balance on our side of the channel, in satoshis.
+	pub min_initial_client_balance_sat: u64,
+	/// The maximum balance on our side of the channel, in satoshis.
+	pub max_initial_client_balance_sat: u64,
+	/// The minimum balance on the LSP's side of the channel, in satoshis.
+	pub min_initial_lsp_balance_sat: u64,
+	/// The maximum balance on the LSP's side of the channel, in satoshis.
+	pub max_initial_lsp_balance_sat: u64,
+	/// The minimum size of the channel, in satoshis.
+	pub min_channel_balance_sat: u64,
+	/// The maximum size of the channel, in satoshis.
+	pub max_channel_balance_sat: u64,
+	/// The maximum number of blocks the LSP will keep the channel open for.
+	pub max_channel_expiry_blocks: u32,
+}
+
+/// The parameters of a channel bought via LSPS1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lsps1OrderParams {
+	/// The balance on the LSP's side of the channel, i.e., our inbound liquidity, in satoshis.
+	pub lsp_balance_sat: u64,
+	/// The balance on our side of the channel, in satoshis.
+	pub client_balance_sat: u64,
+	/// The number of confirmations required before the channel is usable.
+	pub required_channel_confirmations: u16,
+	/// The number of blocks the funding transaction has to confirm within.
+	pub funding_confirms_within_blocks: u16,
+	/// The number of blocks the LSP has to keep the channel open for.
+	pub channel_expiry_blocks: u32,
+	/// The token the LSP may have handed out to us, e.g., for a discount.
+	pub token: Option<String>,
+	/// Whether the channel should be announced to the network.
+	pub announce_channel: bool,
+}
+
+/// The state of an LSPS1 order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lsps1OrderState {
+	/// The order was created and awaits payment.
+	Created,
+	/// The channel was opened.
+	Completed,
+	/// The order failed, e.g., because it expired.
+	Failed,
+}
+
+/// The state of the payment for an LSPS1 order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lsps1PaymentState {
+	/// The LSP waits for us to pay.
+	ExpectPayment,
+	/// The LSP holds our payment until the channel is opened.
+	Hold,
+	/// The LSP claimed our payment.
+	Paid,
+	/// The LSP refunded our payment.
+	Refunded,
+}
+
+/// A channel order placed with an LSP via LSPS1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lsps1Order {
+	/// The id the LSP assigned to the order.
+	pub order_id: String,
+	/// The parameters of the ordered channel.
+	pub params: Lsps1OrderParams,
+	/// The state of the order.
+	pub order_state: Lsps1OrderState,
+	/// The state of the payment for the order.
+	pub payment_state: Lsps1PaymentState,
+	/// The fee charged by the LSP, in satoshis.
+	pub fee_total_sat: u64,
+	/// The total amount to pay, i.e., the fee plus our balance in the channel, in satoshis.
+	pub order_total_sat: u64,
+	/// The invoice via which the order is to be paid.
+	pub payment_invoice: Bolt11Invoice,
+	/// The time the order expires at, as an ISO 8601 timestamp.
+	pub expires_at: String,
+}
+
+/// The fees an LSP charges for opening a JIT channel via LSPS2.
+///
+/// The parameters are signed by the LSP and have to be handed back unmodified when buying a
+/// channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpeningFeeParams {
+	/// The minimum fee charged, in thousandths of a satoshi.
+	pub min_fee_msat: u64,
+	/// The fee charged per millionth of the payment size.
+	pub proportional: u32,
+	/// The time until which the parameters are valid, as an ISO 8601 timestamp.
+	pub valid_until: String,
+	/// The number of blocks the LSP will keep the channel open for at least.
+	pub min_lifetime: u32,
+	/// The maximum `to_self_delay` the LSP will impose on us.
+	pub max_client_to_self_delay: u32,
+	/// The minimum size of the payment opening the channel, in thousandths of a satoshi.
+	pub min_payment_size_msat: u64,
+	/// The maximum size of the payment opening the channel, in thousandths of a satoshi.
+	pub max_payment_size_msat: u64,
+	/// The LSP's promise to honor the parameters.
+	pub promise: String,
+}
+
+impl OpeningFeeParams {
+	/// Returns the fee the LSP may deduct from a payment of the given size.
+	pub fn opening_fee_msat(&self, payment_size_msat: u64) -> u64 {
+		let proportional_fee_msat =
+			(payment_size_msat as u128 * self.proportional as u128 + 999_999) / 1_000_000;
+		self.min_fee_msat.max(proportional_fee_msat.min(u64::MAX as u128) as u64)
+	}
+
+	// The time until which the parameters are valid, in seconds since the UNIX epoch. Timestamps
+	// we fail to parse are treated as already expired.
+	fn valid_until_secs(&self) -> u64 {
+		DateTime::parse_from_rfc3339(&self.valid_until).map_or(0, |t| t.timestamp().max(0) as u64)
+	}
+
+	fn from_json(value: &Value) -> Result<Self, Error> {
+		Ok(Self {
+			min_fee_msat: json_u64(value, "min_fee_msat")?,
+			proportional: json_u64(value, "proportional")? as u32,
+			valid_until: json_str(value, "valid_until")?,
+			min_lifetime: json_u64(value, "min_lifetime")? as u32,
+			max_client_to_self_delay: json_u64(value, "max_client_to_self_delay")? as u32,
+			min_payment_size_msat: json_u64(value, "min_payment_size_msat")?,
+			max_payment_size_msat: json_u64(value, "max_payment_size_msat")?,
+			promise: json_str(value, "promise")?,
+		})
+	}
+
+	fn to_json(&self) -> Value {
+		json!({
+			"min_fee_msat": self.min_fee_msat.to_string(),
+			"proportional": self.proportional,
+			"valid_until": self.valid_until,
+			"min_lifetime": self.min_lifetime,
+			"max_client_to_self_delay": self.max_client_to_self_delay,
+			"min_payment_size_msat": self.min_payment_size_msat.to_string(),
+			"max_payment_size_msat": self.max_payment_size_msat.to_string(),
+			"promise": self.promise,
+		})
+	}
+}
+
+/// The state of a JIT channel bought via LSPS2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JitChannelState {
+	/// The LSP may still open the channel.
+	Pending,
+	/// The channel was opened by the payment with the given hash.
+	Used {
+		/// The hash of the payment the LSP deducted its opening fee from.
+		payment_hash: PaymentHash,
+	},
+	/// The opening fee parameters expired before the channel was used.
+	Expired,
+}
+
+/// A JIT channel bought from an LSP via LSPS2.
+///
+/// The LSP opens the channel once a payment to an invoice including [`route_hint`] arrives,
+/// deducting its opening fee from the payment. Each JIT channel can only be used by a single
+/// payment, which has to arrive before the opening fee parameters expire.
+///
+/// [`route_hint`]: Self::route_hint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JitChannel {
+	/// The node id of the LSP.
+	pub lsp_node_id: PublicKey,
+	/// The SCID to include in the route hint of the invoice.
+	pub intercept_scid: u64,
+	/// The CLTV expiry delta the LSP requires for forwarding to us.
+	pub lsp_cltv_expiry_delta: u32,
+	/// The fees the LSP charges for opening the channel.
+	pub opening_fee_params: OpeningFeeParams,
+	/// The size of the payment the channel was bought for, if it was fixed when buying it.
+	pub payment_size_msat: Option<u64>,
+	/// The state of the channel.
+	pub state: JitChannelState,
+}
+
+impl JitChannel {
+	/// Returns the route hint to include in invoices to be paid via the channel.
+	///
+	/// See [`InvoiceParams::extra_route_hints`].
+	///
+	/// [`InvoiceParams::extra_route_hints`]: crate::invoice::InvoiceParams::extra_route_hints
+	pub fn route_hint(&self) -> RouteHint {
+		RouteHint(vec![RouteHintHop {
+			src_node_id: self.lsp_node_id,
+			short_channel_id: self.intercept_scid,
+			fees: RoutingFees { base_msat: 0, proportional_millionths: 0 },
+			cltv_expiry_delta: self.lsp_cltv_expiry_delta as u16,
+			htlc_minimum_msat: None,
+			htlc_maximum_msat: None,
+		}])
+	}
+
+	// Whether the LSP may deduct `skimmed_fee_msat` via this channel from the payment with the
+	// given hash and size, paying an invoice with the given route hints.
+	fn allows_skimmed_fee(
+		&self, route_hints: &[RouteHint], payment_hash: &PaymentHash, payment_size_msat: u64,
+		skimmed_fee_msat: u64,
+	) -> bool {
+		let is_usable = match self.state {
+			JitChannelState::Pending => true,
+			// A replayed event for the payment that already used the channel.
+			JitChannelState::Used { payment_hash: used_by } => used_by == *payment_hash,
+			JitChannelState::Expired => false,
+		};
+		let is_routed_via_channel = route_hints.iter().any(|hint| {
+			hint.0.last().map_or(false, |hop| {
+				hop.src_node_id == self.lsp_node_id && hop.short_channel_id == self.intercept_scid
+			})
+		});
+		let params = &self.opening_fee_params;
+		let is_valid_size = match self.payment_size_msat {
+			Some(size_msat) => payment_size_msat == size_msat,
+			None => {
+				params.min_payment_size_msat <= payment_size_msat
+					&& payment_size_msat <= params.max_payment_size_msat
+			}
+		};
+		is_usable
+			&& is_routed_via_channel
+			&& is_valid_size
+			&& skimmed_fee_msat <= params.opening_fee_msat(payment_size_msat)
+	}
+}
+
+impl Readable for JitChannel {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let lsp_node_id: PublicKey = Readable::read(reader)?;
+		let intercept_scid: u64 = Readable::read(reader)?;
+		let lsp_cltv_expiry_delta: u32 = Readable::read(reader)?;
+		let min_fee_msat: u64 = Readable::read(reader)?;
+		let proportional: u32 = Readable::read(reader)?;
+		let valid_until: String = Readable::read(reader)?;
+		let min_lifetime: u32 = Readable::read(reader)?;
+		let max_client_to_self_delay: u32 = Readable::read(reader)?;
+		let min_payment_size_msat: u64 = Readable::read(reader)?;
+		let max_payment_size_msat: u64 = Readable::read(reader)?;
+		let promise: String = Readable::read(reader)?;
+		let payment_size_msat: Option<u64> = Readable::read(reader)?;
+		let state: JitChannelState = Readable::read(reader)?;
+		let opening_fee_params = OpeningFeeParams {
+			min_fee_msat,
+			proportional,
+			valid_until,
+			min_lifetime,
+			max_client_to_self_delay,
+			min_payment_size_msat,
+			max_payment_size_msat,
+			promise,
+		};
+		Ok(Self {
+			lsp_node_id,
+			intercept_scid,
+			lsp_cltv_expiry_delta,
+			opening_fee_params,
+			payment_size_msat,
+			state,
+		})
+	}
+}
+
+impl Writeable for JitChannel {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		self.lsp_node_id.write(writer)?;
+		self.intercept_scid.write(writer)?;
+		self.lsp_cltv_expiry_delta.write(writer)?;
+		self.opening_fee_params.min_fee_msat.write(writer)?;
+		self.opening_fee_params.proportional.write(writer)?;
+		self.opening_fee_params.valid_until.write(writer)?;
+		self.opening_fee_params.min_lifetime.write(writer)?;
+		self.opening_fee_params.max_client_to_self_delay.write(writer)?;
+		self.opening_fee_params.min_payment_size_msat.write(writer)?;
+		self.opening_fee_params.max_payment_size_msat.write(writer)?;
+		self.opening_fee_params.promise.write(writer)?;
+		self.payment_size_msat.write(writer)?;
+		self.state.write(writer)?;
+		Ok(())
+	}
+}
+
+impl Readable for JitChannelState {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		match Readable::read(reader)? {
+			0u8 => Ok(Self::Pending),
+			1u8 => {
+				let payment_hash: PaymentHash = Readable::read(reader)?;
+				Ok(Self::Used { payment_hash })
+			}
+			2u8 => Ok(Self::Expired),
+			_ => Err(lightning::ln::msgs::DecodeError::InvalidValue),
+		}
+	}
+}
+
+impl Writeable for JitChannelState {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		match self {
+			Self::Pending => 0u8.write(writer),
+			Self::Used { payment_hash } => {
+				1u8.write(writer)?;
+				payment_hash.write(writer)
+			}
+			Self::Expired => 2u8.write(writer),
+		}
+	}
+}
+
+// A request sent to an LSP, awaiting its response.
+struct PendingRequest {
+	lsp_node_id: PublicKey,
+	response: Option<Result<Value, Value>>,
+}
+
+/// A client for acquiring inbound liquidity from LSPs via the LSPS protocols.
+///
+/// Requests are sent as LSPS0 JSON-RPC messages over custom peer messages, so we need to be
+/// connected to the LSP. All requests block until the LSP responds or the request times out.
+pub struct LspsClient<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pending_messages: Mutex<Vec<(PublicKey, LspsMessage)>>,
+	pending_requests: Mutex<HashMap<String, PendingRequest>>,
+	response_notifier: Condvar,
+	jit_channels: Mutex<Vec<JitChannel>>,
+	peer_manager: RwLock<Option<Arc<PeerManager>>>,
+	persister: K,
+	logger: L,
+}
+
+impl<K: Deref, L: Deref> LspsClient<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(persister: K, logger: L) -> Self {
+		let pending_messages = Mutex::new(Vec::new());
+		let pending_requests = Mutex::new(HashMap::new());
+		let response_notifier = Condvar::new();
+		let jit_channels = Mutex::new(Vec::new());
+		let peer_manager = RwLock::new(None);
+		Self {
+			pending_messages,
+			pending_requests,
+			response_notifier,
+			jit_channels,
+			peer_manager,
+			persister,
+			logger,
+		}
+	}
+
+	/// Sets the [`PeerManager`] that is woken up to send our requests right away.
+	pub(crate) fn set_peer_manager(&self, peer_manager: Arc<PeerManager>) {
+		*self.peer_manager.write().unwrap() = Some(peer_manager);
+	}
+
+	/// Drops the [`PeerManager`], which holds a reference to us in turn.
+	pub(crate) fn drop_peer_manager(&self) {
+		*self.peer_manager.write().unwrap() = None;
+	}
+
+	/// Returns the protocols the LSP with the given node id supports, via `lsps0.list_protocols`.
+	pub fn list_protocols(&self, lsp_node_id: PublicKey) -> Result<Vec<u16>, Error> {
+		let result = self.send_request(lsp_node_id, "lsps0.list_protocols", json!({}))?;
+		let protocols = result.get("protocols").and_then(|p| p.as_array()).ok_or_else(|| {
+			log_error!(self.logger, "Failed to parse list of supported LSPS protocols.");
+			Error::LspRequestFailed
+		})?;
+		Ok(protocols.iter().filter_map(|p| p.as_u64()).map(|p| p as u16).collect())
+	}
+
+	/// Returns the options the LSP with the given node id supports for buying channels via
+	/// LSPS1.
+	pub fn lsps1_get_info(&self, lsp_node_id: PublicKey) -> Result<Lsps1Options, Error> {
+		let result = self.send_request(lsp_node_id, "lsps1.get_info", json!({}))?;
+		let parse = || -> Result<Lsps1Options, Error> {
+			let options = result.get("options").ok_or(Error::LspRequestFailed)?;
+			Ok(Lsps1Options {
+				min_required_channel_confirmations: json_u64(
+					options,
+					"min_required_channel_confirmations",
+				)? as u16,
+				min_funding_confirms_within_blocks: json_u64(
+					options,
+					"min_funding_confirms_within_blocks",
+				)? as u16,
+				min_initial_client_balance_sat: json_u64(
+					options,
+					"min_initial_client_balance_sat",
+				)?,
+				max_initial_client_balance_sat: json_u64(
+					options,
+					"max_initial_client_balance_sat",
+				)?,
+				min_initial_lsp_balance_sat: json_u64(options, "min_initial_lsp_balance_sat")?,
+				max_initial_lsp_balance_sat: json_u64(options, "max_initial_lsp_balance_sat")?,
+				min_channel_balance_sat: json_u64(options, "min_channel_balance_sat")?,
+				max_channel_balance_sat: json_u64(options, "max_channel_balance_sat")?,
+				max_channel_expiry_blocks: json_u64(options, "max_channel_expiry_blocks")? as u32,
+			})
+		};
+		parse().map_err(|e| {
+			log_error!(self.logger, "Failed to parse LSPS1 options: {}", result);
+			e
+		})
+	}
+
+	/// Orders a channel with the given parameters from the LSP with the given node id via LSPS1.
+	///
+	/// The channel is opened once the [`Lsps1Order::payment_invoice`] is paid. The progress of
+	/// the order can be checked via [`lsps1_get_order`].
+	///
+	/// [`lsps1_get_order`]: Self::lsps1_get_order
+	pub fn lsps1_create_order(
+		&self, lsp_node_id: PublicKey, params: Lsps1OrderParams,
+	) -> Result<Lsps1Order, Error> {
+		let mut request = json!({
+			"lsp_balance_sat": params.lsp_balance_sat.to_string(),
+			"client_balance_sat": params.client_balance_sat.to_string(),
+			"required_channel_confirmations": params.required_channel_confirmations,
+			"funding_confirms_within_blocks": params.funding_confirms_within_blocks,
+			"channel_expiry_blocks": params.channel_expiry_blocks,
+			"announce_channel": params.announce_channel,
+		});
+		if let Some(token) = params.token {
+			request["token"] = json!(token);
+		}
+		let result = self.send_request(lsp_node_id, "lsps1.create_order", request)?;
+		self.parse_order(&result)
+	}
+
+	/// Returns the current state of an order previously placed via [`lsps1_create_order`].
+	///
+	/// [`lsps1_create_order`]: Self::lsps1_create_order
+	pub fn lsps1_get_order(
+		&self, lsp_node_id: PublicKey, order_id: &str,
+	) -> Result<Lsps1Order, Error> {
+		let result =
+			self.send_request(lsp_node_id, "lsps1.get_order", json!({ "order_id": order_id }))?;
+		self.parse_order(&result)
+	}
+
+	/// Returns the fees the LSP with the given node id charges for JIT channels via LSPS2.
+	pub fn lsps2_get_info(
+		&self, lsp_node_id: PublicKey, token: Option<String>,
+	) -> Result<Vec<OpeningFeeParams>, Error> {
+		let mut request = json!({});
+		if let Some(token) = token {
+			request["token"] = json!(token);
+		}
+		let result = self.send_request(lsp_node_id, "lsps2.get_info", request)?;
+		let menu = result
+			.get("opening_fee_params_menu")
+			.and_then(|m| m.as_array())
+			.and_then(|m| m.iter().map(OpeningFeeParams::from_json).collect::<Result<_, _>>().ok());
+		menu.ok_or_else(|| {
+			log_error!(self.logger, "Failed to parse LSPS2 opening fee parameters: {}", result);
+			Error::LspRequestFailed
+		})
+	}
+
+	/// Buys a JIT channel from the LSP with the given node id via LSPS2, at the given fees as
+	/// previously returned by [`lsps2_get_info`].
+	///
+	/// If `payment_size_msat` is `None`, the channel may be opened by a payment of any size in
+	/// the range the fee parameters allow. Include the [`JitChannel::route_hint`] in the invoice
+	/// to be paid.
+	///
+	/// [`lsps2_get_info`]: Self::lsps2_get_info
+	pub fn lsps2_buy(
+		&self, lsp_node_id: PublicKey, opening_fee_params: OpeningFeeParams,
+		payment_size_msat: Option<u64>,
+	) -> Result<JitChannel, Error> {
+		if opening_fee_params.valid_until_secs() <= unix_time_secs() {
+			log_error!(
+				self.logger,
+				"Failed to buy JIT channel: the opening fee parameters expired at {}.",
+				opening_fee_params.valid_until
+			);
+			return Err(Error::LspRequestFailed);
+		}
+
+		let mut request = json!({ "opening_fee_params": opening_fee_params.to_json() });
+		if let Some(payment_size_msat) = payment_size_msat {
+			request["payment_size_msat"] = json!(payment_size_msat.to_string());
+		}
+		let result = self.send_request(lsp_node_id, "lsps2.buy", request)?;
+
+		let intercept_scid =
+			json_str(&result, "jit_channel_scid").ok().and_then(|s| parse_scid(&s));
+		let lsp_cltv_expiry_delta = json_u64(&result, "lsp_cltv_expiry_delta").ok();
+		let (intercept_scid, lsp_cltv_expiry_delta) = match (intercept_scid, lsp_cltv_expiry_delta)
+		{
+			(Some(scid), Some(delta)) => (scid, delta as u32),
+			_ => {
+				log_error!(self.logger, "Failed to parse LSPS2 JIT channel: {}", result);
+				return Err(Error::LspRequestFailed);
+			}
+		};
+
+		let jit_channel = JitChannel {
+			lsp_node_id,
+			intercept_scid,
+			lsp_cltv_expiry_delta,
+			opening_fee_params,
+			payment_size_msat,
+			state: JitChannelState::Pending,
+		};
+		let mut locked_jit_channels = self.jit_channels.lock().unwrap();
+		locked_jit_channels.push(jit_channel.clone());
+		self.persist_jit_channels(&locked_jit_channels)?;
+		log_info!(
+			self.logger,
+			"Bought JIT channel with intercept SCID {} from LSP {}.",
+			intercept_scid,
+			lsp_node_id
+		);
+		Ok(jit_channel)
+	}
+
+	/// Returns the JIT channels bought via [`lsps2_buy`].
+	///
+	/// Used and expired channels are dropped a while after their opening fee parameters expired.
+	///
+	/// [`lsps2_buy`]: Self::lsps2_buy
+	pub fn list_jit_channels(&self) -> Vec<JitChannel> {
+		self.locked_jit_channels().clone()
+	}
+
+	/// Returns whether the given node may still open one of the JIT channels we bought from it,
+	/// i.e., whether we currently trust it to open zero-conf channels to us.
+	pub(crate) fn is_lsp(&self, node_id: &PublicKey) -> bool {
+		self.locked_jit_channels()
+			.iter()
+			.any(|c| c.lsp_node_id == *node_id && c.state == JitChannelState::Pending)
+	}
+
+	/// Returns the intercept SCID of the JIT channel via which the given LSP may deduct
+	/// `skimmed_fee_msat` from the payment with the given hash and size, if any.
+	///
+	/// The channel has to be included in the route hints of the payment's `invoice` and must not
+	/// have been used by another payment or expired. Its fee parameters have to allow for a
+	/// payment of `payment_size_msat`.
+	pub(crate) fn jit_channel_for_skimmed_fee(
+		&self, lsp_node_id: &PublicKey, payment_hash: &PaymentHash, invoice: Option<&str>,
+		payment_size_msat: u64, skimmed_fee_msat: u64,
+	) -> Option<u64> {
+		let route_hints = invoice
+			.and_then(|invoice| Bolt11Invoice::from_str(invoice).ok())
+			.map(|invoice| invoice.route_hints())
+			.unwrap_or_default();
+		self.locked_jit_channels()
+			.iter()
+			.find(|c| {
+				c.lsp_node_id == *lsp_node_id
+					&& c.allows_skimmed_fee(
+						&route_hints,
+						payment_hash,
+						payment_size_msat,
+						skimmed_fee_msat,
+					)
+			})
+			.map(|c| c.intercept_scid)
+	}
+
+	/// Marks the JIT channel with the given intercept SCID as used by the payment with the given
+	/// hash, so that neither its fee parameters nor the LSP are trusted anymore.
+	pub(crate) fn mark_jit_channel_used(
+		&self, intercept_scid: u64, payment_hash: PaymentHash,
+	) -> Result<(), Error> {
+		let mut locked_jit_channels = self.locked_jit_channels();
+		let used_state = JitChannelState::Used { payment_hash };
+		match locked_jit_channels.iter_mut().find(|c| c.intercept_scid == intercept_scid) {
+			Some(jit_channel) if jit_channel.state != used_state => {
+				jit_channel.state = used_state;
+				self.persist_jit_channels(&locked_jit_channels)
+			}
+			_ => Ok(()),
+		}
+	}
+
+	// Locks the JIT channels after bringing their states up to date.
+	fn locked_jit_channels(&self) -> MutexGuard<Vec<JitChannel>> {
+		let mut locked_jit_channels = self.jit_channels.lock().unwrap();
+		if update_jit_channels(&mut locked_jit_channels, unix_time_secs()) {
+			if let Err(e) = self.persist_jit_channels(&locked_jit_channels) {
+				log_error!(self.logger, "Failed to persist JIT channels: {}", e);
+			}
+		}
+		locked_jit_channels
+	}
+
+	fn send_request(
+		&self, lsp_node_id: PublicKey, method: &str, params: Value,
+	) -> Result<Value, Error> {
+		let request_id = hex_utils::to_string(&thread_rng().gen::<[u8; 16]>());
+		let request = json!({
+			"jsonrpc": "2.0",
+			"method": method,
+			"params": params,
+			"id": request_id,
+		});
+
+		self.pending_requests
+			.lock()
+			.unwrap()
+			.insert(request_id.clone(), PendingRequest { lsp_node_id, response: None });
+		self.pending_messages
+			.lock()
+			.unwrap()
+			.push((lsp_node_id, LspsMessage { payload: request.to_string() }));
+
+		// Have the request sent right away rather than on the next timer tick.
+		if let Some(peer_manager) = self.peer_manager.read().unwrap().as_ref() {
+			peer_manager.process_events();
+		}
+
+		let (mut locked_requests, _) = self
+			.response_notifier
+			.wait_timeout_while(
+				self.pending_requests.lock().unwrap(),
+				LSPS_REQUEST_TIMEOUT,
+				|requests| requests.get(&request_id).map_or(false, |r| r.response.is_none()),
+			)
+			.unwrap();
+
+		match locked_requests.remove(&request_id).and_then(|r| r.response) {
+			Some(Ok(result)) => Ok(result),
+			Some(Err(error)) => {
+				log_error!(self.logger, "LSP {} failed {} request: {}", lsp_node_id, method, error);
+				Err(Error::LspRequestFailed)
+			}
+			None => {
+				log_error!(
+					self.logger,
+					"LSP {} didn't respond to {} request.",
+					lsp_node_id,
+					method
+				);
+				Err(Error::LspRequestFailed)
+			}
+		}
+	}
+
+	fn parse_order(&self, result: &Value) -> Result<Lsps1Order, Error> {
+		let parse = || -> Result<Lsps1Order, Error> {
+			let payment = result.get("payment").ok_or(Error::LspRequestFailed)?;
+			let params = Lsps1OrderParams {
+				lsp_balance_sat: json_u64(result, "lsp_balance_sat")?,
+				client_balance_sat: json_u64(result, "client_balance_sat")?,
+				required_channel_confirmations: json_u64(result, "required_channel_confirmations")?
+					as u16,
+				funding_confirms_within_blocks: json_u64(result, "funding_confirms_within_blocks")?
+					as u16,
+				channel_expiry_blocks: json_u64(result, "channel_expiry_blocks")? as u32,
+				token: json_str(result, "token").ok().filter(|t| !t.is_empty()),
+				announce_channel: result
+					.get("announce_channel")
+					.and_then(|a| a.as_bool())
+					.ok_or(Error::LspRequestFailed)?,
+			};
+			let order_state = match json_str(result, "order_state")?.as_str() {
+				"CREATED" => Lsps1OrderState::Created,
+				"COMPLETED" => Lsps1OrderState::Completed,
+				"FAILED" => Lsps1OrderState::Failed,
+				_ => return Err(Error::LspRequestFailed),
+			};
+			let payment_state = match json_str(payment, "state")?.as_str() {
+				"EXPECT_PAYMENT" => Lsps1PaymentState::ExpectPayment,
+				"HOLD" => Lsps1PaymentState::Hold,
+				"PAID" => Lsps1PaymentState::Paid,
+				"REFUNDED" => Lsps1PaymentState::Refunded,
+				_ => return Err(Error::LspRequestFailed),
+			};
+			let payment_invoice = Bolt11Invoice::from_str(&json_str(payment, "bolt11_invoice")?)
+				.map_err(|_| Error::LspRequestFailed)?;
+			Ok(Lsps1Order {
+				order_id: json_str(result, "order_id")?,
+				params,
+				order_state,
+				payment_state,
+				fee_total_sat: json_u64(payment, "fee_total_sat")?,
+				order_total_sat: json_u64(payment, "order_total_sat")?,
+				payment_invoice,
+				expires_at: json_str(result, "expires_at")?,
+			})
+		};
+		parse().map_err(|e| {
+			log_error!(self.logger, "Failed to parse LSPS1 order: {}", result);
+			e
+		})
+	}
+
+	fn persist_jit_channels(&self, locked_jit_channels: &[JitChannel]) -> Result<(), Error> {
+		self.persister
+			.persist(LSPS_JIT_CHANNELS_PERSISTENCE_KEY, &JitChannelsSerWrapper(locked_jit_channels))
+			.map_err(|_| Error::PersistenceFailed)?;
+		Ok(())
+	}
+}
+
+impl<K: Deref, L: Deref> CustomMessageReader for LspsClient<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	type CustomMessage = LspsMessage;
+
+	fn read<R: lightning::io::Read>(
+		&self, message_type: u16, buffer: &mut R,
+	) -> Result<Option<Self::CustomMessage>, DecodeError> {
+		if message_type != LSPS_MESSAGE_TYPE {
+			return Ok(None);
+		}
+
+		let mut data = Vec::new();
+		buffer.read_to_end(&mut data)?;
+		let payload = String::from_utf8(data).map_err(|_| DecodeError::InvalidValue)?;
+		Ok(Some(LspsMessage { payload }))
+	}
+}
+
+impl<K: Deref, L: Deref> CustomMessageHandler for LspsClient<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	fn handle_custom_message(
+		&self, msg: Self::CustomMessage, sender_node_id: &PublicKey,
+	) -> Result<(), LightningError> {
+		let message: Map<String, Value> = match serde_json::from_str(&msg.payload) {
+			Ok(message) => message,
+			Err(e) => {
+				log_warn!(
+					self.logger,
+					"Received malformed LSPS message from {}: {}",
+					sender_node_id,
+					e
+				);
+				return Ok(());
+			}
+		};
+
+		let request_id = match message.get("id").and_then(|id| id.as_str()) {
+			Some(request_id) => request_id,
+			None => {
+				log_warn!(self.logger, "Received LSPS message without id from {}.", sender_node_id);
+				return Ok(());
+			}
+		};
+
+		let response = match (message.get("result"), message.get("error")) {
+			(Some(result), None) => Ok(result.clone()),
+			(None, Some(error)) => Err(error.clone()),
+			_ => {
+				log_warn!(
+					self.logger,
+					"Received unexpected LSPS message from {}, we only act as a client.",
+					sender_node_id
+				);
+				return Ok(());
+			}
+		};
+
+		let mut locked_requests = self.pending_requests.lock().unwrap();
+		match locked_requests.get_mut(request_id) {
+			// Only the LSP we sent the request to may respond to it.
+			Some(pending_request)
+				if pending_request.lsp_node_id == *sender_node_id
+					&& pending_request.response.is_none() =>
+			{
+				pending_request.response = Some(response);
+				self.response_notifier.notify_all();
+			}
+			_ => {
+				log_warn!(
+					self.logger,
+					"Received LSPS response from {} for unknown request {}.",
+					sender_node_id,
+					request_id
+				);
+			}
+		}
+		Ok(())
+	}
+
+	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, Self::CustomMessage)> {
+		std::mem::take(&mut *self.pending_messages.lock().unwrap())
+	}
+
+	fn provided_node_features(&self) -> NodeFeatures {
+		NodeFeatures::empty()
+	}
+
+	fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures {
+		InitFeatures::empty()
+	}
+}
+
+impl<K: Deref, L: Deref> ReadableArgs<(K, L)> for LspsClient<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R, args: (K, L),
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let (persister, logger) = args;
+		let read_jit_channels: JitChannelsDeserWrapper = Readable::read(reader)?;
+		let client = Self::new(persister, logger);
+		*client.jit_channels.lock().unwrap() = read_jit_channels.0;
+		Ok(client)
+	}
+}
+
+struct JitChannelsDeserWrapper(Vec<JitChannel>);
+
+impl Readable for JitChannelsDeserWrapper {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let len: u64 = Readable::read(reader)?;
+		let mut jit_channels = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			jit_channels.push(Readable::read(reader)?);
+		}
+		Ok(Self(jit_channels))
+	}
+}
+
+struct JitChannelsSerWrapper<'a>(&'a [JitChannel]);
+
+impl Writeable for JitChannelsSerWrapper<'_> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		(self.0.len() as u64).write(writer)?;
+		for jit_channel in self.0.iter() {
+			jit_channel.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+// LSPS encodes amounts as strings to avoid precision issues with JSON numbers, but we accept
+// plain numbers as well.
+fn json_u64(value: &Value, key: &str) -> Result<u64, Error> {
+	match value.get(key) {
+		Some(Value::String(s)) => s.parse().map_err(|_| Error::LspRequestFailed),
+		Some(Value::Number(n)) => n.as_u64().ok_or(Error::LspRequestFailed),
+		_ => Err(Error::LspRequestFailed),
+	}
+}
+
+// Marks pending JIT channels whose fee parameters expired by `now` as expired and drops used and
+// expired ones once the retention period is over. Returns whether anything changed.
+fn update_jit_channels(jit_channels: &mut Vec<JitChannel>, now: u64) -> bool {
+	let mut updated = false;
+	for jit_channel in jit_channels.iter_mut() {
+		if jit_channel.state == JitChannelState::Pending
+			&& jit_channel.opening_fee_params.valid_until_secs() <= now
+		{
+			jit_channel.state = JitChannelState::Expired;
+			updated = true;
+		}
+	}
+	let len = jit_channels.len();
+	jit_channels.retain(|c| {
+		c.state == JitChannelState::Pending
+			|| c.opening_fee_params.valid_until_secs() + JIT_CHANNEL_RETENTION_SECS > now
+	});
+	updated || jit_channels.len() != len
+}
+
+fn json_str(value: &Value, key: &str) -> Result<String, Error> {
+	value.get(key).and_then(|v| v.as_str()).map(|s| s.to_string()).ok_or(Error::LspRequestFailed)
+}
+
+// Parses an SCID in the `BLOCKxTXxOUTPUT` format used by LSPS.
+fn parse_scid(scid: &str) -> Option<u64> {
+	let mut parts = scid.split('x').map(|p| p.parse::<u64>().ok());
+	let (block, tx, output) = (parts.next()??, parts.next()??, parts.next()??);
+	if parts.next().is_some() || block >= 1 << 24 || tx >= 1 << 24 || output >= 1 << 16 {
+		return None;
+	}
+	Some(block << 40 | tx << 16 | output)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::test_utils::{TestLogger, TestPersister};
+
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	#[test]
+	fn scid_and_opening_fee_parsing() {
+		assert_eq!(parse_scid("529x1x0"), Some(529 << 40 | 1 << 16));
+		assert_eq!(parse_scid("529x1"), None);
+		assert_eq!(parse_scid("529x1x0x0"), None);
+		assert_eq!(parse_scid("16777216x1x0"), None);
+
+		let params = OpeningFeeParams::from_json(&json!({
+			"min_fee_msat": "546000",
+			"proportional": 1200,
+			"valid_until": "2023-02-23T08:47:30.511Z",
+			"min_lifetime": 1008,
+			"max_client_to_self_delay": 2016,
+			"min_payment_size_msat": "1000",
+			"max_payment_size_msat": 1000000000,
+			"promise": "abcdefghijklmnopqrstuvwxyz",
+		}))
+		.unwrap();
+		assert_eq!(params.max_payment_size_msat, 1_000_000_000);
+		assert_eq!(params.opening_fee_msat(100_000_000), 546_000);
+		assert_eq!(params.opening_fee_msat(1_000_000_000), 1_200_000);
+		assert_eq!(params.opening_fee_msat(1_000_001), 546_000);
+		assert_eq!(OpeningFeeParams::from_json(&params.to_json()).unwrap(), params);
+	}
+
+	fn test_jit_channel(
+		lsp_node_id: PublicKey, intercept_scid: u64, payment_size_msat: Option<u64>,
+	) -> JitChannel {
+		JitChannel {
+			lsp_node_id,
+			intercept_scid,
+			lsp_cltv_expiry_delta: 144,
+			opening_fee_params: OpeningFeeParams {
+				min_fee_msat: 10_000,
+				proportional: 1_000,
+				valid_until: "2100-01-01T00:00:00Z".to_string(),
+				min_lifetime: 1008,
+				max_client_to_self_delay: 2016,
+				min_payment_size_msat: 100_000,
+				max_payment_size_msat: 100_000_000,
+				promise: "promise".to_string(),
+			},
+			payment_size_msat,
+			state: JitChannelState::Pending,
+		}
+	}
+
+	#[test]
+	fn skimmed_fee_is_matched_against_the_jit_channel() {
+		let secp_ctx = Secp256k1::new();
+		let lsp_node_id =
+			PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42u8; 32]).unwrap());
+		let payment_hash = PaymentHash([1; 32]);
+		let jit_channel = test_jit_channel(lsp_node_id, 1, None);
+		let route_hints = vec![jit_channel.route_hint()];
+
+		// The fee for 20_000_000 msats is 20_000 msats.
+		assert!(jit_channel.allows_skimmed_fee(&route_hints, &payment_hash, 20_000_000, 20_000));
+		assert!(!jit_channel.allows_skimmed_fee(&route_hints, &payment_hash, 20_000_000, 20_001));
+		// The payment size has to be within the range allowed by the fee parameters.
+		assert!(!jit_channel.allows_skimmed_fee(&route_hints, &payment_hash, 99_999, 10_000));
+		assert!(!jit_channel.allows_skimmed_fee(&route_hints, &payment_hash, 100_000_001, 10_000));
+		// The invoice has to be routed via the channel's intercept SCID.
+		let other_route_hints = vec![test_jit_channel(lsp_node_id, 2, None).route_hint()];
+		assert!(!jit_channel.allows_skimmed_fee(&other_route_hints, &payment_hash, 20_000_000, 0));
+		assert!(!jit_channel.allows_skimmed_fee(&[], &payment_hash, 20_000_000, 0));
+
+		// A fixed payment size has to be matched exactly.
+		let fixed_jit_channel = test_jit_channel(lsp_node_id, 1, Some(20_000_000));
+		assert!(fixed_jit_channel.allows_skimmed_fee(&route_hints, &payment_hash, 20_000_000, 0));
+		assert!(!fixed_jit_channel.allows_skimmed_fee(&route_hints, &payment_hash, 19_999_999, 0));
+
+		// Used channels only match the payment that used them, expired ones nothing.
+		let mut used_jit_channel = jit_channel.clone();
+		used_jit_channel.state = JitChannelState::Used { payment_hash };
+		assert!(used_jit_channel.allows_skimmed_fee(&route_hints, &payment_hash, 20_000_000, 0));
+		let other_payment_hash = PaymentHash([2; 32]);
+		assert!(!used_jit_channel.allows_skimmed_fee(
+			&route_hints,
+			&other_payment_hash,
+			20_000_000,
+			0
+		));
+		let mut expired_jit_channel = jit_channel;
+		expired_jit_channel.state = JitChannelState::Expired;
+		assert!(!expired_jit_channel.allows_skimmed_fee(
+			&route_hints,
+			&payment_hash,
+			20_000_000,
+			0
+		));
+	}
+
+	#[test]
+	fn jit_channels_expire_and_are_pruned() {
+		let secp_ctx = Secp256k1::new();
+		let lsp_node_id =
+			PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42u8; 32]).unwrap());
+		let client = LspsClient::new(Arc::new(TestPersister::new()), Arc::new(TestLogger));
+		let mut expiring_jit_channel = test_jit_channel(lsp_node_id, 1, None);
+		expiring_jit_channel.opening_fee_params.valid_until = "2000-01-01T00:00:00Z".to_string();
+		let valid_until = expiring_jit_channel.opening_fee_params.valid_until_secs();
+		let jit_channel = test_jit_channel(lsp_node_id, 2, None);
+		*client.jit_channels.lock().unwrap() = vec![expiring_jit_channel, jit_channel.clone()];
+
+		// The LSP is only trusted while it may still open a JIT channel.
+		assert!(client.is_lsp(&lsp_node_id));
+		assert_eq!(client.list_jit_channels(), vec![jit_channel]);
+		client.mark_jit_channel_used(2, PaymentHash([1; 32])).unwrap();
+		assert!(!client.is_lsp(&lsp_node_id));
+		assert_eq!(
+			client.list_jit_channels()[0].state,
+			JitChannelState::Used { payment_hash: PaymentHash([1; 32]) }
+		);
+
+		let mut jit_channels = vec![test_jit_channel(lsp_node_id, 1, None)];
+		jit_channels[0].opening_fee_params.valid_until = "2000-01-01T00:00:00Z".to_string();
+		assert!(!update_jit_channels(&mut jit_channels, valid_until - 1));
+		assert!(update_jit_channels(&mut jit_channels, valid_until));
+		assert_eq!(jit_channels[0].state, JitChannelState::Expired);
+		assert!(!update_jit_channels(
+			&mut jit_channels,
+			valid_until + JIT_CHANNEL_RETENTION_SECS - 1
+		));
+		assert!(update_jit_channels(&mut jit_channels, valid_until + JIT_CHANNEL_RETENTION_SECS));
+		assert!(jit_channels.is_empty());
+	}
+
+	#[test]
+	fn responses_are_only_accepted_from_the_requested_lsp() {
+		let client = LspsClient::new(Arc::new(TestPersister::new()), Arc::new(TestLogger));
+		let secp_ctx = Secp256k1::new();
+		let lsp_node_id =
+			PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42u8; 32]).unwrap());
+		let other_node_id =
+			PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[43u8; 32]).unwrap());
+		client
+			.pending_requests
+			.lock()
+			.unwrap()
+			.insert("abc".to_string(), PendingRequest { lsp_node_id, response: None });
+
+		let response = LspsMessage {
+			payload: json!({ "jsonrpc": "2.0", "id": "abc", "result": { "protocols": [1, 2] } })
+				.to_string(),
+		};
+		client.handle_custom_message(response.clone(), &other_node_id).unwrap();
+		assert!(client.pending_requests.lock().unwrap()["abc"].response.is_none());
+
+		client.handle_custom_message(response, &lsp_node_id).unwrap();
+		assert_eq!(
+			client.pending_requests.lock().unwrap()["abc"].response,
+			Some(Ok(json!({ "protocols": [1, 2] })))
+		);
+	}
+}
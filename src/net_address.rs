@@ -0,0 +1,201 @@
+use crate::Error;
+
+use lightning::ln::msgs::NetAddress;
+use lightning::util::ser::Hostname;
+
+use sha3::{Digest, Sha3_256};
+
+use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// The length of a Tor v3 onion service address, without the `.onion` suffix.
+const ONION_V3_ADDRESS_LEN: usize = 56;
+
+/// Formats the given address as `host:port`.
+pub(crate) fn format_address(address: &NetAddress) -> String {
+	match address {
+		NetAddress::IPv4 { addr, port } => format!("{}:{}", Ipv4Addr::from(*addr), port),
+		NetAddress::IPv6 { addr, port } => format!("[{}]:{}", Ipv6Addr::from(*addr), port),
+		NetAddress::OnionV2(data) => {
+			let port = u16::from_be_bytes([data[10], data[11]]);
+			format!("{}.onion:{}", to_base32(&data[..10]), port)
+		}
+		NetAddress::OnionV3 { port, .. } => format!("{}:{}", host(address), port),
+		NetAddress::Hostname { hostname, port } => format!("{}:{}", hostname.as_str(), port),
+	}
+}
+
+/// Parses an address in the `host:port` format, where the host may be an IP address, a Tor v3
+/// onion service address, or a hostname.
+///
+/// Hostnames are not resolved, so the resolution may happen via a proxy when connecting.
+pub(crate) fn parse_address(address: &str) -> Result<NetAddress, Error> {
+	if let Ok(socket_addr) = address.parse::<SocketAddr>() {
+		return Ok(match socket_addr {
+			SocketAddr::V4(addr) => {
+				NetAddress::IPv4 { addr: addr.ip().octets(), port: addr.port() }
+			}
+			SocketAddr::V6(addr) => {
+				NetAddress::IPv6 { addr: addr.ip().octets(), port: addr.port() }
+			}
+		});
+	}
+
+	let (host, port) =
+		address.rsplit_once(':').ok_or(Error::PeerInfoParse("Expected host:port"))?;
+	let port = port.parse::<u16>().map_err(|_| Error::PeerInfoParse("Invalid port"))?;
+
+	if let Some(onion_address) = host.strip_suffix(".onion") {
+		let data = match from_base32(onion_address) {
+			Some(data) if onion_address.len() == ONION_V3_ADDRESS_LEN => data,
+			_ => return Err(Error::PeerInfoParse("Only Tor v3 onion addresses are supported")),
+		};
+		let mut ed25519_pubkey = [0u8; 32];
+		ed25519_pubkey.copy_from_slice(&data[..32]);
+		let checksum = u16::from_be_bytes([data[32], data[33]]);
+		let version = data[34];
+		if version != 3 {
+			return Err(Error::PeerInfoParse("Only Tor v3 onion addresses are supported"));
+		}
+		if checksum != onion_v3_checksum(&ed25519_pubkey, version) {
+			return Err(Error::PeerInfoParse("Invalid onion address checksum"));
+		}
+		return Ok(NetAddress::OnionV3 { ed25519_pubkey, checksum, version, port });
+	}
+
+	let hostname =
+		Hostname::try_from(host.to_string()).map_err(|_| Error::PeerInfoParse("Invalid host"))?;
+	Ok(NetAddress::Hostname { hostname, port })
+}
+
+/// Returns the host part of the given address, as used in SOCKS5 requests.
+pub(crate) fn host(address: &NetAddress) -> String {
+	match address {
+		NetAddress::IPv4 { addr, .. } => Ipv4Addr::from(*addr).to_string(),
+		NetAddress::IPv6 { addr, .. } => Ipv6Addr::from(*addr).to_string(),
+		NetAddress::OnionV2(data) => format!("{}.onion", to_base32(&data[..10])),
+		NetAddress::OnionV3 { ed25519_pubkey, checksum, version, .. } => {
+			let mut data = ed25519_pubkey.to_vec();
+			data.extend_from_slice(&checksum.to_be_bytes());
+			data.push(*version);
+			format!("{}.onion", to_base32(&data))
+		}
+		NetAddress::Hostname { hostname, .. } => hostname.as_str().to_string(),
+	}
+}
+
+/// Returns the port of the given address.
+pub(crate) fn port(address: &NetAddress) -> u16 {
+	match address {
+		NetAddress::IPv4 { port, .. }
+		| NetAddress::IPv6 { port, .. }
+		| NetAddress::OnionV3 { port, .. }
+		| NetAddress::Hostname { port, .. } => *port,
+		NetAddress::OnionV2(data) => u16::from_be_bytes([data[10], data[11]]),
+	}
+}
+
+// Computes the checksum of a Tor v3 onion address, i.e., the first two bytes of
+// SHA3-256(".onion checksum" || pubkey || version), as specified in Tor's rend-spec-v3.
+fn onion_v3_checksum(ed25519_pubkey: &[u8; 32], version: u8) -> u16 {
+	let mut hasher = Sha3_256::new();
+	hasher.update(b".onion checksum");
+	hasher.update(ed25519_pubkey);
+	hasher.update([version]);
+	let hash = hasher.finalize();
+	u16::from_be_bytes([hash[0], hash[1]])
+}
+
+// Encodes the given data as lowercase RFC 4648 base32 without padding, as used for onion
+// addresses.
+fn to_base32(data: &[u8]) -> String {
+	let mut res = String::with_capacity((data.len() * 8 + 4) / 5);
+	let mut buffer = 0u16;
+	let mut bits = 0;
+	for byte in data {
+		buffer = (buffer << 8) | *byte as u16;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			res.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		res.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+	}
+	res
+}
+
+// Decodes RFC 4648 base32 without padding, ignoring case. Trailing bits that don't make up a
+// full byte are dropped.
+fn from_base32(data: &str) -> Option<Vec<u8>> {
+	let mut res = Vec::with_capacity(data.len() * 5 / 8);
+	let mut buffer = 0u16;
+	let mut bits = 0;
+	for c in data.bytes() {
+		let value = BASE32_ALPHABET.iter().position(|a| *a == c.to_ascii_lowercase())? as u16;
+		buffer = (buffer << 5) | value;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			res.push((buffer >> bits) as u8);
+		}
+	}
+	Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn address_formatting_and_parsing() {
+		assert_eq!(to_base32(b"foobar"), "mzxw6ytboi");
+		assert_eq!(from_base32("MZXW6YTBOI"), Some(b"foobar".to_vec()));
+		assert_eq!(from_base32("mzxw6ytbo1"), None);
+
+		let address = NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 };
+		assert_eq!(format_address(&address), "127.0.0.1:9735");
+		assert_eq!(parse_address("127.0.0.1:9735").unwrap(), address);
+
+		let address = parse_address("[::1]:9735").unwrap();
+		assert_eq!(format_address(&address), "[::1]:9735");
+
+		let onion_address = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:9735";
+		let address = parse_address(onion_address).unwrap();
+		match address {
+			NetAddress::OnionV3 { ed25519_pubkey, checksum, version, port } => {
+				assert_eq!(ed25519_pubkey[..4], [209, 179, 139, 131]);
+				assert_eq!(checksum, 0xddd9);
+				assert_eq!(checksum, onion_v3_checksum(&ed25519_pubkey, version));
+				assert_eq!(version, 3);
+				assert_eq!(port, 9735);
+			}
+			_ => panic!("Expected an onion v3 address"),
+		}
+		assert_eq!(format_address(&address), onion_address);
+		assert_eq!(port(&address), 9735);
+		assert!(parse_address("aaaaaaaaaaaaaaaa.onion:9735").is_err());
+		// Other versions and mismatching checksums are rejected.
+		assert!(parse_address(&onion_address.replace("wid.onion", "wie.onion")).is_err());
+		assert!(parse_address(&onion_address.replacen('2', "3", 1)).is_err());
+		assert!(parse_address(
+			"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaad.onion:9735"
+		)
+		.is_err());
+
+		// The port of Tor v2 addresses is encoded big-endian, like everywhere else in the protocol.
+		let mut data = [0u8; 12];
+		data[10..].copy_from_slice(&9735u16.to_be_bytes());
+		let address = NetAddress::OnionV2(data);
+		assert_eq!(format_address(&address), "aaaaaaaaaaaaaaaa.onion:9735");
+		assert_eq!(port(&address), 9735);
+
+		let address = parse_address("example.com:9735").unwrap();
+		assert_eq!(host(&address), "example.com");
+		assert!(parse_address("example.com").is_err());
+		assert!(parse_address("example.com:port").is_err());
+	}
+}
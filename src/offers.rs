@@ -0,0 +1,418 @@
+use crate::hex_utils;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::payment_store::{
+	unix_time_secs, PaymentDirection, PaymentInfo, PaymentStatus, PaymentStore,
+};
+use crate::{ChannelManager, Error, KeysManager};
+
+use lightning::chain::keysinterface::EntropySource;
+use lightning::ln::channelmanager::{PaymentId, Retry};
+use lightning::ln::PaymentHash;
+use lightning::offers::invoice::Bolt12Invoice;
+use lightning::offers::offer::{Amount, Offer};
+use lightning::offers::refund::Refund;
+use lightning::onion_message::{OffersMessage, OffersMessageHandler, PendingOnionMessage};
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use bitcoin::secp256k1::PublicKey;
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The offers we created will be persisted under this key.
+pub(crate) const OFFERS_PERSISTENCE_KEY: &str = "offers";
+
+/// The default time we keep retrying to pay for an offer or refund before we give up.
+pub const DEFAULT_OFFER_PAYMENT_RETRY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates and pays BOLT12 offers and refunds.
+///
+/// Invoice requests for our own offers and the invoices we receive in response to our requests are
+/// exchanged via onion messages and handled by the [`ChannelManager`]. To record the payments for
+/// our offers, this handler is to be given to the onion messenger in its place, passing the
+/// messages on to it.
+pub struct OfferHandler<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	channel_manager: Arc<ChannelManager>,
+	keys_manager: Arc<KeysManager>,
+	payment_store: Arc<PaymentStore<K>>,
+	// Our offers, by their signing pubkey, which is unique per offer.
+	offers: Mutex<HashMap<PublicKey, Offer>>,
+	retry_timeout: Duration,
+	persister: K,
+	logger: L,
+}
+
+impl<K: Deref, L: Deref> OfferHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	/// Creates a new handler, which keeps retrying to send each payment for `retry_timeout`, e.g.,
+	/// [`DEFAULT_OFFER_PAYMENT_RETRY_TIMEOUT`].
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, keys_manager: Arc<KeysManager>,
+		payment_store: Arc<PaymentStore<K>>, retry_timeout: Duration, persister: K, logger: L,
+	) -> Self {
+		let offers = Mutex::new(HashMap::new());
+		Self {
+			channel_manager,
+			keys_manager,
+			payment_store,
+			offers,
+			retry_timeout,
+			persister,
+			logger,
+		}
+	}
+
+	/// Creates a reusable offer with the given `description`.
+	///
+	/// If `amount_msat` is `None`, the payer may choose the amount. If `expiry_secs` is set, the
+	/// offer will expire that many seconds from now.
+	pub fn create_offer(
+		&self, description: String, amount_msat: Option<u64>, expiry_secs: Option<u32>,
+	) -> Result<Offer, Error> {
+		let mut offer_builder =
+			self.channel_manager.create_offer_builder(description).map_err(|e| {
+				log_error!(self.logger, "Failed to create offer builder: {:?}", e);
+				Error::OfferCreationFailed
+			})?;
+
+		if let Some(amount_msat) = amount_msat {
+			offer_builder = offer_builder.amount_msats(amount_msat);
+		}
+
+		if let Some(expiry_secs) = expiry_secs {
+			let absolute_expiry = unix_time_secs() + expiry_secs as u64;
+			offer_builder = offer_builder.absolute_expiry(Duration::from_secs(absolute_expiry));
+		}
+
+		let offer = offer_builder.build().map_err(|e| {
+			log_error!(self.logger, "Failed to create offer: {:?}", e);
+			Error::OfferCreationFailed
+		})?;
+
+		let mut locked_offers = self.offers.lock().unwrap();
+		locked_offers.retain(|_, o| !o.is_expired());
+		locked_offers.insert(offer.signing_pubkey(), offer.clone());
+		self.persist_offers(&locked_offers)?;
+
+		log_info!(self.logger, "Created offer {}", offer);
+		Ok(offer)
+	}
+
+	/// Pays the given `offer` by requesting an invoice from its issuer and paying it.
+	///
+	/// An `amount_msat` must be given if the offer doesn't specify an amount and may be given to
+	/// pay more than requested. The optional `payer_note` is included in the invoice request.
+	///
+	/// As the payment hash is only known once we received the invoice, the payment is tracked by
+	/// the returned [`PaymentId`].
+	pub fn pay_offer(
+		&self, offer: &Offer, amount_msat: Option<u64>, payer_note: Option<String>,
+	) -> Result<PaymentId, Error> {
+		let payment_amount_msat =
+			offer_payment_amount_msat(offer.amount(), amount_msat).map_err(|e| {
+				log_error!(self.logger, "Failed to pay offer {}: invalid amount.", offer);
+				e
+			})?;
+
+		let payment_id = PaymentId(self.keys_manager.get_secure_random_bytes());
+		let mut payment = PaymentInfo::new(
+			payment_id,
+			None,
+			PaymentDirection::Outbound,
+			Some(payment_amount_msat),
+			PaymentStatus::InFlight,
+		);
+		payment.description = Some(offer.description().to_string());
+		payment.offer = Some(offer.to_string());
+		payment.payer_note = payer_note.clone();
+		self.payment_store.insert(payment)?;
+
+		match self.channel_manager.pay_for_offer(
+			offer,
+			None,
+			amount_msat,
+			payer_note,
+			payment_id,
+			Retry::Timeout(self.retry_timeout),
+			None,
+		) {
+			Ok(()) => {
+				log_info!(
+					self.logger,
+					"Initiated paying {} msats for offer {} with payment id {}.",
+					payment_amount_msat,
+					offer,
+					hex_utils::to_string(&payment_id.0),
+				);
+				Ok(payment_id)
+			}
+			Err(e) => {
+				log_error!(self.logger, "Failed to pay offer: {:?}", e);
+				self.payment_store.update(&payment_id, |p| p.set_status(PaymentStatus::Failed))?;
+				Err(Error::PaymentSendingFailed)
+			}
+		}
+	}
+
+	/// Creates a refund of `amount_msat` for the previously received payment with the given hash.
+	///
+	/// The returned [`Refund`] is to be handed to the payer of the original payment, who can then
+	/// request the refund payment via [`request_refund_payment`]. Once they do, the refund will be
+	/// paid automatically and an [`Event::RefundSuccessful`] or [`Event::RefundFailed`] will be
+	/// emitted. The refund expires if it wasn't requested within `expiry_secs`.
+	///
+	/// The total amount refunded for a payment may not exceed the amount originally received.
+	///
+	/// [`request_refund_payment`]: Self::request_refund_payment
+	/// [`Event::RefundSuccessful`]: crate::event::Event::RefundSuccessful
+	/// [`Event::RefundFailed`]: crate::event::Event::RefundFailed
+	pub fn initiate_refund(
+		&self, refunded_payment_hash: PaymentHash, amount_msat: u64, expiry_secs: u32,
+	) -> Result<Refund, Error> {
+		let payment_id = PaymentId(self.keys_manager.get_secure_random_bytes());
+		let description =
+			format!("Refund for payment {}", hex_utils::to_string(&refunded_payment_hash.0));
+		let expires_at = unix_time_secs() + expiry_secs as u64;
+
+		// Record the refund first, so concurrent refunds can't exceed the refunded amount.
+		let mut payment = PaymentInfo::new(
+			payment_id,
+			None,
+			PaymentDirection::Outbound,
+			Some(amount_msat),
+			PaymentStatus::Created,
+		);
+		payment.description = Some(description.clone());
+		payment.refund_for = Some(refunded_payment_hash);
+		payment.expires_at = Some(expires_at);
+		self.payment_store.insert_refund(payment)?;
+
+		let refund = match self
+			.channel_manager
+			.create_refund_builder(
+				description,
+				amount_msat,
+				Duration::from_secs(expires_at),
+				payment_id,
+				Retry::Timeout(self.retry_timeout),
+				None,
+			)
+			.and_then(|builder| builder.build())
+		{
+			Ok(refund) => refund,
+			Err(e) => {
+				log_error!(self.logger, "Failed to create refund: {:?}", e);
+				self.payment_store.update(&payment_id, |p| p.set_status(PaymentStatus::Failed))?;
+				return Err(Error::RefundCreationFailed);
+			}
+		};
+
+		log_info!(
+			self.logger,
+			"Created refund of {} msats for payment {}.",
+			amount_msat,
+			hex_utils::to_string(&refunded_payment_hash.0),
+		);
+		Ok(refund)
+	}
+
+	/// Requests the payment of the given [`Refund`] by sending an invoice to its issuer.
+	///
+	/// The payment will be reported as [`Event::PaymentReceived`] once it arrived.
+	///
+	/// [`Event::PaymentReceived`]: crate::event::Event::PaymentReceived
+	pub fn request_refund_payment(&self, refund: &Refund) -> Result<PaymentHash, Error> {
+		let invoice = self.channel_manager.request_refund_payment(refund).map_err(|e| {
+			log_error!(self.logger, "Failed to request refund payment: {:?}", e);
+			Error::RefundRequestFailed
+		})?;
+
+		let payment_hash = invoice.payment_hash();
+		let mut payment = PaymentInfo::new(
+			PaymentId(payment_hash.0),
+			Some(payment_hash),
+			PaymentDirection::Inbound,
+			Some(invoice.amount_msats()),
+			PaymentStatus::Created,
+		);
+		payment.description = Some(refund.description().to_string());
+		self.payment_store.insert(payment)?;
+
+		Ok(payment_hash)
+	}
+
+	// Records the inbound payment for the invoice we responded to an invoice request with, along
+	// with the details of the offer it was requested for.
+	fn record_offer_payment(
+		&self, signing_pubkey: PublicKey, payer_note: Option<String>, invoice: &Bolt12Invoice,
+	) -> Result<(), Error> {
+		let payment_hash = invoice.payment_hash();
+		let mut payment = PaymentInfo::new(
+			PaymentId(payment_hash.0),
+			Some(payment_hash),
+			PaymentDirection::Inbound,
+			Some(invoice.amount_msats()),
+			PaymentStatus::Created,
+		);
+		let offer = self.offers.lock().unwrap().get(&signing_pubkey).cloned();
+		payment.description = offer.as_ref().map(|o| o.description().to_string());
+		payment.offer = offer.map(|o| o.to_string());
+		payment.payer_note = payer_note;
+		payment.expires_at = Some(unix_time_secs() + invoice.relative_expiry().as_secs());
+		self.payment_store.insert(payment)
+	}
+
+	fn persist_offers(&self, locked_offers: &HashMap<PublicKey, Offer>) -> Result<(), Error> {
+		self.persister
+			.persist(OFFERS_PERSISTENCE_KEY, &OffersSerWrapper(locked_offers))
+			.map_err(|_| Error::PersistenceFailed)?;
+		Ok(())
+	}
+}
+
+impl<K: Deref, L: Deref> OffersMessageHandler for OfferHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	fn handle_message(&self, message: OffersMessage) -> Option<OffersMessage> {
+		let (signing_pubkey, payer_note) = match message {
+			OffersMessage::InvoiceRequest(ref invoice_request) => (
+				invoice_request.signing_pubkey(),
+				invoice_request.payer_note().map(|n| n.to_string()),
+			),
+			_ => return self.channel_manager.handle_message(message),
+		};
+
+		let response = self.channel_manager.handle_message(message);
+		if let Some(OffersMessage::Invoice(ref invoice)) = response {
+			if let Err(e) = self.record_offer_payment(signing_pubkey, payer_note, invoice) {
+				log_error!(self.logger, "Failed to record offer payment: {}", e);
+			}
+		}
+		response
+	}
+
+	fn release_pending_messages(&self) -> Vec<PendingOnionMessage<OffersMessage>> {
+		self.channel_manager.release_pending_messages()
+	}
+}
+
+impl<K: Deref, L: Deref>
+	ReadableArgs<(Arc<ChannelManager>, Arc<KeysManager>, Arc<PaymentStore<K>>, Duration, K, L)>
+	for OfferHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+		args: (Arc<ChannelManager>, Arc<KeysManager>, Arc<PaymentStore<K>>, Duration, K, L),
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let (channel_manager, keys_manager, payment_store, retry_timeout, persister, logger) = args;
+		let read_offers: OffersDeserWrapper = Readable::read(reader)?;
+		let offers = Mutex::new(read_offers.0);
+		Ok(Self {
+			channel_manager,
+			keys_manager,
+			payment_store,
+			offers,
+			retry_timeout,
+			persister,
+			logger,
+		})
+	}
+}
+
+struct OffersDeserWrapper(HashMap<PublicKey, Offer>);
+
+impl Readable for OffersDeserWrapper {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let len: u64 = Readable::read(reader)?;
+		let mut offers = HashMap::with_capacity(len as usize);
+		for _ in 0..len {
+			let encoded_offer: String = Readable::read(reader)?;
+			let offer = Offer::from_str(&encoded_offer)
+				.map_err(|_| lightning::ln::msgs::DecodeError::InvalidValue)?;
+			offers.insert(offer.signing_pubkey(), offer);
+		}
+		Ok(Self(offers))
+	}
+}
+
+struct OffersSerWrapper<'a>(&'a HashMap<PublicKey, Offer>);
+
+impl Writeable for OffersSerWrapper<'_> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		(self.0.len() as u64).write(writer)?;
+		for offer in self.0.values() {
+			offer.to_string().write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+// Returns the amount to pay for an offer requesting `offer_amount`, given the amount the payer
+// chose, if any. Payers may pay more than requested, but never less.
+fn offer_payment_amount_msat(
+	offer_amount: Option<&Amount>, amount_msat: Option<u64>,
+) -> Result<u64, Error> {
+	let offer_amount_msat = match offer_amount {
+		Some(Amount::Bitcoin { amount_msats }) => Some(*amount_msats),
+		Some(Amount::Currency { .. }) => return Err(Error::InvalidAmount),
+		None => None,
+	};
+
+	match (amount_msat, offer_amount_msat) {
+		(Some(amount_msat), Some(offer_amount_msat)) if amount_msat < offer_amount_msat => {
+			Err(Error::InvalidAmount)
+		}
+		(Some(amount_msat), _) => Ok(amount_msat),
+		(None, Some(offer_amount_msat)) => Ok(offer_amount_msat),
+		(None, None) => Err(Error::InvalidAmount),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn offer_payment_amounts_are_checked() {
+		let offer_amount = Amount::Bitcoin { amount_msats: 1_000 };
+
+		// The offer's amount is paid unless the payer chooses to pay more.
+		assert_eq!(offer_payment_amount_msat(Some(&offer_amount), None).unwrap(), 1_000);
+		assert_eq!(offer_payment_amount_msat(Some(&offer_amount), Some(1_000)).unwrap(), 1_000);
+		assert_eq!(offer_payment_amount_msat(Some(&offer_amount), Some(1_500)).unwrap(), 1_500);
+		assert!(matches!(
+			offer_payment_amount_msat(Some(&offer_amount), Some(999)),
+			Err(Error::InvalidAmount)
+		));
+
+		// Offers without an amount require the payer to choose one.
+		assert_eq!(offer_payment_amount_msat(None, Some(500)).unwrap(), 500);
+		assert!(matches!(offer_payment_amount_msat(None, None), Err(Error::InvalidAmount)));
+
+		// Currency amounts can't be converted, so such offers can't be paid.
+		let currency_amount = Amount::Currency { iso4217_code: *b"USD", amount: 10 };
+		assert!(matches!(
+			offer_payment_amount_msat(Some(&currency_amount), Some(1_000_000)),
+			Err(Error::InvalidAmount)
+		));
+	}
+}
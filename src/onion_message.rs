@@ -0,0 +1,233 @@
+use crate::event::{Event, EventQueue};
+use crate::logger::{log_error, log_given_level, log_info, log_internal, FilesystemLogger, Logger};
+use crate::offers::OfferHandler;
+use crate::{Error, KeysManager};
+
+use lightning::ln::msgs::DecodeError;
+use lightning::onion_message::{
+	CustomOnionMessageHandler as LdkCustomOnionMessageHandler, DefaultMessageRouter, Destination,
+	OnionMessageContents, PendingOnionMessage,
+};
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Writeable, Writer};
+
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// TLV types below this value are reserved for the onion message protocol itself.
+const MIN_CUSTOM_ONION_MESSAGE_TYPE: u64 = 64;
+
+/// Relays onion messages for our peers and hands those addressed to us to the [`OfferHandler`] or
+/// the [`CustomOnionMessageHandler`].
+pub(crate) type OnionMessenger<K> = lightning::onion_message::OnionMessenger<
+	Arc<KeysManager>,
+	Arc<KeysManager>,
+	Arc<FilesystemLogger>,
+	Arc<DefaultMessageRouter>,
+	Arc<OfferHandler<K, Arc<FilesystemLogger>>>,
+	Arc<CustomOnionMessageHandler<K, Arc<FilesystemLogger>>>,
+>;
+
+/// Builds the [`OnionMessenger`], which is to be given to the peer manager as its onion message
+/// handler, so that it exchanges our pending messages with our peers.
+pub(crate) fn build_onion_messenger<K: Deref>(
+	keys_manager: Arc<KeysManager>, offer_handler: Arc<OfferHandler<K, Arc<FilesystemLogger>>>,
+	custom_message_handler: Arc<CustomOnionMessageHandler<K, Arc<FilesystemLogger>>>,
+	logger: Arc<FilesystemLogger>,
+) -> OnionMessenger<K>
+where
+	K::Target: KVStorePersister,
+{
+	lightning::onion_message::OnionMessenger::new(
+		Arc::clone(&keys_manager),
+		keys_manager,
+		logger,
+		Arc::new(DefaultMessageRouter::new()),
+		offer_handler,
+		custom_message_handler,
+	)
+}
+
+/// A custom onion message of a given TLV type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomOnionMessage {
+	/// The TLV type of the message.
+	pub tlv_type: u64,
+	/// The raw message data.
+	pub data: Vec<u8>,
+}
+
+impl OnionMessageContents for CustomOnionMessage {
+	fn tlv_type(&self) -> u64 {
+		self.tlv_type
+	}
+}
+
+impl Writeable for CustomOnionMessage {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		writer.write_all(&self.data)
+	}
+}
+
+/// Sends custom onion messages and delivers received custom onion messages of registered TLV
+/// types as [`Event::OnionMessageReceived`].
+pub struct CustomOnionMessageHandler<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	registered_types: RwLock<HashSet<u64>>,
+	pending_messages: Mutex<Vec<PendingOnionMessage<CustomOnionMessage>>>,
+	event_queue: Arc<EventQueue<K>>,
+	logger: L,
+}
+
+impl<K: Deref, L: Deref> CustomOnionMessageHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(event_queue: Arc<EventQueue<K>>, logger: L) -> Self {
+		let registered_types = RwLock::new(HashSet::new());
+		let pending_messages = Mutex::new(Vec::new());
+		Self { registered_types, pending_messages, event_queue, logger }
+	}
+
+	/// Registers interest in custom onion messages of the given TLV type.
+	///
+	/// Received messages of registered types will be delivered as
+	/// [`Event::OnionMessageReceived`], all others are ignored.
+	pub fn register_message_type(&self, tlv_type: u64) -> Result<(), Error> {
+		if tlv_type < MIN_CUSTOM_ONION_MESSAGE_TYPE {
+			return Err(Error::InvalidOnionMessageType);
+		}
+		self.registered_types.write().unwrap().insert(tlv_type);
+		Ok(())
+	}
+
+	/// Stops delivering custom onion messages of the given TLV type.
+	pub fn unregister_message_type(&self, tlv_type: u64) {
+		self.registered_types.write().unwrap().remove(&tlv_type);
+	}
+
+	/// Sends a custom onion message with the given TLV type and data to the given destination,
+	/// which may either be a node or a blinded path.
+	///
+	/// The message will be sent the next time the peer handler processes pending events.
+	pub fn send_message(
+		&self, destination: Destination, tlv_type: u64, data: Vec<u8>,
+	) -> Result<(), Error> {
+		if tlv_type < MIN_CUSTOM_ONION_MESSAGE_TYPE {
+			return Err(Error::InvalidOnionMessageType);
+		}
+
+		let contents = CustomOnionMessage { tlv_type, data };
+		self.pending_messages.lock().unwrap().push(PendingOnionMessage {
+			contents,
+			destination,
+			reply_path: None,
+		});
+		Ok(())
+	}
+}
+
+impl<K: Deref, L: Deref> LdkCustomOnionMessageHandler for CustomOnionMessageHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	type CustomMessage = CustomOnionMessage;
+
+	fn handle_custom_message(&self, msg: Self::CustomMessage) -> Option<Self::CustomMessage> {
+		log_info!(
+			self.logger,
+			"Received custom onion message of type {} ({} bytes).",
+			msg.tlv_type,
+			msg.data.len(),
+		);
+		let event = Event::OnionMessageReceived { tlv_type: msg.tlv_type, data: msg.data };
+		if let Err(e) = self.event_queue.add_event(event) {
+			log_error!(self.logger, "Failed to push to event queue: {}", e);
+		}
+		None
+	}
+
+	fn read_custom_message<R: lightning::io::Read>(
+		&self, message_type: u64, buffer: &mut R,
+	) -> Result<Option<Self::CustomMessage>, DecodeError> {
+		if !self.registered_types.read().unwrap().contains(&message_type) {
+			return Ok(None);
+		}
+
+		let mut data = Vec::new();
+		buffer.read_to_end(&mut data)?;
+		Ok(Some(CustomOnionMessage { tlv_type: message_type, data }))
+	}
+
+	fn release_pending_custom_messages(&self) -> Vec<PendingOnionMessage<Self::CustomMessage>> {
+		std::mem::take(&mut *self.pending_messages.lock().unwrap())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::test_utils::{TestLogger, TestPersister};
+
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+	#[test]
+	fn only_registered_message_types_are_delivered() {
+		let test_persister = Arc::new(TestPersister::new());
+		let event_queue = Arc::new(EventQueue::new(Arc::clone(&test_persister)));
+		let handler =
+			CustomOnionMessageHandler::new(Arc::clone(&event_queue), Arc::new(TestLogger));
+
+		assert!(matches!(handler.register_message_type(63), Err(Error::InvalidOnionMessageType)));
+		handler.register_message_type(64).unwrap();
+		handler.register_message_type(1 << 16).unwrap();
+
+		let data = vec![1u8, 2, 3];
+		assert_eq!(handler.read_custom_message(65, &mut &data[..]).unwrap(), None);
+		let message = handler.read_custom_message(1 << 16, &mut &data[..]).unwrap().unwrap();
+		assert_eq!(message, CustomOnionMessage { tlv_type: 1 << 16, data: data.clone() });
+
+		assert_eq!(handler.handle_custom_message(message), None);
+		assert!(test_persister.get_and_clear_pending_persist());
+		assert_eq!(
+			event_queue.next_event(),
+			Event::OnionMessageReceived { tlv_type: 1 << 16, data: data.clone() }
+		);
+
+		handler.unregister_message_type(1 << 16);
+		assert_eq!(handler.read_custom_message(1 << 16, &mut &data[..]).unwrap(), None);
+		assert!(handler.read_custom_message(64, &mut &data[..]).unwrap().is_some());
+	}
+
+	#[test]
+	fn only_custom_message_types_are_sent() {
+		let test_persister = Arc::new(TestPersister::new());
+		let event_queue = Arc::new(EventQueue::new(Arc::clone(&test_persister)));
+		let handler = CustomOnionMessageHandler::new(event_queue, Arc::new(TestLogger));
+
+		let secp_ctx = Secp256k1::new();
+		let node_id =
+			PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42u8; 32]).unwrap());
+
+		assert!(matches!(
+			handler.send_message(Destination::Node(node_id), 63, vec![1]),
+			Err(Error::InvalidOnionMessageType)
+		));
+		assert!(handler.release_pending_custom_messages().is_empty());
+
+		handler.send_message(Destination::Node(node_id), 64, vec![1]).unwrap();
+		let pending_messages = handler.release_pending_custom_messages();
+		assert_eq!(pending_messages.len(), 1);
+		assert_eq!(
+			pending_messages[0].contents,
+			CustomOnionMessage { tlv_type: 64, data: vec![1] }
+		);
+		assert!(handler.release_pending_custom_messages().is_empty());
+	}
+}
@@ -0,0 +1,888 @@
+use crate::Error;
+
+use lightning::ln::channelmanager::PaymentId;
+use lightning::ln::{PaymentHash, PaymentPreimage, PaymentSecret};
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The payment store will be persisted under this key.
+pub(crate) const PAYMENTS_PERSISTENCE_KEY: &str = "payments";
+
+/// The direction of a payment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentDirection {
+	/// The payment is inbound.
+	Inbound,
+	/// The payment is outbound.
+	Outbound,
+}
+
+/// The status of a payment.
+///
+/// `Succeeded`, `Failed`, and `Abandoned` are final states a payment can't transition out of. An
+/// `Expired` payment may still succeed if its funds arrive late, or be abandoned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentStatus {
+	/// The payment was created, e.g., an invoice was issued, but no HTLCs were sent or received
+	/// yet.
+	Created,
+	/// HTLCs for the payment are in flight.
+	InFlight,
+	/// Some paths of the payment failed and are being retried.
+	Retrying,
+	/// The payment succeeded.
+	Succeeded,
+	/// The payment failed.
+	Failed,
+	/// The payment wasn't completed before its expiry.
+	Expired,
+	/// The payment was abandoned by the user via [`PaymentStore::abandon_payment`].
+	Abandoned,
+}
+
+impl PaymentStatus {
+	/// Returns whether this is a final status.
+	pub fn is_final(&self) -> bool {
+		match self {
+			Self::Created | Self::InFlight | Self::Retrying | Self::Expired => false,
+			Self::Succeeded | Self::Failed | Self::Abandoned => true,
+		}
+	}
+
+	/// Returns whether a payment may transition from this status to `next`.
+	pub fn can_transition_to(&self, next: PaymentStatus) -> bool {
+		match self {
+			Self::Created => next != Self::Created,
+			Self::InFlight => !matches!(next, Self::Created | Self::InFlight),
+			Self::Retrying => next != Self::Created,
+			Self::Expired => {
+				matches!(next, Self::InFlight | Self::Succeeded | Self::Abandoned)
+			}
+			Self::Succeeded | Self::Failed | Self::Abandoned => false,
+		}
+	}
+}
+
+/// A status a payment transitioned to at a given time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentStatusUpdate {
+	/// The status the payment transitioned to.
+	pub status: PaymentStatus,
+	/// The time of the transition, in seconds since the UNIX epoch.
+	pub timestamp: u64,
+}
+
+/// Information about a payment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentInfo {
+	/// The identifier of the payment.
+	///
+	/// For payments other than outbound BOLT12 payments this is always the payment hash.
+	pub payment_id: PaymentId,
+	/// The hash of the payment, if already known.
+	///
+	/// The hash of an outbound BOLT12 payment only becomes known once we received the invoice.
+	pub payment_hash: Option<PaymentHash>,
+	/// The pre-image of the payment, if known.
+	pub preimage: Option<PaymentPreimage>,
+	/// The secret of the payment, if known.
+	pub secret: Option<PaymentSecret>,
+	/// The amount of the payment, in thousandths of a satoshi, if known.
+	pub amount_msat: Option<u64>,
+	/// The fees paid for an outbound payment, in thousandths of a satoshi, if known.
+	pub fee_msat: Option<u64>,
+	/// The direction of the payment.
+	pub direction: PaymentDirection,
+	/// The current status of the payment.
+	pub status: PaymentStatus,
+	/// The description of the payment, if any.
+	pub description: Option<String>,
+	/// The BOLT11 invoice of the payment, if any.
+	pub invoice: Option<String>,
+	/// The custom TLV records sent or received along with a spontaneous payment.
+	pub custom_tlvs: Vec<(u64, Vec<u8>)>,
+	/// The BOLT12 offer this payment pays for, if any.
+	pub offer: Option<String>,
+	/// The note the payer included when requesting an invoice for a BOLT12 offer, if any.
+	pub payer_note: Option<String>,
+	/// The hash of the inbound payment this outbound payment refunds, if any.
+	pub refund_for: Option<PaymentHash>,
+	/// Whether this is an inbound payment for a hold invoice, which is only claimed once the
+	/// preimage is explicitly supplied.
+	pub hold_invoice: bool,
+	/// The block height at which a claimable hold invoice payment will be failed back by LDK, if
+	/// known.
+	pub claim_deadline: Option<u32>,
+	/// Whether this is a circular payment to ourselves, moving liquidity between our channels.
+	pub rebalance: bool,
+	/// The time the payment was created, in seconds since the UNIX epoch.
+	pub created_at: u64,
+	/// The time the payment was last updated, in seconds since the UNIX epoch.
+	pub updated_at: u64,
+	/// The time after which a payment that wasn't started yet expires, in seconds since the UNIX
+	/// epoch.
+	pub expires_at: Option<u64>,
+	/// The history of the payment's status transitions, oldest first.
+	pub status_history: Vec<PaymentStatusUpdate>,
+}
+
+impl PaymentInfo {
+	pub(crate) fn new(
+		payment_id: PaymentId, payment_hash: Option<PaymentHash>, direction: PaymentDirection,
+		amount_msat: Option<u64>, status: PaymentStatus,
+	) -> Self {
+		let now = unix_time_secs();
+		Self {
+			payment_id,
+			payment_hash,
+			preimage: None,
+			secret: None,
+			amount_msat,
+			fee_msat: None,
+			direction,
+			status,
+			description: None,
+			invoice: None,
+			custom_tlvs: Vec::new(),
+			offer: None,
+			payer_note: None,
+			refund_for: None,
+			hold_invoice: false,
+			claim_deadline: None,
+			rebalance: false,
+			created_at: now,
+			updated_at: now,
+			expires_at: None,
+			status_history: vec![PaymentStatusUpdate { status, timestamp: now }],
+		}
+	}
+
+	/// Sets the status of the payment and records the transition in the status history.
+	///
+	/// Returns `false` and leaves the payment untouched if the transition isn't valid. Setting the
+	/// current status again is a no-op.
+	pub(crate) fn set_status(&mut self, status: PaymentStatus) -> bool {
+		if status == self.status {
+			return true;
+		}
+
+		if !self.status.can_transition_to(status) {
+			return false;
+		}
+
+		let now = unix_time_secs();
+		self.status = status;
+		self.updated_at = now;
+		self.status_history.push(PaymentStatusUpdate { status, timestamp: now });
+		true
+	}
+}
+
+/// A query selecting payments from the [`PaymentStore`].
+///
+/// Matching payments are returned newest first. The default query returns all payments.
+#[derive(Debug, Clone, Default)]
+pub struct PaymentQuery {
+	/// Only select payments of the given direction.
+	pub direction: Option<PaymentDirection>,
+	/// Only select payments with the given status.
+	pub status: Option<PaymentStatus>,
+	/// Only select rebalances if `true`, or only other payments if `false`.
+	pub rebalance: Option<bool>,
+	/// Only select payments created at or after the given time, in seconds since the UNIX epoch.
+	pub created_after: Option<u64>,
+	/// Only select payments created before the given time, in seconds since the UNIX epoch.
+	pub created_before: Option<u64>,
+	/// The number of matching payments to skip.
+	pub offset: usize,
+	/// The maximum number of payments to return.
+	pub limit: Option<usize>,
+}
+
+impl PaymentQuery {
+	fn matches(&self, payment: &PaymentInfo) -> bool {
+		self.direction.map_or(true, |d| d == payment.direction)
+			&& self.status.map_or(true, |s| s == payment.status)
+			&& self.rebalance.map_or(true, |r| r == payment.rebalance)
+			&& self.created_after.map_or(true, |t| payment.created_at >= t)
+			&& self.created_before.map_or(true, |t| payment.created_at < t)
+	}
+}
+
+/// A persisted store of all inbound and outbound payments.
+pub struct PaymentStore<K: Deref>
+where
+	K::Target: KVStorePersister,
+{
+	payments: Mutex<HashMap<PaymentId, PaymentInfo>>,
+	persister: K,
+}
+
+impl<K: Deref> PaymentStore<K>
+where
+	K::Target: KVStorePersister,
+{
+	pub(crate) fn new(persister: K) -> Self {
+		let payments = Mutex::new(HashMap::new());
+		Self { payments, persister }
+	}
+
+	pub(crate) fn insert(&self, payment: PaymentInfo) -> Result<(), Error> {
+		let mut locked_payments = self.payments.lock().unwrap();
+		locked_payments.insert(payment.payment_id, payment);
+		self.persist_payments(&locked_payments)
+	}
+
+	/// Inserts the given outbound `refund` payment, if the inbound payment it refunds succeeded and
+	/// its amount covers the refund on top of all refunds for it that didn't fail yet.
+	///
+	/// The check and insertion happen atomically, so concurrent refunds can't exceed the refunded
+	/// payment's amount.
+	pub(crate) fn insert_refund(&self, refund: PaymentInfo) -> Result<(), Error> {
+		let refunded_payment_hash = refund.refund_for.ok_or(Error::PaymentNotFound)?;
+		let mut locked_payments = self.payments.lock().unwrap();
+		let refunded_amount_msat = locked_payments
+			.get(&PaymentId(refunded_payment_hash.0))
+			.filter(|p| {
+				p.direction == PaymentDirection::Inbound && p.status == PaymentStatus::Succeeded
+			})
+			.ok_or(Error::PaymentNotFound)?
+			.amount_msat
+			.unwrap_or(0);
+
+		let already_refunded_msat: u64 = locked_payments
+			.values()
+			.filter(|p| {
+				p.direction == PaymentDirection::Outbound
+					&& p.refund_for == Some(refunded_payment_hash)
+			})
+			.filter(|p| {
+				!matches!(
+					p.status,
+					PaymentStatus::Failed | PaymentStatus::Expired | PaymentStatus::Abandoned
+				)
+			})
+			.filter_map(|p| p.amount_msat)
+			.sum();
+		let amount_msat = refund.amount_msat.unwrap_or(0);
+		if amount_msat == 0 || already_refunded_msat + amount_msat > refunded_amount_msat {
+			return Err(Error::InvalidAmount);
+		}
+
+		locked_payments.insert(refund.payment_id, refund);
+		self.persist_payments(&locked_payments)
+	}
+
+	/// Applies `update_fn` to the payment with the given id, if it exists.
+	///
+	/// Returns the result of `update_fn`, or `None` if the payment is unknown.
+	pub(crate) fn update<R, F: FnOnce(&mut PaymentInfo) -> R>(
+		&self, payment_id: &PaymentId, update_fn: F,
+	) -> Result<Option<R>, Error> {
+		let mut locked_payments = self.payments.lock().unwrap();
+		if let Some(payment) = locked_payments.get_mut(payment_id) {
+			let res = update_fn(payment);
+			self.persist_payments(&locked_payments)?;
+			Ok(Some(res))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Marks all payments that weren't started before their expiry as [`PaymentStatus::Expired`].
+	///
+	/// This is called regularly by the [`InvoiceHandler`]. Returns the ids of the payments that
+	/// expired.
+	///
+	/// [`InvoiceHandler`]: crate::invoice::InvoiceHandler
+	pub(crate) fn expire_stale_payments(&self) -> Result<Vec<PaymentId>, Error> {
+		let now = unix_time_secs();
+		let mut locked_payments = self.payments.lock().unwrap();
+		let mut expired = Vec::new();
+		for payment in locked_payments.values_mut() {
+			// Held payments that already arrived are failed back by their claim deadline instead.
+			let is_claimable = payment.hold_invoice && payment.claim_deadline.is_some();
+			if payment.status != PaymentStatus::Created || is_claimable {
+				continue;
+			}
+
+			if payment.expires_at.map_or(false, |expires_at| expires_at <= now)
+				&& payment.set_status(PaymentStatus::Expired)
+			{
+				expired.push(payment.payment_id);
+			}
+		}
+
+		if !expired.is_empty() {
+			self.persist_payments(&locked_payments)?;
+		}
+		Ok(expired)
+	}
+
+	/// Abandons the payment with the given id, e.g., an invoice that is no longer to be paid.
+	///
+	/// Only payments that weren't started yet, or that expired, can be abandoned. Funds arriving
+	/// for an abandoned payment are rejected.
+	pub fn abandon_payment(&self, payment_id: &PaymentId) -> Result<(), Error> {
+		let mut locked_payments = self.payments.lock().unwrap();
+		let payment = locked_payments.get_mut(payment_id).ok_or(Error::PaymentNotFound)?;
+		if !matches!(payment.status, PaymentStatus::Created | PaymentStatus::Expired) {
+			return Err(Error::PaymentNotAbandonable);
+		}
+		payment.set_status(PaymentStatus::Abandoned);
+		self.persist_payments(&locked_payments)
+	}
+
+	/// Returns whether a payment with the given id is known.
+	pub fn contains(&self, payment_id: &PaymentId) -> bool {
+		self.payments.lock().unwrap().contains_key(payment_id)
+	}
+
+	/// Returns the payment with the given id, if it is known.
+	pub fn get(&self, payment_id: &PaymentId) -> Option<PaymentInfo> {
+		self.payments.lock().unwrap().get(payment_id).cloned()
+	}
+
+	/// Returns the payment with the given hash, if it is known.
+	pub fn get_by_hash(&self, payment_hash: &PaymentHash) -> Option<PaymentInfo> {
+		let locked_payments = self.payments.lock().unwrap();
+		locked_payments.values().find(|p| p.payment_hash.as_ref() == Some(payment_hash)).cloned()
+	}
+
+	/// Returns all payments matching the given query, newest first.
+	///
+	/// Payments created at the same time are ordered by descending id, so pages are stable.
+	pub fn list_payments(&self, query: &PaymentQuery) -> Vec<PaymentInfo> {
+		let locked_payments = self.payments.lock().unwrap();
+		let mut payments =
+			locked_payments.values().filter(|p| query.matches(p)).collect::<Vec<_>>();
+		payments.sort_by(|a, b| {
+			b.created_at.cmp(&a.created_at).then_with(|| b.payment_id.0.cmp(&a.payment_id.0))
+		});
+		payments
+			.into_iter()
+			.skip(query.offset)
+			.take(query.limit.unwrap_or(usize::MAX))
+			.cloned()
+			.collect()
+	}
+
+	fn persist_payments(
+		&self, locked_payments: &HashMap<PaymentId, PaymentInfo>,
+	) -> Result<(), Error> {
+		self.persister
+			.persist(PAYMENTS_PERSISTENCE_KEY, &PaymentsSerWrapper(locked_payments))
+			.map_err(|_| Error::PersistenceFailed)?;
+		Ok(())
+	}
+}
+
+impl<K: Deref> ReadableArgs<K> for PaymentStore<K>
+where
+	K::Target: KVStorePersister,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R, persister: K,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let read_payments: PaymentsDeserWrapper = Readable::read(reader)?;
+		let payments = Mutex::new(read_payments.0);
+		Ok(Self { payments, persister })
+	}
+}
+
+pub(crate) fn unix_time_secs() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl Readable for PaymentDirection {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		match Readable::read(reader)? {
+			0u8 => Ok(Self::Inbound),
+			1u8 => Ok(Self::Outbound),
+			_ => Err(lightning::ln::msgs::DecodeError::InvalidValue),
+		}
+	}
+}
+
+impl Writeable for PaymentDirection {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		match self {
+			Self::Inbound => 0u8.write(writer),
+			Self::Outbound => 1u8.write(writer),
+		}
+	}
+}
+
+impl Readable for PaymentStatus {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		match Readable::read(reader)? {
+			0u8 => Ok(Self::Created),
+			1u8 => Ok(Self::InFlight),
+			2u8 => Ok(Self::Retrying),
+			3u8 => Ok(Self::Succeeded),
+			4u8 => Ok(Self::Failed),
+			5u8 => Ok(Self::Expired),
+			6u8 => Ok(Self::Abandoned),
+			_ => Err(lightning::ln::msgs::DecodeError::InvalidValue),
+		}
+	}
+}
+
+impl Writeable for PaymentStatus {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		match self {
+			Self::Created => 0u8.write(writer),
+			Self::InFlight => 1u8.write(writer),
+			Self::Retrying => 2u8.write(writer),
+			Self::Succeeded => 3u8.write(writer),
+			Self::Failed => 4u8.write(writer),
+			Self::Expired => 5u8.write(writer),
+			Self::Abandoned => 6u8.write(writer),
+		}
+	}
+}
+
+impl Readable for PaymentInfo {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let payment_id: PaymentId = Readable::read(reader)?;
+		let payment_hash: Option<PaymentHash> = Readable::read(reader)?;
+		let preimage: Option<PaymentPreimage> = Readable::read(reader)?;
+		let secret: Option<PaymentSecret> = Readable::read(reader)?;
+		let amount_msat: Option<u64> = Readable::read(reader)?;
+		let fee_msat: Option<u64> = Readable::read(reader)?;
+		let direction: PaymentDirection = Readable::read(reader)?;
+		let status: PaymentStatus = Readable::read(reader)?;
+		let description: Option<String> = Readable::read(reader)?;
+		let invoice: Option<String> = Readable::read(reader)?;
+
+		let tlvs_len: u16 = Readable::read(reader)?;
+		let mut custom_tlvs = Vec::with_capacity(tlvs_len as usize);
+		for _ in 0..tlvs_len {
+			let tlv_type: u64 = Readable::read(reader)?;
+			let tlv_value: Vec<u8> = Readable::read(reader)?;
+			custom_tlvs.push((tlv_type, tlv_value));
+		}
+
+		let offer: Option<String> = Readable::read(reader)?;
+		let payer_note: Option<String> = Readable::read(reader)?;
+		let refund_for: Option<PaymentHash> = Readable::read(reader)?;
+		let hold_invoice: bool = Readable::read(reader)?;
+		let claim_deadline: Option<u32> = Readable::read(reader)?;
+		let rebalance: bool = Readable::read(reader)?;
+		let created_at: u64 = Readable::read(reader)?;
+		let updated_at: u64 = Readable::read(reader)?;
+		let expires_at: Option<u64> = Readable::read(reader)?;
+
+		let history_len: u16 = Readable::read(reader)?;
+		let mut status_history = Vec::with_capacity(history_len as usize);
+		for _ in 0..history_len {
+			let status: PaymentStatus = Readable::read(reader)?;
+			let timestamp: u64 = Readable::read(reader)?;
+			status_history.push(PaymentStatusUpdate { status, timestamp });
+		}
+
+		Ok(Self {
+			payment_id,
+			payment_hash,
+			preimage,
+			secret,
+			amount_msat,
+			fee_msat,
+			direction,
+			status,
+			description,
+			invoice,
+			custom_tlvs,
+			offer,
+			payer_note,
+			refund_for,
+			hold_invoice,
+			claim_deadline,
+			rebalance,
+			created_at,
+			updated_at,
+			expires_at,
+			status_history,
+		})
+	}
+}
+
+impl Writeable for PaymentInfo {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		self.payment_id.write(writer)?;
+		self.payment_hash.write(writer)?;
+		self.preimage.write(writer)?;
+		self.secret.write(writer)?;
+		self.amount_msat.write(writer)?;
+		self.fee_msat.write(writer)?;
+		self.direction.write(writer)?;
+		self.status.write(writer)?;
+		self.description.write(writer)?;
+		self.invoice.write(writer)?;
+
+		(self.custom_tlvs.len() as u16).write(writer)?;
+		for (tlv_type, tlv_value) in self.custom_tlvs.iter() {
+			tlv_type.write(writer)?;
+			tlv_value.write(writer)?;
+		}
+
+		self.offer.write(writer)?;
+		self.payer_note.write(writer)?;
+		self.refund_for.write(writer)?;
+		self.hold_invoice.write(writer)?;
+		self.claim_deadline.write(writer)?;
+		self.rebalance.write(writer)?;
+		self.created_at.write(writer)?;
+		self.updated_at.write(writer)?;
+		self.expires_at.write(writer)?;
+
+		(self.status_history.len() as u16).write(writer)?;
+		for update in self.status_history.iter() {
+			update.status.write(writer)?;
+			update.timestamp.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+struct PaymentsDeserWrapper(HashMap<PaymentId, PaymentInfo>);
+
+impl Readable for PaymentsDeserWrapper {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let len: u64 = Readable::read(reader)?;
+		let mut payments = HashMap::with_capacity(len as usize);
+		for _ in 0..len {
+			let payment: PaymentInfo = Readable::read(reader)?;
+			payments.insert(payment.payment_id, payment);
+		}
+		Ok(Self(payments))
+	}
+}
+
+struct PaymentsSerWrapper<'a>(&'a HashMap<PaymentId, PaymentInfo>);
+
+impl Writeable for PaymentsSerWrapper<'_> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		(self.0.len() as u64).write(writer)?;
+		for payment in self.0.values() {
+			payment.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::test_utils::TestPersister;
+	use std::sync::Arc;
+
+	#[test]
+	fn payment_store_persistence() {
+		let test_persister = Arc::new(TestPersister::new());
+		let payment_store = PaymentStore::new(Arc::clone(&test_persister));
+
+		let payment_hash = PaymentHash([42u8; 32]);
+		let payment_id = PaymentId(payment_hash.0);
+		assert!(!payment_store.contains(&payment_id));
+
+		let payment = PaymentInfo::new(
+			payment_id,
+			Some(payment_hash),
+			PaymentDirection::Inbound,
+			Some(1000),
+			PaymentStatus::Created,
+		);
+		payment_store.insert(payment).unwrap();
+		assert!(test_persister.get_and_clear_pending_persist());
+		assert!(payment_store.contains(&payment_id));
+
+		assert_eq!(
+			payment_store.update(&payment_id, |p| p.set_status(PaymentStatus::Succeeded)).unwrap(),
+			Some(true)
+		);
+		assert!(test_persister.get_and_clear_pending_persist());
+
+		let stored_payment = payment_store.get(&payment_id).unwrap();
+		assert_eq!(payment_store.get_by_hash(&payment_hash), Some(stored_payment.clone()));
+		assert_eq!(stored_payment.status, PaymentStatus::Succeeded);
+		assert_eq!(stored_payment.status_history.len(), 2);
+
+		// Check the payment survives a serialization round trip.
+		let mut encoded = Vec::new();
+		PaymentsSerWrapper(&payment_store.payments.lock().unwrap()).write(&mut encoded).unwrap();
+		let decoded: PaymentsDeserWrapper = Readable::read(&mut &encoded[..]).unwrap();
+		assert_eq!(decoded.0.get(&payment_id), Some(&stored_payment));
+
+		// Updating an unknown payment doesn't persist.
+		assert_eq!(
+			payment_store
+				.update(&PaymentId([23u8; 32]), |p| p.set_status(PaymentStatus::Failed))
+				.unwrap(),
+			None
+		);
+		assert_eq!(false, test_persister.get_and_clear_pending_persist());
+	}
+
+	#[test]
+	fn payment_store_queries() {
+		let test_persister = Arc::new(TestPersister::new());
+		let payment_store = PaymentStore::new(Arc::clone(&test_persister));
+
+		for i in 0..10u8 {
+			let direction =
+				if i % 2 == 0 { PaymentDirection::Inbound } else { PaymentDirection::Outbound };
+			let mut payment =
+				PaymentInfo::new(PaymentId([i; 32]), None, direction, None, PaymentStatus::Created);
+			payment.created_at = i as u64;
+			payment_store.insert(payment).unwrap();
+		}
+
+		let all = payment_store.list_payments(&PaymentQuery::default());
+		assert_eq!(all.len(), 10);
+		assert_eq!(all[0].payment_id, PaymentId([9u8; 32]));
+
+		let query = PaymentQuery {
+			direction: Some(PaymentDirection::Inbound),
+			offset: 1,
+			limit: Some(2),
+			..Default::default()
+		};
+		let page = payment_store.list_payments(&query);
+		assert_eq!(
+			page.iter().map(|p| p.payment_id).collect::<Vec<_>>(),
+			vec![PaymentId([6u8; 32]), PaymentId([4u8; 32])]
+		);
+
+		let query =
+			PaymentQuery { created_after: Some(3), created_before: Some(5), ..Default::default() };
+		assert_eq!(payment_store.list_payments(&query).len(), 2);
+
+		// Payments created at the same time are ordered by id, so paging through them neither
+		// skips nor repeats any.
+		for i in 10..20u8 {
+			let mut payment = PaymentInfo::new(
+				PaymentId([i; 32]),
+				None,
+				PaymentDirection::Inbound,
+				None,
+				PaymentStatus::Created,
+			);
+			payment.created_at = 100;
+			payment_store.insert(payment).unwrap();
+		}
+		let mut paged_ids = Vec::new();
+		for offset in (0..10).step_by(3) {
+			let query = PaymentQuery {
+				created_after: Some(100),
+				offset,
+				limit: Some(3),
+				..Default::default()
+			};
+			paged_ids.extend(payment_store.list_payments(&query).into_iter().map(|p| p.payment_id));
+		}
+		assert_eq!(paged_ids, (10..20u8).rev().map(|i| PaymentId([i; 32])).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn payment_status_transitions() {
+		let mut payment = PaymentInfo::new(
+			PaymentId([42u8; 32]),
+			None,
+			PaymentDirection::Outbound,
+			Some(1000),
+			PaymentStatus::Created,
+		);
+
+		assert!(payment.set_status(PaymentStatus::InFlight));
+		assert!(payment.set_status(PaymentStatus::Retrying));
+		assert!(payment.set_status(PaymentStatus::InFlight));
+		assert!(!payment.set_status(PaymentStatus::Created));
+		assert!(payment.set_status(PaymentStatus::Failed));
+
+		// Duplicate events for final states are no-ops, while leaving a final state is rejected.
+		assert!(payment.set_status(PaymentStatus::Failed));
+		assert!(!payment.set_status(PaymentStatus::Succeeded));
+		assert_eq!(payment.status, PaymentStatus::Failed);
+		assert_eq!(payment.status_history.len(), 5);
+	}
+
+	#[test]
+	fn stale_payments_expire() {
+		let test_persister = Arc::new(TestPersister::new());
+		let payment_store = PaymentStore::new(Arc::clone(&test_persister));
+
+		let mut stale = PaymentInfo::new(
+			PaymentId([1u8; 32]),
+			None,
+			PaymentDirection::Inbound,
+			None,
+			PaymentStatus::Created,
+		);
+		stale.expires_at = Some(unix_time_secs() - 1);
+		payment_store.insert(stale).unwrap();
+
+		let mut fresh = PaymentInfo::new(
+			PaymentId([2u8; 32]),
+			None,
+			PaymentDirection::Inbound,
+			None,
+			PaymentStatus::Created,
+		);
+		fresh.expires_at = Some(unix_time_secs() + 3600);
+		payment_store.insert(fresh).unwrap();
+
+		let mut held = PaymentInfo::new(
+			PaymentId([3u8; 32]),
+			None,
+			PaymentDirection::Inbound,
+			None,
+			PaymentStatus::Created,
+		);
+		held.expires_at = Some(unix_time_secs() - 1);
+		held.hold_invoice = true;
+		held.claim_deadline = Some(800_000);
+		payment_store.insert(held).unwrap();
+		test_persister.get_and_clear_pending_persist();
+
+		assert_eq!(payment_store.expire_stale_payments().unwrap(), vec![PaymentId([1u8; 32])]);
+		assert!(test_persister.get_and_clear_pending_persist());
+		assert_eq!(
+			payment_store.get(&PaymentId([1u8; 32])).unwrap().status,
+			PaymentStatus::Expired
+		);
+		assert_eq!(
+			payment_store.get(&PaymentId([2u8; 32])).unwrap().status,
+			PaymentStatus::Created
+		);
+		assert_eq!(
+			payment_store.get(&PaymentId([3u8; 32])).unwrap().status,
+			PaymentStatus::Created
+		);
+
+		// Funds arriving late for an expired payment still complete it.
+		assert_eq!(
+			payment_store
+				.update(&PaymentId([1u8; 32]), |p| p.set_status(PaymentStatus::Succeeded))
+				.unwrap(),
+			Some(true)
+		);
+	}
+
+	#[test]
+	fn payments_can_be_abandoned() {
+		let test_persister = Arc::new(TestPersister::new());
+		let payment_store = PaymentStore::new(Arc::clone(&test_persister));
+
+		let unpaid = PaymentId([1u8; 32]);
+		let paid = PaymentId([2u8; 32]);
+		for (payment_id, status) in
+			[(unpaid, PaymentStatus::Created), (paid, PaymentStatus::InFlight)]
+		{
+			let payment =
+				PaymentInfo::new(payment_id, None, PaymentDirection::Inbound, None, status);
+			payment_store.insert(payment).unwrap();
+		}
+		test_persister.get_and_clear_pending_persist();
+
+		payment_store.abandon_payment(&unpaid).unwrap();
+		assert!(test_persister.get_and_clear_pending_persist());
+		assert_eq!(payment_store.get(&unpaid).unwrap().status, PaymentStatus::Abandoned);
+		assert!(!payment_store.get(&unpaid).unwrap().set_status(PaymentStatus::Succeeded));
+
+		assert!(matches!(payment_store.abandon_payment(&paid), Err(Error::PaymentNotAbandonable)));
+		assert!(matches!(
+			payment_store.abandon_payment(&PaymentId([3u8; 32])),
+			Err(Error::PaymentNotFound)
+		));
+		assert_eq!(payment_store.get(&paid).unwrap().status, PaymentStatus::InFlight);
+	}
+
+	#[test]
+	fn refunds_cant_exceed_refunded_amount() {
+		let test_persister = Arc::new(TestPersister::new());
+		let payment_store = PaymentStore::new(Arc::clone(&test_persister));
+
+		let refunded_payment_hash = PaymentHash([1u8; 32]);
+		let new_refund = |id: u8, amount_msat: u64| {
+			let mut refund = PaymentInfo::new(
+				PaymentId([id; 32]),
+				None,
+				PaymentDirection::Outbound,
+				Some(amount_msat),
+				PaymentStatus::Created,
+			);
+			refund.refund_for = Some(refunded_payment_hash);
+			refund
+		};
+
+		// Only payments we received can be refunded.
+		assert!(matches!(
+			payment_store.insert_refund(new_refund(10, 100)),
+			Err(Error::PaymentNotFound)
+		));
+		let mut refunded_payment = PaymentInfo::new(
+			PaymentId(refunded_payment_hash.0),
+			Some(refunded_payment_hash),
+			PaymentDirection::Inbound,
+			Some(1_000),
+			PaymentStatus::Created,
+		);
+		payment_store.insert(refunded_payment.clone()).unwrap();
+		assert!(matches!(
+			payment_store.insert_refund(new_refund(10, 100)),
+			Err(Error::PaymentNotFound)
+		));
+		refunded_payment.set_status(PaymentStatus::Succeeded);
+		payment_store.insert(refunded_payment).unwrap();
+		assert!(test_persister.get_and_clear_pending_persist());
+
+		assert!(matches!(
+			payment_store.insert_refund(new_refund(10, 0)),
+			Err(Error::InvalidAmount)
+		));
+		assert!(matches!(
+			payment_store.insert_refund(new_refund(10, 1_001)),
+			Err(Error::InvalidAmount)
+		));
+		assert!(!payment_store.contains(&PaymentId([10u8; 32])));
+		assert!(!test_persister.get_and_clear_pending_persist());
+
+		// Partial refunds add up to the refunded amount.
+		payment_store.insert_refund(new_refund(10, 600)).unwrap();
+		assert!(test_persister.get_and_clear_pending_persist());
+		assert!(matches!(
+			payment_store.insert_refund(new_refund(11, 401)),
+			Err(Error::InvalidAmount)
+		));
+		payment_store.insert_refund(new_refund(11, 400)).unwrap();
+		assert!(matches!(
+			payment_store.insert_refund(new_refund(12, 1)),
+			Err(Error::InvalidAmount)
+		));
+
+		// Failed refunds don't count towards the refunded amount.
+		payment_store
+			.update(&PaymentId([11u8; 32]), |p| p.set_status(PaymentStatus::Failed))
+			.unwrap();
+		payment_store.insert_refund(new_refund(12, 400)).unwrap();
+		assert!(matches!(
+			payment_store.insert_refund(new_refund(13, 1)),
+			Err(Error::InvalidAmount)
+		));
+	}
+}
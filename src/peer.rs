@@ -0,0 +1,768 @@
+use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::net_address::{self, format_address, parse_address};
+use crate::proxy::{connect_via_proxy, ProxyConfig};
+use crate::{ChannelManager, Error, NetworkGraph, PeerManager};
+
+use lightning::ln::msgs::NetAddress;
+use lightning::routing::gossip::NodeId;
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use bitcoin::secp256k1::PublicKey;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The peers we reconnect to will be persisted under this key.
+pub(crate) const PEERS_PERSISTENCE_KEY: &str = "peers";
+
+/// The time we wait for a connection to be established.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The interval in which we check for disconnected peers to reconnect to.
+const RECONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The time we wait before the first reconnection attempt after a failed one.
+const RECONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The maximum time we wait between reconnection attempts.
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// The time a connection has to stay up before we reset the backoff of the peer.
+const RECONNECT_STABLE_CONNECTION_TIME: Duration = Duration::from_secs(60);
+
+/// The node id and address of a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+	/// The node id of the peer.
+	pub node_id: PublicKey,
+	/// The address of the peer.
+	pub address: NetAddress,
+}
+
+impl FromStr for PeerInfo {
+	type Err = Error;
+
+	/// Parses peer info in the `node_id@host:port` format.
+	///
+	/// The host may be an IP address, a Tor v3 onion service address, or a hostname. Hostnames
+	/// are resolved when connecting, via the proxy if one is configured.
+	fn from_str(peer_info: &str) -> Result<Self, Self::Err> {
+		let (node_id, address) =
+			peer_info.split_once('@').ok_or(Error::PeerInfoParse("Expected node_id@host:port"))?;
+		let node_id =
+			PublicKey::from_str(node_id).map_err(|_| Error::PeerInfoParse("Invalid node id"))?;
+		let address = parse_address(address)?;
+		Ok(Self { node_id, address })
+	}
+}
+
+impl fmt::Display for PeerInfo {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}@{}", self.node_id, format_address(&self.address))
+	}
+}
+
+impl Readable for PeerInfo {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let node_id: PublicKey = Readable::read(reader)?;
+		let address: String = Readable::read(reader)?;
+		let address =
+			parse_address(&address).map_err(|_| lightning::ln::msgs::DecodeError::InvalidValue)?;
+		Ok(Self { node_id, address })
+	}
+}
+
+impl Writeable for PeerInfo {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		self.node_id.write(writer)?;
+		format_address(&self.address).write(writer)?;
+		Ok(())
+	}
+}
+
+/// Details of a connected peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerDetails {
+	/// The node id of the peer.
+	pub node_id: PublicKey,
+	/// The address of the peer's end of the connection, if known.
+	pub address: Option<NetAddress>,
+	/// The features of the peer, as little-endian flags, if known from a channel with it or its
+	/// node announcement.
+	pub features: Vec<u8>,
+	/// Whether we have channels with the peer.
+	pub has_channels: bool,
+	/// Whether we reconnect to the peer when disconnected.
+	pub is_persisted: bool,
+}
+
+// The state of our reconnection attempts to a peer, kept until the peer stayed connected for
+// `RECONNECT_STABLE_CONNECTION_TIME`.
+struct ReconnectState {
+	backoff: Duration,
+	next_attempt: Instant,
+	in_progress: bool,
+	// The time since which the peer is connected, if it is.
+	connected_since: Option<Instant>,
+}
+
+impl ReconnectState {
+	fn new(now: Instant) -> Self {
+		Self {
+			backoff: RECONNECT_INITIAL_BACKOFF,
+			next_attempt: now,
+			in_progress: false,
+			connected_since: None,
+		}
+	}
+
+	// Schedules the next attempt after the current one finished. We back off after successful
+	// attempts too, as the connection may drop again right away.
+	fn attempt_finished(&mut self, connected: bool, now: Instant) {
+		self.in_progress = false;
+		self.connected_since = if connected { Some(now) } else { None };
+		self.next_attempt = now + self.backoff;
+		self.backoff = (self.backoff * 2).min(RECONNECT_MAX_BACKOFF);
+	}
+}
+
+// The peers we know about, kept under a single lock.
+#[derive(Default)]
+struct Peers {
+	persisted: HashMap<PublicKey, PeerInfo>,
+	// The addresses of peers we connected to, which are persisted once we have channels with them.
+	known_addresses: HashMap<PublicKey, NetAddress>,
+	// The peers the user disconnected from, which we don't reconnect to.
+	disconnected: HashSet<PublicKey>,
+	reconnect_states: HashMap<PublicKey, ReconnectState>,
+}
+
+/// Connects to and disconnects from peers, and keeps us connected to the peers we have channels
+/// with.
+///
+/// Disconnected peers are reconnected with exponential backoff, starting right away after a
+/// restart. The backoff is only reset once a connection stayed up for a minute, so peers whose
+/// connections keep dropping aren't hammered with attempts. If a [`ProxyConfig`] is given, all
+/// outbound connections are made via the proxy.
+pub struct PeerHandler<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	peer_manager: Arc<PeerManager>,
+	channel_manager: Arc<ChannelManager>,
+	network_graph: Arc<NetworkGraph>,
+	peers: Arc<Mutex<Peers>>,
+	proxy: Option<ProxyConfig>,
+	tokio_runtime: Arc<tokio::runtime::Runtime>,
+	persister: K,
+	logger: L,
+}
+
+impl<K: Deref + Clone + Send + Sync + 'static, L: Deref + Clone + Send + Sync + 'static>
+	PeerHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(
+		peer_manager: Arc<PeerManager>, channel_manager: Arc<ChannelManager>,
+		network_graph: Arc<NetworkGraph>, proxy: Option<ProxyConfig>,
+		tokio_runtime: Arc<tokio::runtime::Runtime>, persister: K, logger: L,
+	) -> Self {
+		let peers = Arc::new(Mutex::new(Peers::default()));
+		let handler = Self {
+			peer_manager,
+			channel_manager,
+			network_graph,
+			peers,
+			proxy,
+			tokio_runtime,
+			persister,
+			logger,
+		};
+		handler.start();
+		handler
+	}
+
+	// Starts reconnecting to disconnected peers every `RECONNECT_CHECK_INTERVAL`.
+	fn start(&self) {
+		let reconnect_peer_manager = Arc::clone(&self.peer_manager);
+		let reconnect_channel_manager = Arc::clone(&self.channel_manager);
+		let reconnect_network_graph = Arc::clone(&self.network_graph);
+		let reconnect_peers = Arc::clone(&self.peers);
+		let reconnect_proxy = self.proxy.clone();
+		let reconnect_persister = self.persister.clone();
+		let reconnect_logger = self.logger.clone();
+		self.tokio_runtime.spawn(async move {
+			let mut interval = tokio::time::interval(RECONNECT_CHECK_INTERVAL);
+			loop {
+				interval.tick().await;
+				reconnect_disconnected_peers(
+					&reconnect_peer_manager,
+					&reconnect_channel_manager,
+					&reconnect_network_graph,
+					&reconnect_peers,
+					reconnect_proxy.as_ref(),
+					&reconnect_persister,
+					&reconnect_logger,
+				);
+			}
+		});
+	}
+}
+
+impl<K: Deref, L: Deref> PeerHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	/// Connects to the given peer.
+	///
+	/// If `persist` is set, we'll reconnect to the peer whenever we get disconnected, including
+	/// after restarts. Peers we have channels with are always reconnected to.
+	pub fn connect(&self, peer_info: PeerInfo, persist: bool) -> Result<(), Error> {
+		let node_id = peer_info.node_id;
+		if !self.is_connected(&node_id) {
+			// We may be called from within the runtime, where blocking on it would panic, so we
+			// connect on the runtime and wait for the result outside of it.
+			let (sender, receiver) = std::sync::mpsc::channel();
+			let peer_manager = Arc::clone(&self.peer_manager);
+			let address = peer_info.address.clone();
+			let proxy = self.proxy.clone();
+			self.tokio_runtime.spawn(async move {
+				let res = connect_peer(peer_manager, node_id, address, proxy).await;
+				let _ = sender.send(res);
+			});
+			tokio::task::block_in_place(move || receiver.recv())
+				.unwrap_or(Err(Error::ConnectionFailed))
+				.map_err(|e| {
+					log_error!(self.logger, "Failed to connect to peer {}: {}", peer_info, e);
+					e
+				})?;
+			log_info!(self.logger, "Connected to peer {}.", peer_info);
+		}
+
+		let mut locked_peers = self.peers.lock().unwrap();
+		locked_peers.disconnected.remove(&node_id);
+		locked_peers.known_addresses.insert(node_id, peer_info.address.clone());
+		if persist || self.has_channels(&node_id) {
+			if locked_peers.persisted.get(&node_id) != Some(&peer_info) {
+				locked_peers.persisted.insert(node_id, peer_info);
+				persist_peers(&self.persister, &locked_peers.persisted)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Disconnects from the peer with the given node id.
+	///
+	/// We won't reconnect to the peer automatically anymore until we're restarted, even if we
+	/// have channels with it. These channels can't be used until we connect to the peer again.
+	pub fn disconnect(&self, node_id: &PublicKey) -> Result<(), Error> {
+		{
+			let mut locked_peers = self.peers.lock().unwrap();
+			if locked_peers.persisted.remove(node_id).is_some() {
+				persist_peers(&self.persister, &locked_peers.persisted)?;
+			}
+			locked_peers.known_addresses.remove(node_id);
+			locked_peers.disconnected.insert(*node_id);
+			locked_peers.reconnect_states.remove(node_id);
+		}
+		self.peer_manager.disconnect_by_node_id(*node_id);
+		log_info!(self.logger, "Disconnected from peer {}.", node_id);
+		Ok(())
+	}
+
+	/// Returns the details of all connected peers.
+	pub fn list_peers(&self) -> Vec<PeerDetails> {
+		let channels = self.channel_manager.list_channels();
+		let read_only_graph = self.network_graph.read_only();
+		let locked_peers = self.peers.lock().unwrap();
+		self.peer_manager
+			.get_peer_node_ids()
+			.into_iter()
+			.map(|(node_id, address)| {
+				let channel = channels.iter().find(|c| c.counterparty.node_id == node_id);
+				let features = channel
+					.map(|c| c.counterparty.features.le_flags().to_vec())
+					.or_else(|| {
+						read_only_graph
+							.node(&NodeId::from_pubkey(&node_id))
+							.and_then(|n| n.announcement_info.as_ref())
+							.map(|a| a.features.le_flags().to_vec())
+					})
+					.unwrap_or_default();
+				PeerDetails {
+					node_id,
+					address,
+					features,
+					has_channels: channel.is_some(),
+					is_persisted: locked_peers.persisted.contains_key(&node_id),
+				}
+			})
+			.collect()
+	}
+
+	fn is_connected(&self, node_id: &PublicKey) -> bool {
+		self.peer_manager.get_peer_node_ids().iter().any(|(id, _)| id == node_id)
+	}
+
+	fn has_channels(&self, node_id: &PublicKey) -> bool {
+		self.channel_manager.list_channels().iter().any(|c| c.counterparty.node_id == *node_id)
+	}
+}
+
+impl<K: Deref + Clone + Send + Sync + 'static, L: Deref + Clone + Send + Sync + 'static>
+	ReadableArgs<(
+		Arc<PeerManager>,
+		Arc<ChannelManager>,
+		Arc<NetworkGraph>,
+		Option<ProxyConfig>,
+		Arc<tokio::runtime::Runtime>,
+		K,
+		L,
+	)> for PeerHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+		args: (
+			Arc<PeerManager>,
+			Arc<ChannelManager>,
+			Arc<NetworkGraph>,
+			Option<ProxyConfig>,
+			Arc<tokio::runtime::Runtime>,
+			K,
+			L,
+		),
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let (peer_manager, channel_manager, network_graph, proxy, tokio_runtime, persister, logger) =
+			args;
+		let read_peers: PeersDeserWrapper = Readable::read(reader)?;
+		let peers = Arc::new(Mutex::new(Peers { persisted: read_peers.0, ..Default::default() }));
+		let handler = Self {
+			peer_manager,
+			channel_manager,
+			network_graph,
+			peers,
+			proxy,
+			tokio_runtime,
+			persister,
+			logger,
+		};
+		handler.start();
+		Ok(handler)
+	}
+}
+
+/// Starts reconnection attempts to all persisted peers and peers we have channels with that are
+/// disconnected and due for another attempt.
+///
+/// Peers we have channels with but never connected to ourselves are reconnected to via the
+/// addresses they announced.
+fn reconnect_disconnected_peers<K: Deref, L: Deref>(
+	peer_manager: &Arc<PeerManager>, channel_manager: &ChannelManager,
+	network_graph: &NetworkGraph, peers: &Arc<Mutex<Peers>>, proxy: Option<&ProxyConfig>,
+	persister: &K, logger: &L,
+) where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	let connected_peers = peer_manager
+		.get_peer_node_ids()
+		.into_iter()
+		.map(|(node_id, _)| node_id)
+		.collect::<HashSet<_>>();
+	let channel_peers = channel_manager
+		.list_channels()
+		.into_iter()
+		.map(|c| c.counterparty.node_id)
+		.collect::<HashSet<_>>();
+
+	let due_peers = {
+		let mut locked_peers = peers.lock().unwrap();
+		persist_channel_peers(&mut locked_peers, &channel_peers, persister, logger);
+		due_reconnections(
+			&mut locked_peers,
+			&connected_peers,
+			&channel_peers,
+			network_graph,
+			proxy,
+			Instant::now(),
+		)
+	};
+
+	for (node_id, address) in due_peers {
+		let peer_manager = Arc::clone(peer_manager);
+		let peers = Arc::clone(peers);
+		let proxy = proxy.cloned();
+		tokio::spawn(async move {
+			let res = connect_peer(peer_manager, node_id, address, proxy).await;
+			if let Some(state) = peers.lock().unwrap().reconnect_states.get_mut(&node_id) {
+				state.attempt_finished(res.is_ok(), Instant::now());
+			}
+		});
+	}
+}
+
+// Returns the peers due for another reconnection attempt at `now`, along with the address to
+// connect to, and marks the attempts as in progress.
+//
+// Reconnection states of peers that stayed connected for `RECONNECT_STABLE_CONNECTION_TIME` are
+// dropped, resetting their backoff.
+fn due_reconnections(
+	peers: &mut Peers, connected_peers: &HashSet<PublicKey>, channel_peers: &HashSet<PublicKey>,
+	network_graph: &NetworkGraph, proxy: Option<&ProxyConfig>, now: Instant,
+) -> Vec<(PublicKey, NetAddress)> {
+	let Peers { persisted, disconnected, reconnect_states, .. } = peers;
+	reconnect_states.retain(|node_id, state| {
+		if !connected_peers.contains(node_id) {
+			state.connected_since = None;
+			return true;
+		}
+		if state.in_progress {
+			return true;
+		}
+		// The peer may also have connected to us, or been connected to via `connect`.
+		let connected_since = *state.connected_since.get_or_insert(now);
+		now.saturating_duration_since(connected_since) < RECONNECT_STABLE_CONNECTION_TIME
+	});
+
+	let candidates = persisted.keys().chain(channel_peers.iter()).collect::<HashSet<_>>();
+	let mut due_peers = Vec::new();
+	for node_id in candidates {
+		if connected_peers.contains(node_id) || disconnected.contains(node_id) {
+			continue;
+		}
+
+		let state = reconnect_states.entry(*node_id).or_insert_with(|| ReconnectState::new(now));
+		if state.in_progress || state.next_attempt > now {
+			continue;
+		}
+
+		let address = match persisted
+			.get(node_id)
+			.map(|p| p.address.clone())
+			.or_else(|| announced_address(network_graph, node_id, proxy))
+		{
+			Some(address) => address,
+			None => continue,
+		};
+
+		state.in_progress = true;
+		due_peers.push((*node_id, address));
+	}
+	due_peers
+}
+
+// Persists the addresses of the peers we connected to and now have channels with.
+fn persist_channel_peers<K: Deref, L: Deref>(
+	peers: &mut Peers, channel_peers: &HashSet<PublicKey>, persister: &K, logger: &L,
+) where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	let mut updated = false;
+	for node_id in channel_peers {
+		if let Some(address) = peers.known_addresses.get(node_id) {
+			if !peers.persisted.contains_key(node_id) {
+				peers
+					.persisted
+					.insert(*node_id, PeerInfo { node_id: *node_id, address: address.clone() });
+				updated = true;
+			}
+		}
+	}
+	if updated {
+		if let Err(e) = persist_peers(persister, &peers.persisted) {
+			log_error!(logger, "Failed to persist channel peers: {}", e);
+		}
+	}
+}
+
+// Returns the first announced address of the given node we can connect to. Onion addresses can
+// only be connected to via a proxy, so we prefer IP addresses.
+fn announced_address(
+	network_graph: &NetworkGraph, node_id: &PublicKey, proxy: Option<&ProxyConfig>,
+) -> Option<NetAddress> {
+	let read_only_graph = network_graph.read_only();
+	let announcement =
+		read_only_graph.node(&NodeId::from_pubkey(node_id))?.announcement_info.as_ref()?;
+	let addresses = &announcement.addresses;
+	addresses
+		.iter()
+		.find(|a| matches!(a, NetAddress::IPv4 { .. } | NetAddress::IPv6 { .. }))
+		.or_else(|| {
+			proxy?;
+			addresses.iter().find(|a| matches!(a, NetAddress::OnionV3 { .. }))
+		})
+		.cloned()
+}
+
+fn persist_peers<K: Deref>(
+	persister: &K, persisted_peers: &HashMap<PublicKey, PeerInfo>,
+) -> Result<(), Error>
+where
+	K::Target: KVStorePersister,
+{
+	persister
+		.persist(PEERS_PERSISTENCE_KEY, &PeersSerWrapper(persisted_peers))
+		.map_err(|_| Error::PersistenceFailed)?;
+	Ok(())
+}
+
+struct PeersDeserWrapper(HashMap<PublicKey, PeerInfo>);
+
+impl Readable for PeersDeserWrapper {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let len: u64 = Readable::read(reader)?;
+		let mut peers = HashMap::with_capacity(len as usize);
+		for _ in 0..len {
+			let peer_info: PeerInfo = Readable::read(reader)?;
+			peers.insert(peer_info.node_id, peer_info);
+		}
+		Ok(Self(peers))
+	}
+}
+
+struct PeersSerWrapper<'a>(&'a HashMap<PublicKey, PeerInfo>);
+
+impl Writeable for PeersSerWrapper<'_> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		(self.0.len() as u64).write(writer)?;
+		for peer_info in self.0.values() {
+			peer_info.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+// Connects to the given peer, via the proxy if given, and waits until the handshake is
+// complete.
+async fn connect_peer(
+	peer_manager: Arc<PeerManager>, node_id: PublicKey, address: NetAddress,
+	proxy: Option<ProxyConfig>,
+) -> Result<(), Error> {
+	let deadline = tokio::time::Instant::now() + CONNECTION_TIMEOUT;
+	let stream = tokio::time::timeout_at(deadline, open_stream(&address, proxy.as_ref()))
+		.await
+		.map_err(|_| Error::ConnectionFailed)?
+		.map_err(|_| Error::ConnectionFailed)?;
+	let stream = stream.into_std().map_err(|_| Error::ConnectionFailed)?;
+	let connection_closed =
+		lightning_net_tokio::setup_outbound(Arc::clone(&peer_manager), node_id, stream);
+	let mut connection_closed = Box::pin(connection_closed);
+
+	loop {
+		if peer_manager.get_peer_node_ids().iter().any(|(id, _)| *id == node_id) {
+			return Ok(());
+		}
+		if tokio::time::Instant::now() >= deadline {
+			return Err(Error::ConnectionFailed);
+		}
+		tokio::select! {
+			_ = &mut connection_closed => return Err(Error::ConnectionFailed),
+			_ = tokio::time::sleep(Duration::from_millis(10)) => {}
+		}
+	}
+}
+
+// Opens a TCP connection to the given address. Onion addresses require a proxy.
+async fn open_stream(
+	address: &NetAddress, proxy: Option<&ProxyConfig>,
+) -> Result<tokio::net::TcpStream, std::io::Error> {
+	if let Some(proxy) = proxy {
+		return connect_via_proxy(proxy, address).await;
+	}
+	match address {
+		NetAddress::IPv4 { .. } | NetAddress::IPv6 { .. } | NetAddress::Hostname { .. } => {
+			let host = net_address::host(address);
+			tokio::net::TcpStream::connect((host.as_str(), net_address::port(address))).await
+		}
+		NetAddress::OnionV2(_) | NetAddress::OnionV3 { .. } => Err(std::io::Error::new(
+			std::io::ErrorKind::Unsupported,
+			"onion addresses can only be connected to via a proxy",
+		)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::test_utils::{
+		add_test_channel, announce_test_node, test_filesystem_logger, test_node_id, TestLogger,
+		TestPersister,
+	};
+
+	use bitcoin::Network;
+
+	#[test]
+	fn peer_info_parsing() {
+		let node_id = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+		let peer_info = PeerInfo::from_str(&format!("{}@127.0.0.1:9735", node_id)).unwrap();
+		assert_eq!(peer_info.node_id.to_string(), node_id);
+		assert_eq!(peer_info.address, NetAddress::IPv4 { addr: [127, 0, 0, 1], port: 9735 });
+		assert_eq!(peer_info.to_string(), format!("{}@127.0.0.1:9735", node_id));
+
+		let onion_peer_info = format!(
+			"{}@2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion:9735",
+			node_id
+		);
+		let peer_info = PeerInfo::from_str(&onion_peer_info).unwrap();
+		assert!(matches!(peer_info.address, NetAddress::OnionV3 { port: 9735, .. }));
+		assert_eq!(peer_info.to_string(), onion_peer_info);
+		let mut encoded = Vec::new();
+		peer_info.write(&mut encoded).unwrap();
+		let decoded: PeerInfo = Readable::read(&mut &encoded[..]).unwrap();
+		assert_eq!(decoded, peer_info);
+
+		assert!(PeerInfo::from_str("127.0.0.1:9735").is_err());
+		assert!(PeerInfo::from_str("deadbeef@127.0.0.1:9735").is_err());
+		assert!(PeerInfo::from_str(&format!("{}@127.0.0.1", node_id)).is_err());
+	}
+
+	fn test_peer_info(seed: u8) -> PeerInfo {
+		PeerInfo {
+			node_id: test_node_id(seed),
+			address: NetAddress::IPv4 { addr: [127, 0, 0, seed], port: 9735 },
+		}
+	}
+
+	#[test]
+	fn reconnect_backoff_is_kept_until_connection_is_stable() {
+		let network_graph =
+			NetworkGraph::new(Network::Regtest, test_filesystem_logger("reconnect_backoff"));
+		let peer_info = test_peer_info(1);
+		let node_id = peer_info.node_id;
+		let mut peers = Peers::default();
+		peers.persisted.insert(node_id, peer_info.clone());
+		let connected = [node_id].iter().cloned().collect::<HashSet<_>>();
+		let disconnected = HashSet::new();
+		let start = Instant::now();
+		let at = |secs: u64| start + Duration::from_secs(secs);
+		let due = |peers: &mut Peers, connected_peers: &HashSet<PublicKey>, now: Instant| {
+			due_reconnections(peers, connected_peers, &HashSet::new(), &network_graph, None, now)
+		};
+
+		// We attempt to reconnect right away, but only once at a time.
+		assert_eq!(
+			due(&mut peers, &disconnected, at(0)),
+			vec![(node_id, peer_info.address.clone())]
+		);
+		assert!(due(&mut peers, &disconnected, at(0)).is_empty());
+
+		// The connection drops again shortly after succeeding, so we keep backing off.
+		peers.reconnect_states.get_mut(&node_id).unwrap().attempt_finished(true, at(0));
+		assert!(due(&mut peers, &connected, at(30)).is_empty());
+		assert_eq!(due(&mut peers, &disconnected, at(31)).len(), 1);
+		peers.reconnect_states.get_mut(&node_id).unwrap().attempt_finished(false, at(31));
+		assert_eq!(peers.reconnect_states[&node_id].backoff, Duration::from_secs(4));
+		assert!(due(&mut peers, &disconnected, at(32)).is_empty());
+		assert_eq!(due(&mut peers, &disconnected, at(33)).len(), 1);
+
+		// Once the connection stayed up long enough, the backoff is reset.
+		peers.reconnect_states.get_mut(&node_id).unwrap().attempt_finished(true, at(33));
+		let stable_secs = 33 + RECONNECT_STABLE_CONNECTION_TIME.as_secs();
+		assert!(due(&mut peers, &connected, at(stable_secs - 1)).is_empty());
+		assert!(peers.reconnect_states.contains_key(&node_id));
+		assert!(due(&mut peers, &connected, at(stable_secs)).is_empty());
+		assert!(!peers.reconnect_states.contains_key(&node_id));
+		assert_eq!(due(&mut peers, &disconnected, at(stable_secs + 1)).len(), 1);
+		assert_eq!(peers.reconnect_states[&node_id].backoff, RECONNECT_INITIAL_BACKOFF);
+
+		// The backoff is capped.
+		let state = peers.reconnect_states.get_mut(&node_id).unwrap();
+		for _ in 0..20 {
+			state.attempt_finished(false, at(0));
+		}
+		assert_eq!(state.backoff, RECONNECT_MAX_BACKOFF);
+	}
+
+	#[test]
+	fn reconnect_candidates_are_selected() {
+		let network_graph =
+			NetworkGraph::new(Network::Regtest, test_filesystem_logger("reconnect_candidates"));
+		// Node 2 announced an IP address, node 3 only an onion address and node 4 nothing.
+		add_test_channel(&network_graph, 1, &test_node_id(2), &test_node_id(3));
+		let ip_address = NetAddress::IPv4 { addr: [127, 0, 0, 2], port: 9735 };
+		let onion_address =
+			NetAddress::OnionV3 { ed25519_pubkey: [3; 32], checksum: 0, version: 3, port: 9735 };
+		announce_test_node(&network_graph, &test_node_id(2), "node2", vec![ip_address.clone()]);
+		announce_test_node(&network_graph, &test_node_id(3), "node3", vec![onion_address.clone()]);
+
+		// We reconnect to persisted peers and peers we have channels with, unless they're
+		// connected or the user disconnected from them.
+		let mut peers = Peers::default();
+		for seed in [1, 5, 6].iter() {
+			let peer_info = test_peer_info(*seed);
+			peers.persisted.insert(peer_info.node_id, peer_info);
+		}
+		peers.disconnected.insert(test_node_id(5));
+		let connected_peers = [test_node_id(6)].iter().cloned().collect::<HashSet<_>>();
+		let channel_peers =
+			[2, 3, 4, 6].iter().map(|seed| test_node_id(*seed)).collect::<HashSet<_>>();
+
+		let now = Instant::now();
+		let due_peers = due_reconnections(
+			&mut peers,
+			&connected_peers,
+			&channel_peers,
+			&network_graph,
+			None,
+			now,
+		);
+		assert_eq!(due_peers.len(), 2);
+		assert!(due_peers.contains(&(test_node_id(1), test_peer_info(1).address)));
+		assert!(due_peers.contains(&(test_node_id(2), ip_address)));
+
+		// Onion addresses are only used with a proxy.
+		let proxy = ProxyConfig {
+			address: "127.0.0.1:9050".parse().unwrap(),
+			credentials: None,
+			route_esplora_traffic: false,
+		};
+		let due_peers = due_reconnections(
+			&mut peers,
+			&connected_peers,
+			&channel_peers,
+			&network_graph,
+			Some(&proxy),
+			now,
+		);
+		assert_eq!(due_peers, vec![(test_node_id(3), onion_address)]);
+	}
+
+	#[test]
+	fn channel_peers_are_persisted() {
+		let persister = Arc::new(TestPersister::new());
+		let logger = Arc::new(TestLogger);
+		let mut peers = Peers::default();
+		for seed in [1, 2].iter() {
+			let peer_info = test_peer_info(*seed);
+			peers.known_addresses.insert(peer_info.node_id, peer_info.address);
+		}
+
+		// Only peers we connected to and have channels with are persisted.
+		let channel_peers = [test_node_id(1), test_node_id(3)].iter().cloned().collect();
+		persist_channel_peers(&mut peers, &channel_peers, &persister, &logger);
+		assert!(persister.get_and_clear_pending_persist());
+		assert_eq!(peers.persisted.len(), 1);
+		assert_eq!(peers.persisted[&test_node_id(1)], test_peer_info(1));
+
+		persist_channel_peers(&mut peers, &channel_peers, &persister, &logger);
+		assert!(!persister.get_and_clear_pending_persist());
+	}
+}
@@ -0,0 +1,334 @@
+use crate::keysend::DEFAULT_KEYSEND_FINAL_CLTV_EXPIRY_DELTA;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::scoring::RoutingScorer;
+use crate::{ChannelManager, Error, NetworkGraph, Router};
+
+use lightning::ln::channelmanager::{PaymentId, MIN_FINAL_CLTV_EXPIRY_DELTA};
+use lightning::routing::gossip::NodeId;
+use lightning::routing::router::{Path, PaymentParameters, Route, RouteParameters, Router as _};
+use lightning::util::persist::KVStorePersister;
+use lightning_invoice::Bolt11Invoice;
+
+use bitcoin::secp256k1::PublicKey;
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// How probes are sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeMode {
+	/// Send the probes and return right away. The scorer will learn from the results as they
+	/// arrive.
+	Background,
+	/// Send the probes and wait up to `timeout` for their results before returning. Use this
+	/// before sending a payment to check the destination is reachable.
+	///
+	/// The [`KeysendPayer`] can be configured to do so before each payment.
+	///
+	/// [`KeysendPayer`]: crate::keysend::KeysendPayer
+	Preflight {
+		/// The maximum time to wait for the probe results.
+		timeout: Duration,
+	},
+}
+
+/// The estimated cost and likelihood of success of a payment, as learned through probing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeEstimate {
+	/// The amount that was probed, in thousandths of a satoshi.
+	pub amount_msat: u64,
+	/// The fees we'd expect to pay for a payment of `amount_msat`, in thousandths of a satoshi.
+	pub estimated_fee_msat: u64,
+	/// The probability, between 0 and 1, with which the channels along the probed route are
+	/// estimated to have sufficient liquidity for the payment.
+	pub success_probability: f64,
+	/// The number of paths the route is split into.
+	pub num_paths: usize,
+	/// Whether all probes succeeded. This is only known in [`ProbeMode::Preflight`], and will be
+	/// `None` if the results did not arrive in time.
+	pub probe_succeeded: Option<bool>,
+}
+
+/// Sends probes to learn about the liquidity along the routes to a destination.
+///
+/// Probe results are fed into the scorer, which future payments will benefit from.
+pub struct ProbeHandler<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	channel_manager: Arc<ChannelManager>,
+	router: Arc<Router>,
+	routing_scorer: Arc<RoutingScorer<K>>,
+	network_graph: Arc<NetworkGraph>,
+	// The results of probes we're waiting on in `ProbeMode::Preflight`.
+	pending_probes: Mutex<HashMap<PaymentId, Option<bool>>>,
+	probe_notifier: Condvar,
+	logger: L,
+}
+
+impl<K: Deref, L: Deref> ProbeHandler<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, router: Arc<Router>,
+		routing_scorer: Arc<RoutingScorer<K>>, network_graph: Arc<NetworkGraph>, logger: L,
+	) -> Self {
+		let pending_probes = Mutex::new(HashMap::new());
+		let probe_notifier = Condvar::new();
+		Self {
+			channel_manager,
+			router,
+			routing_scorer,
+			network_graph,
+			pending_probes,
+			probe_notifier,
+			logger,
+		}
+	}
+
+	/// Probes the routes to the node with the given `node_id` for a spontaneous payment of
+	/// `amount_msat`, using the [`DEFAULT_KEYSEND_FINAL_CLTV_EXPIRY_DELTA`].
+	pub fn probe_node(
+		&self, node_id: PublicKey, amount_msat: u64, mode: ProbeMode,
+	) -> Result<ProbeEstimate, Error> {
+		let payment_params =
+			PaymentParameters::from_node_id(node_id, DEFAULT_KEYSEND_FINAL_CLTV_EXPIRY_DELTA);
+		self.send_probes(payment_params, amount_msat, mode)
+	}
+
+	/// Probes the routes to the payee of the given invoice.
+	///
+	/// For variable-amount invoices, the amount to probe for has to be given as `amount_msat`.
+	pub fn probe_invoice(
+		&self, invoice: &Bolt11Invoice, amount_msat: Option<u64>, mode: ProbeMode,
+	) -> Result<ProbeEstimate, Error> {
+		let amount_msat = match (invoice.amount_milli_satoshis(), amount_msat) {
+			(Some(invoice_amount_msat), None) => invoice_amount_msat,
+			(None, Some(amount_msat)) => amount_msat,
+			_ => return Err(Error::InvalidAmount),
+		};
+
+		// Recipients fail back payments expiring too soon for them to claim safely, even if their
+		// invoice asked for a shorter delta.
+		let final_cltv_expiry_delta =
+			(invoice.min_final_cltv_expiry_delta() as u32).max(MIN_FINAL_CLTV_EXPIRY_DELTA as u32);
+		let payment_params = PaymentParameters::from_node_id(
+			invoice.recover_payee_pub_key(),
+			final_cltv_expiry_delta,
+		)
+		.with_expiry_time(
+			invoice.duration_since_epoch().as_secs() + invoice.expiry_time().as_secs(),
+		)
+		.with_route_hints(invoice.route_hints())
+		.map_err(|()| {
+			log_error!(self.logger, "Failed to apply the route hints of invoice {}.", invoice);
+			Error::RouteNotFound
+		})?;
+		self.send_probes(payment_params, amount_msat, mode)
+	}
+
+	/// Probes the routes to the node with the given `node_id` in [`ProbeMode::Preflight`] before a
+	/// payment of `amount_msat` is sent to it.
+	///
+	/// Fails if any of the probes failed. If their results don't arrive within `timeout`, the
+	/// node is assumed to be reachable.
+	pub(crate) fn preflight_node(
+		&self, node_id: PublicKey, amount_msat: u64, final_cltv_expiry_delta: u32,
+		timeout: Duration,
+	) -> Result<ProbeEstimate, Error> {
+		let payment_params = PaymentParameters::from_node_id(node_id, final_cltv_expiry_delta);
+		let estimate =
+			self.send_probes(payment_params, amount_msat, ProbeMode::Preflight { timeout })?;
+		if estimate.probe_succeeded == Some(false) {
+			log_error!(
+				self.logger,
+				"Preflight probes for {} msats to {} failed.",
+				amount_msat,
+				node_id
+			);
+			return Err(Error::RouteNotFound);
+		}
+		Ok(estimate)
+	}
+
+	fn send_probes(
+		&self, payment_params: PaymentParameters, amount_msat: u64, mode: ProbeMode,
+	) -> Result<ProbeEstimate, Error> {
+		if amount_msat == 0 {
+			return Err(Error::InvalidAmount);
+		}
+
+		let route = self.find_route(payment_params, amount_msat)?;
+
+		let mut probe_ids = Vec::with_capacity(route.paths.len());
+		{
+			let mut locked_probes = self.pending_probes.lock().unwrap();
+			for path in &route.paths {
+				match self.channel_manager.send_probe(path.clone()) {
+					Ok((_, probe_id)) => {
+						if let ProbeMode::Preflight { .. } = mode {
+							locked_probes.insert(probe_id, None);
+						}
+						probe_ids.push(probe_id);
+					}
+					Err(e) => {
+						log_error!(self.logger, "Failed to send probe: {:?}", e);
+						for probe_id in &probe_ids {
+							locked_probes.remove(probe_id);
+						}
+						return Err(Error::ProbeSendingFailed);
+					}
+				}
+			}
+		}
+
+		log_info!(
+			self.logger,
+			"Sent {} probe(s) for {} msats to {}.",
+			probe_ids.len(),
+			amount_msat,
+			route.paths[0].hops.last().map_or("unknown".to_string(), |h| h.pubkey.to_string()),
+		);
+
+		let probe_succeeded = match mode {
+			ProbeMode::Background => None,
+			ProbeMode::Preflight { timeout } => self.await_probes(&probe_ids, timeout),
+		};
+
+		Ok(ProbeEstimate {
+			amount_msat,
+			estimated_fee_msat: route.get_total_fees(),
+			success_probability: self.estimate_success_probability(&route),
+			num_paths: route.paths.len(),
+			probe_succeeded,
+		})
+	}
+
+	fn find_route(
+		&self, payment_params: PaymentParameters, amount_msat: u64,
+	) -> Result<Route, Error> {
+		let route_params = RouteParameters { payment_params, final_value_msat: amount_msat };
+		let first_hops = self.channel_manager.list_usable_channels();
+		self.router
+			.find_route(
+				&self.channel_manager.get_our_node_id(),
+				&route_params,
+				Some(&first_hops.iter().collect::<Vec<_>>()),
+				self.channel_manager.compute_inflight_htlcs(),
+			)
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to find route for probe: {}", e.err);
+				Error::RouteNotFound
+			})
+	}
+
+	// Waits until all given probes have resolved or the timeout is reached. Returns `None` on
+	// timeout.
+	fn await_probes(&self, probe_ids: &[PaymentId], timeout: Duration) -> Option<bool> {
+		let (mut locked_probes, _) = self
+			.probe_notifier
+			.wait_timeout_while(self.pending_probes.lock().unwrap(), timeout, |probes| {
+				probe_ids.iter().any(|id| probes.get(id).map_or(false, |r| r.is_none()))
+			})
+			.unwrap();
+
+		let results =
+			probe_ids.iter().map(|id| locked_probes.remove(id).flatten()).collect::<Vec<_>>();
+		if results.contains(&Some(false)) {
+			Some(false)
+		} else if results.contains(&None) {
+			None
+		} else {
+			Some(true)
+		}
+	}
+
+	fn estimate_success_probability(&self, route: &Route) -> f64 {
+		let read_only_graph = self.network_graph.read_only();
+		route
+			.paths
+			.iter()
+			.map(|path| {
+				let mut probability = 1.0;
+				let mut amount_msat = 0;
+				// Walk the path backwards, so we know the amount that has to pass each hop.
+				// Our own first hop is skipped as the router only uses it if it has enough
+				// outbound capacity.
+				for hop in path.hops.iter().skip(1).rev() {
+					amount_msat += hop.fee_msat;
+					let target = NodeId::from_pubkey(&hop.pubkey);
+					let liquidity_range = self
+						.routing_scorer
+						.estimated_channel_liquidity_range(hop.short_channel_id, &target)
+						.or_else(|| {
+							read_only_graph
+								.channel(hop.short_channel_id)
+								.and_then(|c| c.capacity_sats)
+								.map(|capacity_sats| (0, capacity_sats * 1000))
+						});
+					if let Some((min_liquidity_msat, max_liquidity_msat)) = liquidity_range {
+						probability *= success_probability(
+							amount_msat,
+							min_liquidity_msat,
+							max_liquidity_msat,
+						);
+					}
+				}
+				probability
+			})
+			.product()
+	}
+
+	pub(crate) fn probe_successful(&self, probe_id: PaymentId, path: &Path) {
+		self.routing_scorer.probe_successful(path);
+		self.probe_resolved(probe_id, true);
+	}
+
+	pub(crate) fn probe_failed(
+		&self, probe_id: PaymentId, path: &Path, short_channel_id: Option<u64>,
+	) {
+		// We can only learn from the failure if we know which channel is to blame.
+		if let Some(scid) = short_channel_id {
+			self.routing_scorer.probe_failed(path, scid);
+		}
+		self.probe_resolved(probe_id, false);
+	}
+
+	fn probe_resolved(&self, probe_id: PaymentId, succeeded: bool) {
+		let mut locked_probes = self.pending_probes.lock().unwrap();
+		if let Some(result) = locked_probes.get_mut(&probe_id) {
+			*result = Some(succeeded);
+			self.probe_notifier.notify_all();
+		}
+	}
+}
+
+// Returns the probability that a channel can route `amount_msat`, assuming its liquidity is
+// distributed uniformly within the given bounds.
+fn success_probability(amount_msat: u64, min_liquidity_msat: u64, max_liquidity_msat: u64) -> f64 {
+	if amount_msat <= min_liquidity_msat {
+		1.0
+	} else if amount_msat >= max_liquidity_msat {
+		0.0
+	} else {
+		(max_liquidity_msat - amount_msat) as f64 / (max_liquidity_msat - min_liquidity_msat) as f64
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn success_probability_within_liquidity_bounds() {
+		assert_eq!(success_probability(1_000, 2_000, 10_000), 1.0);
+		assert_eq!(success_probability(10_000, 2_000, 10_000), 0.0);
+		assert_eq!(success_probability(20_000, 2_000, 10_000), 0.0);
+		assert_eq!(success_probability(6_000, 2_000, 10_000), 0.5);
+	}
+}
@@ -0,0 +1,275 @@
+use crate::hex_utils;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::payment_store::{PaymentDirection, PaymentInfo, PaymentStatus, PaymentStore};
+use crate::{ChannelManager, Error, KeysManager, Router};
+
+use lightning::chain::keysinterface::EntropySource;
+use lightning::ln::channelmanager::{
+	ChannelDetails, CounterpartyForwardingInfo, PaymentId, RecipientOnionFields,
+};
+use lightning::routing::router::{
+	Path, PaymentParameters, Route, RouteHop, RouteParameters, Router as _,
+};
+use lightning::util::persist::KVStorePersister;
+use lightning_invoice::DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA;
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// The time, in seconds, after which the inbound side of a rebalance expires.
+const REBALANCE_PAYMENT_EXPIRY_SECS: u32 = 600;
+
+/// Moves liquidity between our own channels by routing a payment from one of our channels back
+/// to us via another one.
+pub struct Rebalancer<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	channel_manager: Arc<ChannelManager>,
+	router: Arc<Router>,
+	keys_manager: Arc<KeysManager>,
+	payment_store: Arc<PaymentStore<K>>,
+	logger: L,
+}
+
+impl<K: Deref, L: Deref> Rebalancer<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, router: Arc<Router>, keys_manager: Arc<KeysManager>,
+		payment_store: Arc<PaymentStore<K>>, logger: L,
+	) -> Self {
+		Self { channel_manager, router, keys_manager, payment_store, logger }
+	}
+
+	/// Sends `amount_msat` out via the channel with `outgoing_channel_id` and routes it back to
+	/// us via the channel with `incoming_channel_id`, paying at most `max_fee_msat` in fees.
+	///
+	/// The payment is recorded as a rebalance in the [`PaymentStore`]. Returns its id, which
+	/// will be reported in the corresponding [`Event::PaymentSuccessful`] or
+	/// [`Event::PaymentFailed`].
+	///
+	/// [`Event::PaymentSuccessful`]: crate::event::Event::PaymentSuccessful
+	/// [`Event::PaymentFailed`]: crate::event::Event::PaymentFailed
+	pub fn rebalance(
+		&self, outgoing_channel_id: [u8; 32], incoming_channel_id: [u8; 32], amount_msat: u64,
+		max_fee_msat: u64,
+	) -> Result<PaymentId, Error> {
+		if amount_msat == 0 {
+			return Err(Error::InvalidAmount);
+		}
+		if outgoing_channel_id == incoming_channel_id {
+			log_error!(
+				self.logger,
+				"Failed to rebalance: the outgoing and incoming channel must differ."
+			);
+			return Err(Error::RebalanceFailed);
+		}
+
+		let channels = self.channel_manager.list_usable_channels();
+		let find_channel = |channel_id: [u8; 32]| {
+			channels.iter().find(|c| c.channel_id == channel_id).ok_or_else(|| {
+				log_error!(
+					self.logger,
+					"Failed to rebalance: channel {} is not usable.",
+					hex_utils::to_string(&channel_id)
+				);
+				Error::RebalanceFailed
+			})
+		};
+		let outgoing_channel = find_channel(outgoing_channel_id)?;
+		let incoming_channel = find_channel(incoming_channel_id)?;
+
+		let route = self.find_circular_route(outgoing_channel, incoming_channel, amount_msat)?;
+		let fee_msat = route.get_total_fees();
+		if fee_msat > max_fee_msat {
+			log_error!(
+				self.logger,
+				"Failed to rebalance: route fee of {} msats exceeds the maximum of {} msats.",
+				fee_msat,
+				max_fee_msat
+			);
+			return Err(Error::RebalanceFailed);
+		}
+
+		let (payment_hash, payment_secret) = self
+			.channel_manager
+			.create_inbound_payment(Some(amount_msat), REBALANCE_PAYMENT_EXPIRY_SECS, None)
+			.map_err(|()| Error::RebalanceFailed)?;
+
+		// The inbound side is tracked via the hash, so we use a random id for the outbound side
+		// to keep them apart.
+		let payment_id = PaymentId(self.keys_manager.get_secure_random_bytes());
+		let mut payment = PaymentInfo::new(
+			payment_id,
+			Some(payment_hash),
+			PaymentDirection::Outbound,
+			Some(amount_msat),
+			PaymentStatus::InFlight,
+		);
+		payment.secret = Some(payment_secret);
+		payment.fee_msat = Some(fee_msat);
+		payment.rebalance = true;
+		self.payment_store.insert(payment)?;
+
+		match self.channel_manager.send_payment_with_route(
+			&route,
+			payment_hash,
+			RecipientOnionFields::secret_only(payment_secret),
+			payment_id,
+		) {
+			Ok(()) => {
+				log_info!(
+					self.logger,
+					"Initiated rebalancing {} msats from channel {} to channel {}, paying {} msats in fees.",
+					amount_msat,
+					hex_utils::to_string(&outgoing_channel_id),
+					hex_utils::to_string(&incoming_channel_id),
+					fee_msat,
+				);
+				Ok(payment_id)
+			}
+			Err(e) => {
+				log_error!(self.logger, "Failed to send rebalance payment: {:?}", e);
+				self.payment_store.update(&payment_id, |p| p.set_status(PaymentStatus::Failed))?;
+				Err(Error::PaymentSendingFailed)
+			}
+		}
+	}
+
+	// The router can't find routes to ourselves, so we find a route to the counterparty of the
+	// incoming channel that starts with the outgoing channel, and append the final hop back to
+	// ourselves.
+	fn find_circular_route(
+		&self, outgoing_channel: &ChannelDetails, incoming_channel: &ChannelDetails,
+		amount_msat: u64,
+	) -> Result<Route, Error> {
+		let (incoming_scid, forwarding_info) = match (
+			incoming_channel.short_channel_id,
+			&incoming_channel.counterparty.forwarding_info,
+		) {
+			(Some(scid), Some(forwarding_info)) => (scid, forwarding_info),
+			_ => {
+				log_error!(
+						self.logger,
+						"Failed to rebalance: the counterparty of the incoming channel didn't announce its forwarding policy yet."
+					);
+				return Err(Error::RebalanceFailed);
+			}
+		};
+
+		let last_hop_fee_msat = forwarding_fee_msat(forwarding_info, amount_msat);
+		let last_hop_node_id = incoming_channel.counterparty.node_id;
+		let route_params = RouteParameters {
+			payment_params: PaymentParameters::from_node_id(
+				last_hop_node_id,
+				forwarding_info.cltv_expiry_delta as u32,
+			),
+			final_value_msat: amount_msat + last_hop_fee_msat,
+		};
+		let mut route = self
+			.router
+			.find_route(
+				&self.channel_manager.get_our_node_id(),
+				&route_params,
+				Some(&[outgoing_channel]),
+				self.channel_manager.compute_inflight_htlcs(),
+			)
+			.map_err(|e| {
+				log_error!(self.logger, "Failed to find rebalance route: {}", e.err);
+				Error::RouteNotFound
+			})?;
+
+		if route.paths.len() != 1 {
+			log_error!(self.logger, "Failed to rebalance: multi-path routes aren't supported.");
+			return Err(Error::RouteNotFound);
+		}
+
+		let final_hop = RouteHop {
+			pubkey: self.channel_manager.get_our_node_id(),
+			node_features: self.channel_manager.node_features(),
+			short_channel_id: incoming_scid,
+			channel_features: self.channel_manager.channel_features(),
+			fee_msat: amount_msat,
+			cltv_expiry_delta: DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA as u32,
+		};
+		splice_final_hop(&mut route.paths[0], forwarding_info, final_hop);
+		Ok(route)
+	}
+}
+
+// The fee the counterparty of the incoming channel charges for forwarding `amount_msat` to us.
+fn forwarding_fee_msat(forwarding_info: &CounterpartyForwardingInfo, amount_msat: u64) -> u64 {
+	forwarding_info.fee_base_msat as u64
+		+ amount_msat * forwarding_info.fee_proportional_millionths as u64 / 1_000_000
+}
+
+// Appends `final_hop` back to us to a path ending at the counterparty of the incoming channel.
+//
+// That counterparty was the recipient so far, but now forwards the payment, so it takes its
+// forwarding fee instead of the full amount and adds its forwarding CLTV delta instead of the
+// final one.
+fn splice_final_hop(
+	path: &mut Path, forwarding_info: &CounterpartyForwardingInfo, final_hop: RouteHop,
+) {
+	if let Some(last_hop) = path.hops.last_mut() {
+		last_hop.fee_msat = forwarding_fee_msat(forwarding_info, final_hop.fee_msat);
+		last_hop.cltv_expiry_delta = forwarding_info.cltv_expiry_delta as u32;
+	}
+	path.hops.push(final_hop);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+	fn hop(node: u8, short_channel_id: u64, fee_msat: u64, cltv_expiry_delta: u32) -> RouteHop {
+		let secp_ctx = Secp256k1::new();
+		RouteHop {
+			pubkey: PublicKey::from_secret_key(
+				&secp_ctx,
+				&SecretKey::from_slice(&[node; 32]).unwrap(),
+			),
+			node_features: NodeFeatures::empty(),
+			short_channel_id,
+			channel_features: ChannelFeatures::empty(),
+			fee_msat,
+			cltv_expiry_delta,
+		}
+	}
+
+	#[test]
+	fn final_hop_is_spliced_into_path() {
+		let forwarding_info = CounterpartyForwardingInfo {
+			fee_base_msat: 1_000,
+			fee_proportional_millionths: 100,
+			cltv_expiry_delta: 72,
+		};
+		let amount_msat = 10_000_000;
+		let last_hop_fee_msat = 2_000;
+		assert_eq!(forwarding_fee_msat(&forwarding_info, amount_msat), last_hop_fee_msat);
+
+		// We pay 500 msats to the first hop for forwarding to the counterparty of the incoming
+		// channel, which is the recipient of the path found by the router.
+		let mut path = Path {
+			hops: vec![hop(2, 1, 500, 40), hop(3, 2, amount_msat + last_hop_fee_msat, 144)],
+			blinded_tail: None,
+		};
+		let final_hop = hop(1, 3, amount_msat, DEFAULT_MIN_FINAL_CLTV_EXPIRY_DELTA as u32);
+		splice_final_hop(&mut path, &forwarding_info, final_hop.clone());
+
+		assert_eq!(path.hops.len(), 3);
+		assert_eq!(path.hops[0], hop(2, 1, 500, 40));
+		assert_eq!(path.hops[1], hop(3, 2, last_hop_fee_msat, 72));
+		assert_eq!(path.hops[2], final_hop);
+		assert_eq!(path.final_value_msat(), amount_msat);
+		assert_eq!(path.fee_msat(), 500 + last_hop_fee_msat);
+	}
+}
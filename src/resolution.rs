@@ -0,0 +1,470 @@
+use crate::event::{ClosureReason, Event, EventQueue};
+use crate::hex_utils;
+use crate::logger::{log_error, log_given_level, log_info, log_internal, Logger};
+use crate::{ChainMonitor, Error};
+
+use lightning::chain::channelmonitor::Balance;
+use lightning::chain::transaction::TransactionData;
+use lightning::chain::Confirm;
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
+
+use bitcoin::{BlockHeader, Txid};
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+/// The closed channels will be persisted under this key.
+pub(crate) const CLOSED_CHANNELS_PERSISTENCE_KEY: &str = "closed_channels";
+
+/// The number of blocks for which we keep tracking channels after they were resolved.
+const RESOLVED_CHANNEL_RETENTION_BLOCKS: u32 = 1008;
+
+/// Details of a channel that has been closed, tracked until all of its funds have been resolved
+/// on-chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosedChannelDetails {
+	/// The `channel_id` of the channel.
+	pub channel_id: [u8; 32],
+	/// The `user_channel_id` of the channel.
+	pub user_channel_id: u128,
+	/// The reason why the channel was closed.
+	pub reason: ClosureReason,
+	/// The current state of the on-chain resolution of the channel.
+	pub status: ResolutionStatus,
+	/// The block height at which we first saw the channel resolved, if it is.
+	pub resolved_height: Option<u32>,
+}
+
+/// The state of the on-chain resolution of a closed channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolutionStatus {
+	/// Some of the channel's funds are still waiting to be claimed on-chain.
+	Pending {
+		/// The amount, in satoshis, we will be able to claim once the closing transaction
+		/// confirmed.
+		awaiting_close_sats: u64,
+		/// The amount, in satoshis, which is encumbered by a timelock.
+		timelocked_sats: u64,
+		/// The block height at which the last of the timelocked funds become spendable, if any.
+		spendable_height: Option<u32>,
+		/// The amount, in satoshis, locked in HTLCs which still need to be resolved on-chain.
+		onchain_htlc_sats: u64,
+		/// The number of HTLCs which still need to be resolved on-chain.
+		num_onchain_htlcs: u32,
+		/// The amount, in satoshis, of revoked outputs the counterparty broadcast which we are
+		/// still contesting on-chain.
+		revoked_output_sats: u64,
+	},
+	/// All funds have been claimed on-chain and swept to the on-chain wallet.
+	Resolved,
+	/// The channel was closed before its funding transaction was signed, so there were never any
+	/// funds to resolve.
+	NeverFunded,
+}
+
+impl ResolutionStatus {
+	fn is_final(&self) -> bool {
+		matches!(self, Self::Resolved | Self::NeverFunded)
+	}
+
+	fn from_balances(balances: &[Balance]) -> Self {
+		if balances.is_empty() {
+			return Self::Resolved;
+		}
+
+		let mut awaiting_close_sats = 0;
+		let mut timelocked_sats = 0;
+		let mut spendable_height = None;
+		let mut onchain_htlc_sats = 0;
+		let mut num_onchain_htlcs = 0;
+		let mut revoked_output_sats = 0;
+		for balance in balances {
+			match balance {
+				Balance::ClaimableOnChannelClose { claimable_amount_satoshis } => {
+					awaiting_close_sats += claimable_amount_satoshis;
+				}
+				Balance::ClaimableAwaitingConfirmations {
+					claimable_amount_satoshis,
+					confirmation_height,
+				} => {
+					timelocked_sats += claimable_amount_satoshis;
+					spendable_height = spendable_height.max(Some(*confirmation_height));
+				}
+				Balance::ContentiousClaimable { claimable_amount_satoshis, .. }
+				| Balance::MaybeTimeoutClaimableHTLC { claimable_amount_satoshis, .. }
+				| Balance::MaybePreimageClaimableHTLC { claimable_amount_satoshis, .. } => {
+					onchain_htlc_sats += claimable_amount_satoshis;
+					num_onchain_htlcs += 1;
+				}
+				Balance::CounterpartyRevokedOutputClaimable { claimable_amount_satoshis } => {
+					revoked_output_sats += claimable_amount_satoshis;
+				}
+			}
+		}
+
+		Self::Pending {
+			awaiting_close_sats,
+			timelocked_sats,
+			spendable_height,
+			onchain_htlc_sats,
+			num_onchain_htlcs,
+			revoked_output_sats,
+		}
+	}
+}
+
+impl Readable for ResolutionStatus {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		match Readable::read(reader)? {
+			0u8 => {
+				let awaiting_close_sats: u64 = Readable::read(reader)?;
+				let timelocked_sats: u64 = Readable::read(reader)?;
+				let spendable_height: Option<u32> = Readable::read(reader)?;
+				let onchain_htlc_sats: u64 = Readable::read(reader)?;
+				let num_onchain_htlcs: u32 = Readable::read(reader)?;
+				let revoked_output_sats: u64 = Readable::read(reader)?;
+				Ok(Self::Pending {
+					awaiting_close_sats,
+					timelocked_sats,
+					spendable_height,
+					onchain_htlc_sats,
+					num_onchain_htlcs,
+					revoked_output_sats,
+				})
+			}
+			1u8 => Ok(Self::Resolved),
+			2u8 => Ok(Self::NeverFunded),
+			_ => Err(lightning::ln::msgs::DecodeError::InvalidValue),
+		}
+	}
+}
+
+impl Writeable for ResolutionStatus {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		match self {
+			Self::Pending {
+				awaiting_close_sats,
+				timelocked_sats,
+				spendable_height,
+				onchain_htlc_sats,
+				num_onchain_htlcs,
+				revoked_output_sats,
+			} => {
+				0u8.write(writer)?;
+				awaiting_close_sats.write(writer)?;
+				timelocked_sats.write(writer)?;
+				spendable_height.write(writer)?;
+				onchain_htlc_sats.write(writer)?;
+				num_onchain_htlcs.write(writer)?;
+				revoked_output_sats.write(writer)?;
+			}
+			Self::Resolved => 1u8.write(writer)?,
+			Self::NeverFunded => 2u8.write(writer)?,
+		}
+		Ok(())
+	}
+}
+
+impl Readable for ClosedChannelDetails {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let channel_id: [u8; 32] = Readable::read(reader)?;
+		let user_channel_id: u128 = Readable::read(reader)?;
+		let reason: ClosureReason = Readable::read(reader)?;
+		let status: ResolutionStatus = Readable::read(reader)?;
+		let resolved_height: Option<u32> = Readable::read(reader)?;
+		Ok(Self { channel_id, user_channel_id, reason, status, resolved_height })
+	}
+}
+
+impl Writeable for ClosedChannelDetails {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		self.channel_id.write(writer)?;
+		self.user_channel_id.write(writer)?;
+		self.reason.write(writer)?;
+		self.status.write(writer)?;
+		self.resolved_height.write(writer)?;
+		Ok(())
+	}
+}
+
+/// Tracks closed channels until all of their funds have been resolved on-chain.
+///
+/// The resolution status is updated whenever a new block is connected, emitting an
+/// [`Event::ChannelResolved`] for each channel that became resolved. Resolved channels are kept
+/// around for a while and then pruned.
+pub struct ResolutionTracker<K: Deref, L: Deref>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	closed_channels: Mutex<HashMap<[u8; 32], ClosedChannelDetails>>,
+	best_block_height: Mutex<Option<u32>>,
+	chain_monitor: Arc<ChainMonitor>,
+	event_queue: Arc<EventQueue<K>>,
+	persister: K,
+	logger: L,
+}
+
+impl<K: Deref, L: Deref> ResolutionTracker<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	pub(crate) fn new(
+		chain_monitor: Arc<ChainMonitor>, event_queue: Arc<EventQueue<K>>, persister: K, logger: L,
+	) -> Self {
+		let closed_channels = Mutex::new(HashMap::new());
+		let best_block_height = Mutex::new(None);
+		Self { closed_channels, best_block_height, chain_monitor, event_queue, persister, logger }
+	}
+
+	pub(crate) fn channel_closed(
+		&self, channel_id: [u8; 32], user_channel_id: u128, reason: ClosureReason,
+	) -> Result<(), Error> {
+		let mut locked_channels = self.closed_channels.lock().unwrap();
+		let status = ResolutionStatus::Pending {
+			awaiting_close_sats: 0,
+			timelocked_sats: 0,
+			spendable_height: None,
+			onchain_htlc_sats: 0,
+			num_onchain_htlcs: 0,
+			revoked_output_sats: 0,
+		};
+		locked_channels.insert(
+			channel_id,
+			ClosedChannelDetails {
+				channel_id,
+				user_channel_id,
+				reason,
+				status,
+				resolved_height: None,
+			},
+		);
+		self.persist_channels(&locked_channels)
+	}
+
+	/// Updates the resolution status of all tracked channels from the balances reported by their
+	/// channel monitors, and prunes channels resolved more than
+	/// [`RESOLVED_CHANNEL_RETENTION_BLOCKS`] ago.
+	///
+	/// Emits an [`Event::ChannelResolved`] for each channel that was resolved since the last
+	/// update.
+	pub(crate) fn update(&self) -> Result<(), Error> {
+		let mut monitor_balances = HashMap::new();
+		for funding_txo in self.chain_monitor.list_monitors() {
+			if let Ok(monitor) = self.chain_monitor.get_monitor(funding_txo) {
+				monitor_balances
+					.insert(funding_txo.to_channel_id(), monitor.get_claimable_balances());
+			}
+		}
+		let best_block_height = *self.best_block_height.lock().unwrap();
+
+		let mut locked_channels = self.closed_channels.lock().unwrap();
+		let mut updated = false;
+		let mut resolved = Vec::new();
+		for (channel_id, details) in locked_channels.iter_mut() {
+			if !details.status.is_final() {
+				// Monitors are only created once the funding transaction is signed, so channels
+				// closed before that never had any funds at stake.
+				let new_status = match monitor_balances.get(channel_id) {
+					Some(balances) => ResolutionStatus::from_balances(balances),
+					None => ResolutionStatus::NeverFunded,
+				};
+				if new_status == details.status {
+					continue;
+				}
+				if new_status == ResolutionStatus::Resolved {
+					resolved.push((details.channel_id, details.user_channel_id));
+				}
+				details.status = new_status;
+				updated = true;
+			}
+
+			if details.status.is_final() && details.resolved_height.is_none() {
+				details.resolved_height = best_block_height;
+				updated |= best_block_height.is_some();
+			}
+		}
+
+		if let Some(height) = best_block_height {
+			let num_channels = locked_channels.len();
+			locked_channels.retain(|_, d| {
+				d.resolved_height
+					.map_or(true, |h| height < h.saturating_add(RESOLVED_CHANNEL_RETENTION_BLOCKS))
+			});
+			updated |= locked_channels.len() != num_channels;
+		}
+
+		if updated {
+			self.persist_channels(&locked_channels)?;
+		}
+		drop(locked_channels);
+
+		for (channel_id, user_channel_id) in resolved {
+			log_info!(
+				self.logger,
+				"Channel {} has been fully resolved on-chain.",
+				hex_utils::to_string(&channel_id),
+			);
+			self.event_queue.add_event(Event::ChannelResolved { channel_id, user_channel_id })?;
+		}
+		Ok(())
+	}
+
+	/// Returns the details of all tracked closed channels.
+	pub fn list_closed_channels(&self) -> Vec<ClosedChannelDetails> {
+		self.closed_channels.lock().unwrap().values().cloned().collect()
+	}
+
+	/// Returns the details of all closed channels which are not yet fully resolved.
+	pub fn list_pending_resolutions(&self) -> Vec<ClosedChannelDetails> {
+		self.closed_channels
+			.lock()
+			.unwrap()
+			.values()
+			.filter(|d| !d.status.is_final())
+			.cloned()
+			.collect()
+	}
+
+	fn persist_channels(
+		&self, locked_channels: &HashMap<[u8; 32], ClosedChannelDetails>,
+	) -> Result<(), Error> {
+		self.persister
+			.persist(CLOSED_CHANNELS_PERSISTENCE_KEY, &ClosedChannelsSerWrapper(locked_channels))
+			.map_err(|_| Error::PersistenceFailed)?;
+		Ok(())
+	}
+}
+
+impl<K: Deref, L: Deref> Confirm for ResolutionTracker<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	fn transactions_confirmed(
+		&self, _header: &BlockHeader, _txdata: &TransactionData, _height: u32,
+	) {
+	}
+
+	fn transaction_unconfirmed(&self, _txid: &Txid) {}
+
+	fn best_block_updated(&self, _header: &BlockHeader, height: u32) {
+		*self.best_block_height.lock().unwrap() = Some(height);
+		if let Err(e) = self.update() {
+			log_error!(self.logger, "Failed to update channel resolutions: {}", e);
+		}
+	}
+
+	fn get_relevant_txids(&self) -> Vec<Txid> {
+		Vec::new()
+	}
+}
+
+impl<K: Deref, L: Deref> ReadableArgs<(Arc<ChainMonitor>, Arc<EventQueue<K>>, K, L)>
+	for ResolutionTracker<K, L>
+where
+	K::Target: KVStorePersister,
+	L::Target: Logger,
+{
+	#[inline]
+	fn read<R: lightning::io::Read>(
+		reader: &mut R, args: (Arc<ChainMonitor>, Arc<EventQueue<K>>, K, L),
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let (chain_monitor, event_queue, persister, logger) = args;
+		let read_channels: ClosedChannelsDeserWrapper = Readable::read(reader)?;
+		let closed_channels = Mutex::new(read_channels.0);
+		let best_block_height = Mutex::new(None);
+		Ok(Self {
+			closed_channels,
+			best_block_height,
+			chain_monitor,
+			event_queue,
+			persister,
+			logger,
+		})
+	}
+}
+
+struct ClosedChannelsDeserWrapper(HashMap<[u8; 32], ClosedChannelDetails>);
+
+impl Readable for ClosedChannelsDeserWrapper {
+	fn read<R: lightning::io::Read>(
+		reader: &mut R,
+	) -> Result<Self, lightning::ln::msgs::DecodeError> {
+		let len: u64 = Readable::read(reader)?;
+		let mut channels = HashMap::with_capacity(len as usize);
+		for _ in 0..len {
+			let details: ClosedChannelDetails = Readable::read(reader)?;
+			channels.insert(details.channel_id, details);
+		}
+		Ok(Self(channels))
+	}
+}
+
+struct ClosedChannelsSerWrapper<'a>(&'a HashMap<[u8; 32], ClosedChannelDetails>);
+
+impl Writeable for ClosedChannelsSerWrapper<'_> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), lightning::io::Error> {
+		(self.0.len() as u64).write(writer)?;
+		for details in self.0.values() {
+			details.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn resolution_status_from_balances() {
+		assert_eq!(ResolutionStatus::from_balances(&[]), ResolutionStatus::Resolved);
+
+		let balances = vec![
+			Balance::ClaimableAwaitingConfirmations {
+				claimable_amount_satoshis: 1000,
+				confirmation_height: 120,
+			},
+			Balance::ClaimableAwaitingConfirmations {
+				claimable_amount_satoshis: 500,
+				confirmation_height: 144,
+			},
+			Balance::MaybeTimeoutClaimableHTLC {
+				claimable_amount_satoshis: 200,
+				claimable_height: 130,
+			},
+		];
+		let expected_status = ResolutionStatus::Pending {
+			awaiting_close_sats: 0,
+			timelocked_sats: 1500,
+			spendable_height: Some(144),
+			onchain_htlc_sats: 200,
+			num_onchain_htlcs: 1,
+			revoked_output_sats: 0,
+		};
+		assert_eq!(ResolutionStatus::from_balances(&balances), expected_status);
+
+		// Revoked outputs are contested on-chain, rather than awaiting the closing transaction.
+		let balances = vec![
+			Balance::ClaimableOnChannelClose { claimable_amount_satoshis: 700 },
+			Balance::CounterpartyRevokedOutputClaimable { claimable_amount_satoshis: 3000 },
+		];
+		let expected_status = ResolutionStatus::Pending {
+			awaiting_close_sats: 700,
+			timelocked_sats: 0,
+			spendable_height: None,
+			onchain_htlc_sats: 0,
+			num_onchain_htlcs: 0,
+			revoked_output_sats: 3000,
+		};
+		assert_eq!(ResolutionStatus::from_balances(&balances), expected_status);
+	}
+}
@@ -0,0 +1,308 @@
+use crate::logger::{log_error, log_given_level, log_info, log_internal, FilesystemLogger, Logger};
+use crate::{Error, NetworkGraph, Router, Scorer};
+
+use lightning::routing::gossip::NodeId;
+use lightning::routing::router::Path;
+use lightning::routing::scoring::{
+	ProbabilisticScoringDecayParameters, ProbabilisticScoringFeeParameters, Score,
+};
+use lightning::util::persist::KVStorePersister;
+use lightning::util::ser::{ReadableArgs, Writeable};
+
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The routing scorer will be persisted under this key.
+pub(crate) const SCORER_PERSISTENCE_KEY: &str = "scorer";
+
+/// The minimum time between two persistences of the scorer triggered by scorer updates.
+const SCORER_PERSISTENCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Parameters tuning how the routing scorer penalizes channels and how fast it forgets what it
+/// learned about their liquidity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScoringParameters {
+	/// A fixed penalty, in thousandths of a satoshi, applied to each channel.
+	pub base_penalty_msat: u64,
+	/// A penalty, in thousandths of a satoshi, per 2^30 thousandths of a satoshi of payment
+	/// amount, applied to each channel.
+	pub base_penalty_amount_multiplier_msat: u64,
+	/// A penalty, in thousandths of a satoshi, scaled by the negative log10 of the estimated
+	/// probability the channel can route the payment.
+	pub liquidity_penalty_multiplier_msat: u64,
+	/// Like `liquidity_penalty_multiplier_msat`, but additionally scaled by the payment amount.
+	pub liquidity_penalty_amount_multiplier_msat: u64,
+	/// The time after which half of what we learned about a channel's liquidity is forgotten.
+	pub liquidity_offset_half_life: Duration,
+}
+
+impl Default for ScoringParameters {
+	fn default() -> Self {
+		let fee_params = ProbabilisticScoringFeeParameters::default();
+		let decay_params = ProbabilisticScoringDecayParameters::default();
+		Self {
+			base_penalty_msat: fee_params.base_penalty_msat,
+			base_penalty_amount_multiplier_msat: fee_params.base_penalty_amount_multiplier_msat,
+			liquidity_penalty_multiplier_msat: fee_params.liquidity_penalty_multiplier_msat,
+			liquidity_penalty_amount_multiplier_msat: fee_params
+				.liquidity_penalty_amount_multiplier_msat,
+			liquidity_offset_half_life: decay_params.liquidity_offset_half_life,
+		}
+	}
+}
+
+impl ScoringParameters {
+	pub(crate) fn fee_params(&self) -> ProbabilisticScoringFeeParameters {
+		ProbabilisticScoringFeeParameters {
+			base_penalty_msat: self.base_penalty_msat,
+			base_penalty_amount_multiplier_msat: self.base_penalty_amount_multiplier_msat,
+			liquidity_penalty_multiplier_msat: self.liquidity_penalty_multiplier_msat,
+			liquidity_penalty_amount_multiplier_msat: self.liquidity_penalty_amount_multiplier_msat,
+			..Default::default()
+		}
+	}
+
+	pub(crate) fn decay_params(&self) -> ProbabilisticScoringDecayParameters {
+		ProbabilisticScoringDecayParameters {
+			liquidity_offset_half_life: self.liquidity_offset_half_life,
+			..Default::default()
+		}
+	}
+}
+
+/// Maintains the probabilistic scorer used for routing, which learns about the liquidity of
+/// channels from the outcome of payments and probes.
+///
+/// The scorer state is persisted and may be exported and imported, e.g., to share what one node
+/// learned with other nodes. It is persisted one last time when the scorer is dropped.
+pub struct RoutingScorer<K: Deref>
+where
+	K::Target: KVStorePersister,
+{
+	scorer: Arc<Mutex<Scorer>>,
+	router: Arc<Router>,
+	network_graph: Arc<NetworkGraph>,
+	decay_params: ProbabilisticScoringDecayParameters,
+	last_persisted: Mutex<Instant>,
+	persister: K,
+	logger: Arc<FilesystemLogger>,
+}
+
+impl<K: Deref> RoutingScorer<K>
+where
+	K::Target: KVStorePersister,
+{
+	pub(crate) fn new(
+		scorer: Arc<Mutex<Scorer>>, network_graph: Arc<NetworkGraph>, params: &ScoringParameters,
+		random_seed_bytes: [u8; 32], persister: K, logger: Arc<FilesystemLogger>,
+	) -> Self {
+		let router = Arc::new(Router::new(
+			Arc::clone(&network_graph),
+			Arc::clone(&logger),
+			random_seed_bytes,
+			Arc::clone(&scorer),
+			params.fee_params(),
+		));
+		let decay_params = params.decay_params();
+		let last_persisted = Mutex::new(Instant::now());
+		Self { scorer, router, network_graph, decay_params, last_persisted, persister, logger }
+	}
+
+	/// Returns the router, which finds routes penalizing channels as configured by the
+	/// [`ScoringParameters`].
+	pub(crate) fn router(&self) -> Arc<Router> {
+		Arc::clone(&self.router)
+	}
+
+	pub(crate) fn payment_path_successful(&self, path: &Path) {
+		self.scorer.lock().unwrap().payment_path_successful(path);
+		self.scorer_updated();
+	}
+
+	pub(crate) fn payment_path_failed(&self, path: &Path, short_channel_id: u64) {
+		self.scorer.lock().unwrap().payment_path_failed(path, short_channel_id);
+		self.scorer_updated();
+	}
+
+	pub(crate) fn probe_successful(&self, path: &Path) {
+		self.scorer.lock().unwrap().probe_successful(path);
+		self.scorer_updated();
+	}
+
+	pub(crate) fn probe_failed(&self, path: &Path, short_channel_id: u64) {
+		self.scorer.lock().unwrap().probe_failed(path, short_channel_id);
+		self.scorer_updated();
+	}
+
+	/// Returns the lower and upper bound, in thousandths of a satoshi, of the liquidity we
+	/// estimate the given channel has available towards the node `target`, if we learned
+	/// anything about it.
+	pub fn estimated_channel_liquidity_range(
+		&self, short_channel_id: u64, target: &NodeId,
+	) -> Option<(u64, u64)> {
+		self.scorer.lock().unwrap().estimated_channel_liquidity_range(short_channel_id, target)
+	}
+
+	/// Exports the current scorer state in serialized form.
+	pub fn export_scorer(&self) -> Vec<u8> {
+		self.scorer.lock().unwrap().encode()
+	}
+
+	/// Replaces the current scorer state with the given one, as previously returned by
+	/// [`export_scorer`].
+	///
+	/// Liquidity estimates for channels we don't know about are dropped.
+	///
+	/// [`export_scorer`]: Self::export_scorer
+	pub fn import_scorer(&self, data: &[u8]) -> Result<(), Error> {
+		let args = (self.decay_params, Arc::clone(&self.network_graph), Arc::clone(&self.logger));
+		let imported_scorer =
+			Scorer::read(&mut lightning::io::Cursor::new(data), args).map_err(|e| {
+				log_error!(self.logger, "Failed to decode imported scorer: {}", e);
+				Error::InvalidScorerData
+			})?;
+
+		let mut locked_scorer = self.scorer.lock().unwrap();
+		*locked_scorer = imported_scorer;
+		self.persist_scorer(&locked_scorer)?;
+		log_info!(self.logger, "Imported scorer state of {} bytes.", data.len());
+		Ok(())
+	}
+
+	/// Persists the scorer, regardless of when it was last persisted.
+	pub(crate) fn persist(&self) -> Result<(), Error> {
+		let locked_scorer = self.scorer.lock().unwrap();
+		self.persist_scorer(&locked_scorer)
+	}
+
+	// Persists the scorer if we haven't done so for a while. The scorer may get updated many
+	// times in a short period, so we don't want to write it out each time.
+	fn scorer_updated(&self) {
+		if self.last_persisted.lock().unwrap().elapsed() < SCORER_PERSISTENCE_INTERVAL {
+			return;
+		}
+
+		if let Err(e) = self.persist() {
+			log_error!(self.logger, "Failed to persist scorer: {}", e);
+		}
+	}
+
+	fn persist_scorer(&self, locked_scorer: &Scorer) -> Result<(), Error> {
+		self.persister
+			.persist(SCORER_PERSISTENCE_KEY, locked_scorer)
+			.map_err(|_| Error::PersistenceFailed)?;
+		*self.last_persisted.lock().unwrap() = Instant::now();
+		Ok(())
+	}
+}
+
+impl<K: Deref> Drop for RoutingScorer<K>
+where
+	K::Target: KVStorePersister,
+{
+	fn drop(&mut self) {
+		// Updates since the last persistence would otherwise be lost on shutdown.
+		if let Err(e) = self.persist() {
+			log_error!(self.logger, "Failed to persist scorer: {}", e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::test_utils::{
+		add_test_channel, test_filesystem_logger, test_node_id, TestPersister,
+	};
+
+	use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+	use lightning::routing::router::RouteHop;
+
+	use bitcoin::Network;
+
+	fn test_scorer(
+		network_graph: &Arc<NetworkGraph>, persister: Arc<TestPersister>,
+		logger: &Arc<FilesystemLogger>,
+	) -> RoutingScorer<Arc<TestPersister>> {
+		let params = ScoringParameters::default();
+		let scorer = Arc::new(Mutex::new(Scorer::new(
+			params.decay_params(),
+			Arc::clone(network_graph),
+			Arc::clone(logger),
+		)));
+		RoutingScorer::new(
+			scorer,
+			Arc::clone(network_graph),
+			&params,
+			[42u8; 32],
+			persister,
+			Arc::clone(logger),
+		)
+	}
+
+	// Returns a path from node 1 to node 2 over the channel with the given `short_channel_id`.
+	fn test_path(short_channel_id: u64) -> Path {
+		let hop = RouteHop {
+			pubkey: test_node_id(2),
+			node_features: NodeFeatures::empty(),
+			short_channel_id,
+			channel_features: ChannelFeatures::empty(),
+			fee_msat: 50_000_000,
+			cltv_expiry_delta: 40,
+		};
+		Path { hops: vec![hop], blinded_tail: None }
+	}
+
+	#[test]
+	fn exported_scorer_can_be_imported() {
+		let logger = test_filesystem_logger("exported_scorer_can_be_imported");
+		let network_graph = Arc::new(NetworkGraph::new(Network::Regtest, Arc::clone(&logger)));
+		add_test_channel(&network_graph, 42, &test_node_id(1), &test_node_id(2));
+		let target = NodeId::from_pubkey(&test_node_id(2));
+
+		let persister = Arc::new(TestPersister::new());
+		let scorer = test_scorer(&network_graph, Arc::clone(&persister), &logger);
+		assert_eq!(scorer.estimated_channel_liquidity_range(42, &target), None);
+		scorer.payment_path_failed(&test_path(42), 42);
+		let liquidity_range = scorer.estimated_channel_liquidity_range(42, &target);
+		assert!(liquidity_range.is_some());
+
+		let imported_persister = Arc::new(TestPersister::new());
+		let imported_scorer = test_scorer(&network_graph, Arc::clone(&imported_persister), &logger);
+		assert_eq!(imported_scorer.estimated_channel_liquidity_range(42, &target), None);
+		imported_scorer.import_scorer(&scorer.export_scorer()).unwrap();
+		assert!(imported_persister.get_and_clear_pending_persist());
+		assert_eq!(imported_scorer.estimated_channel_liquidity_range(42, &target), liquidity_range);
+
+		assert!(matches!(imported_scorer.import_scorer(&[1, 2, 3]), Err(Error::InvalidScorerData)));
+		assert!(!imported_persister.get_and_clear_pending_persist());
+		assert_eq!(imported_scorer.estimated_channel_liquidity_range(42, &target), liquidity_range);
+	}
+
+	#[test]
+	fn scorer_updates_are_persisted_throttled_and_on_drop() {
+		let logger = test_filesystem_logger("scorer_updates_are_persisted_throttled_and_on_drop");
+		let network_graph = Arc::new(NetworkGraph::new(Network::Regtest, Arc::clone(&logger)));
+		add_test_channel(&network_graph, 42, &test_node_id(1), &test_node_id(2));
+
+		let persister = Arc::new(TestPersister::new());
+		let scorer = test_scorer(&network_graph, Arc::clone(&persister), &logger);
+
+		// Updates within the persistence interval aren't persisted right away.
+		scorer.payment_path_failed(&test_path(42), 42);
+		scorer.probe_successful(&test_path(42));
+		assert!(!persister.get_and_clear_pending_persist());
+
+		*scorer.last_persisted.lock().unwrap() = Instant::now() - SCORER_PERSISTENCE_INTERVAL;
+		scorer.payment_path_successful(&test_path(42));
+		assert!(persister.get_and_clear_pending_persist());
+
+		// The interval starts over with each persistence.
+		scorer.probe_failed(&test_path(42), 42);
+		assert!(!persister.get_and_clear_pending_persist());
+
+		drop(scorer);
+		assert!(persister.get_and_clear_pending_persist());
+	}
+}
@@ -1,7 +1,20 @@
+use crate::logger::FilesystemLogger;
+use crate::NetworkGraph;
+
+use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+use lightning::ln::msgs::{NetAddress, UnsignedChannelUpdate, UnsignedNodeAnnouncement};
+use lightning::routing::gossip::{NodeAlias, NodeId};
+use lightning::util::logger::{Logger, Record};
 use lightning::util::persist::KVStorePersister;
 use lightning::util::ser::Writeable;
 
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::Network;
+
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub(crate) struct TestPersister {
 	pending_persist: AtomicBool,
@@ -24,3 +37,69 @@ impl KVStorePersister for TestPersister {
 		Ok(())
 	}
 }
+
+pub(crate) struct TestLogger;
+
+impl Logger for TestLogger {
+	fn log(&self, _record: &Record) {}
+}
+
+pub(crate) fn test_filesystem_logger(test_name: &str) -> Arc<FilesystemLogger> {
+	let file_path = std::env::temp_dir().join("ldk_lite_tests").join(format!("{}.log", test_name));
+	Arc::new(FilesystemLogger::new(file_path.to_string_lossy().to_string()))
+}
+
+pub(crate) fn test_node_id(seed: u8) -> PublicKey {
+	let secp_ctx = Secp256k1::new();
+	PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[seed; 32]).unwrap())
+}
+
+// Adds a channel between the given nodes to the graph, with the same policy in both directions.
+pub(crate) fn add_test_channel(
+	network_graph: &NetworkGraph, short_channel_id: u64, node_one: &PublicKey, node_two: &PublicKey,
+) {
+	let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+	network_graph
+		.add_channel_from_partial_announcement(
+			short_channel_id,
+			timestamp as u64,
+			ChannelFeatures::empty(),
+			*node_one,
+			*node_two,
+		)
+		.unwrap();
+	for direction in [0u8, 1u8].iter() {
+		let update = UnsignedChannelUpdate {
+			chain_hash: genesis_block(Network::Regtest).header.block_hash(),
+			short_channel_id,
+			timestamp,
+			flags: *direction,
+			cltv_expiry_delta: 40,
+			htlc_minimum_msat: 1_000,
+			htlc_maximum_msat: 100_000_000,
+			fee_base_msat: 1_000,
+			fee_proportional_millionths: 100,
+			excess_data: Vec::new(),
+		};
+		network_graph.update_channel_unsigned(&update).unwrap();
+	}
+}
+
+// Announces the given node, which needs to have a channel in the graph already.
+pub(crate) fn announce_test_node(
+	network_graph: &NetworkGraph, node_id: &PublicKey, alias: &str, addresses: Vec<NetAddress>,
+) {
+	let mut alias_bytes = [0u8; 32];
+	alias_bytes[..alias.len()].copy_from_slice(alias.as_bytes());
+	let announcement = UnsignedNodeAnnouncement {
+		features: NodeFeatures::empty(),
+		timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32,
+		node_id: NodeId::from_pubkey(node_id),
+		rgb: [0; 3],
+		alias: NodeAlias(alias_bytes),
+		addresses,
+		excess_address_data: Vec::new(),
+		excess_data: Vec::new(),
+	};
+	network_graph.update_node_from_unsigned_announcement(&announcement).unwrap();
+}
@@ -2,6 +2,7 @@ use crate::logger::{
 	log_error, log_given_level, log_internal, log_trace, FilesystemLogger, Logger,
 };
 
+use crate::proxy::ProxyConfig;
 use crate::Error;
 
 use lightning::chain::chaininterface::{
@@ -15,7 +16,8 @@ use lightning::chain::keysinterface::{
 use lightning::ln::msgs::DecodeError;
 use lightning::ln::script::ShutdownScript;
 
-use bdk::blockchain::{Blockchain, EsploraBlockchain};
+use bdk::blockchain::esplora::EsploraBlockchainConfig;
+use bdk::blockchain::{Blockchain, ConfigurableBlockchain, EsploraBlockchain};
 use bdk::database::BatchDatabase;
 use bdk::wallet::AddressIndex;
 use bdk::{FeeRate, SignOptions, SyncOptions};
@@ -155,7 +157,6 @@ where
 		Ok(address_info.address)
 	}
 
-	#[cfg(any(test))]
 	pub(crate) fn get_balance(&self) -> Result<bdk::Balance, Error> {
 		Ok(self.inner.lock().unwrap().get_balance()?)
 	}
@@ -176,6 +177,21 @@ where
 	}
 }
 
+/// Builds the Esplora client used for wallet sync, routing its traffic via the proxy if the
+/// [`ProxyConfig`] says so.
+pub(crate) fn build_esplora_blockchain(
+	esplora_server_url: String, stop_gap: usize, proxy: Option<&ProxyConfig>,
+) -> Result<EsploraBlockchain, Error> {
+	let config = EsploraBlockchainConfig {
+		base_url: esplora_server_url,
+		proxy: proxy.and_then(|p| p.esplora_proxy_url()),
+		concurrency: None,
+		stop_gap,
+		timeout: None,
+	};
+	Ok(EsploraBlockchain::from_config(&config)?)
+}
+
 impl<D> FeeEstimator for Wallet<D>
 where
 	D: BatchDatabase,